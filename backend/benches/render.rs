@@ -0,0 +1,162 @@
+// Rendering shared conversation pages
+//
+//   cargo bench --bench render
+//
+// `per_request_registry` does what the HTML endpoint used to do for every page
+// (new registry, parse index.hbs, encode both images), `shared_registry` uses
+// the PageRenderer made at startup. `html_endpoint` goes through the whole app
+// with conversations kept in memory.
+
+use actix_web::cookie::Key;
+use actix_web::{test, web};
+use base64::Engine;
+use chrono::Utc;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use handlebars::Handlebars;
+use shareprompts_backend_api::api::page_cache::PageCache;
+use shareprompts_backend_api::models::*;
+use shareprompts_backend_api::render::{self, PageRenderer};
+use shareprompts_backend_api::storage::MemoryStore;
+use shareprompts_backend_api::{app, auth, mailer, AppState, ConversationStore};
+use std::sync::Arc;
+
+fn conversation() -> Conversation {
+    let dialog: Vec<serde_json::Value> = (0..20)
+        .map(|i| {
+            serde_json::json!({
+                "who": if i % 2 == 0 { "human" } else { "gpt" },
+                "what": format!("Message {} with a bit of **markdown** and `code`", i),
+            })
+        })
+        .collect();
+    Conversation {
+        id: "bench".to_string(),
+        hmac: "hmac".to_string(),
+        contents: serde_json::json!({"avatar": "", "dialog": dialog}),
+        public: true,
+        research: false,
+        deleted: false,
+        user_id: "bench".to_string(),
+        folder_id: None,
+        created_at: Utc::now(),
+        title: "Benchmark".to_string(),
+        model: "test".to_string(),
+        openaiid: "openai".to_string(),
+        updated_at: Utc::now(),
+        length: 20,
+        schema_version: CONTENTS_SCHEMA_VERSION,
+        version: 1,
+    }
+}
+
+fn site_file(name: &str) -> Vec<u8> {
+    std::fs::read(
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("site")
+            .join(name),
+    )
+    .expect("read site file")
+}
+
+// The page as it was rendered before there was a shared registry
+fn per_request_page(
+    conv: &Conversation,
+    hbs: &str,
+    css: &str,
+    js: &str,
+    chatgpt: &[u8],
+    logo: &[u8],
+) -> String {
+    let mut reg = Handlebars::new();
+    render::register_helpers(&mut reg);
+    let contents = read_contents(conv.schema_version, &conv.contents).unwrap();
+    let metadata = conv.metadata();
+    let encode = |png: &[u8]| {
+        format!(
+            "data:image/png;base64,{}",
+            base64::engine::general_purpose::STANDARD.encode(png)
+        )
+    };
+    reg.render_template(
+        hbs,
+        &serde_json::json!({
+            "style": css,
+            "main_js": js,
+            "title": metadata.title,
+            "model": metadata.model,
+            "openaiid": metadata.openaiid,
+            "avatar": contents.avatar,
+            "dialog": contents.dialog,
+            "chatgpt_uri": encode(chatgpt),
+            "logo_uri": encode(logo),
+            "timestamp": "2023/07/01 12:00:00 UTC",
+            "hmac": &conv.hmac,
+            "public": &conv.public,
+            "research": &conv.research,
+        }),
+    )
+    .unwrap()
+}
+
+fn conversation_page(c: &mut Criterion) {
+    let conv = conversation();
+    let hbs = String::from_utf8(site_file("index.hbs")).unwrap();
+    let css = String::from_utf8(site_file("index.css")).unwrap();
+    let js = String::from_utf8(site_file("main.js")).unwrap();
+    let chatgpt = site_file("chatgpt.png");
+    let logo = site_file("logo-128.png");
+    let pages = PageRenderer::embedded();
+
+    let mut group = c.benchmark_group("conversation_page");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("per_request_registry", |b| {
+        b.iter(|| per_request_page(&conv, &hbs, &css, &js, &chatgpt, &logo))
+    });
+    group.bench_function("shared_registry", |b| {
+        b.iter(|| pages.conversation_html(&conv).unwrap())
+    });
+    group.finish();
+}
+
+fn html_endpoint(c: &mut Criterion) {
+    let system = actix_web::rt::System::new();
+    let store = Arc::new(MemoryStore::new());
+    system.block_on(store.insert(&conversation())).unwrap();
+    let state = web::Data::new(AppState {
+        auth: auth::AuthProviders::new(Vec::new()),
+        mailer: mailer::mailer(&mailer::MailerConfig::default()),
+        public_url: "http://localhost".to_string(),
+        max_free_user_count: 1000,
+        session_ttl_days: 30,
+        magic_link_cooldown_secs: 60,
+        pages: PageRenderer::embedded(),
+        // Every request renders, like the first view of a conversation
+        page_cache: PageCache::new(0),
+    });
+    let store: Arc<dyn ConversationStore> = store;
+    let service = system.block_on(test::init_service(app(
+        None,
+        store,
+        state,
+        Key::derive_from(&[7u8; 64]),
+    )));
+
+    let mut group = c.benchmark_group("html_endpoint");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("get_conversation_html", |b| {
+        b.iter(|| {
+            system.block_on(async {
+                let req = test::TestRequest::get()
+                    .uri("/conversation/html/bench")
+                    .to_request();
+                let resp = test::call_service(&service, req).await;
+                assert!(resp.status().is_success());
+                test::read_body(resp).await
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, conversation_page, html_endpoint);
+criterion_main!(benches);
@@ -0,0 +1,27 @@
+// Pick the site files compiled into the binary (see render.rs)
+//
+// `npm run build` writes the bundled style sheet and script to dist/, those
+// win over the sources in site/ so a release build embeds what deploys used to
+// copy next to the binary. Plain `cargo build` embeds site/ as is.
+
+use std::path::Path;
+
+const SITE_FILES: [(&str, &str); 5] = [
+    ("SITE_INDEX_HBS", "index.hbs"),
+    ("SITE_INDEX_CSS", "index.css"),
+    ("SITE_MAIN_JS", "main.js"),
+    ("SITE_CHATGPT_PNG", "chatgpt.png"),
+    ("SITE_LOGO_PNG", "logo-128.png"),
+];
+
+fn main() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    for (var, name) in SITE_FILES {
+        let built = root.join("dist").join(name);
+        let source = root.join("site").join(name);
+        println!("cargo:rerun-if-changed={}", built.display());
+        println!("cargo:rerun-if-changed={}", source.display());
+        let path = if built.is_file() { built } else { source };
+        println!("cargo:rustc-env={}={}", var, path.display());
+    }
+}
@@ -0,0 +1,11 @@
+CREATE TABLE tags (
+  id TEXT PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  name TEXT NOT NULL,
+  UNIQUE (user_id, name)
+);
+CREATE TABLE conversation_tags (
+  conversation_id TEXT NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+  tag_id TEXT NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+  PRIMARY KEY (conversation_id, tag_id)
+);
@@ -0,0 +1,3 @@
+DROP INDEX folder_id_index;
+ALTER TABLE conversations DROP COLUMN folder_id;
+DROP TABLE folders;
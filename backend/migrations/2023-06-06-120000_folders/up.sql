@@ -0,0 +1,8 @@
+CREATE TABLE folders (
+  id TEXT PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  name TEXT NOT NULL,
+  UNIQUE (user_id, name)
+);
+ALTER TABLE conversations ADD COLUMN folder_id TEXT REFERENCES folders (id) ON DELETE SET NULL;
+CREATE INDEX folder_id_index ON conversations (folder_id);
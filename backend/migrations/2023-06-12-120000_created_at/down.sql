@@ -0,0 +1,2 @@
+DROP INDEX user_id_created_at_index;
+ALTER TABLE conversations DROP COLUMN created_at;
@@ -0,0 +1,6 @@
+ALTER TABLE conversations ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+UPDATE conversations SET created_at = to_timestamp(
+  (metadata::json->'creationdate'->>'secs_since_epoch')::DOUBLE PRECISION
+  + (metadata::json->'creationdate'->>'nanos_since_epoch')::DOUBLE PRECISION / 1e9
+);
+CREATE INDEX user_id_created_at_index ON conversations (user_id, created_at DESC, id DESC);
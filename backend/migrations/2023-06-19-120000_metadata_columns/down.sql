@@ -0,0 +1,21 @@
+DROP TRIGGER set_updated_at ON conversations;
+DROP INDEX user_id_model_index;
+DROP INDEX user_id_title_index;
+ALTER TABLE conversations ADD COLUMN metadata TEXT NOT NULL DEFAULT '';
+UPDATE conversations SET metadata = json_build_object(
+  'title', title,
+  'openaiid', openaiid,
+  'model', model,
+  'creationdate', json_build_object(
+    'secs_since_epoch', floor(extract(epoch FROM created_at))::BIGINT,
+    'nanos_since_epoch', ((extract(epoch FROM created_at) - floor(extract(epoch FROM created_at))) * 1e9)::BIGINT
+  ),
+  'length', length
+)::TEXT;
+ALTER TABLE conversations ALTER COLUMN metadata DROP DEFAULT;
+ALTER TABLE conversations
+  DROP COLUMN title,
+  DROP COLUMN model,
+  DROP COLUMN openaiid,
+  DROP COLUMN updated_at,
+  DROP COLUMN length;
@@ -0,0 +1,16 @@
+ALTER TABLE conversations
+  ADD COLUMN title TEXT NOT NULL DEFAULT '',
+  ADD COLUMN model TEXT NOT NULL DEFAULT '',
+  ADD COLUMN openaiid TEXT NOT NULL DEFAULT '',
+  ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  ADD COLUMN length INTEGER NOT NULL DEFAULT 0;
+UPDATE conversations SET
+  title = COALESCE(metadata::json->>'title', ''),
+  model = COALESCE(metadata::json->>'model', ''),
+  openaiid = COALESCE(metadata::json->>'openaiid', ''),
+  length = COALESCE((metadata::json->>'length')::INTEGER, 0),
+  updated_at = created_at;
+ALTER TABLE conversations DROP COLUMN metadata;
+CREATE INDEX user_id_title_index ON conversations (user_id, lower(title), id);
+CREATE INDEX user_id_model_index ON conversations (user_id, model, id);
+SELECT diesel_manage_updated_at('conversations');
@@ -0,0 +1,2 @@
+ALTER TABLE conversations DROP COLUMN schema_version;
+ALTER TABLE conversations ALTER COLUMN contents TYPE TEXT USING contents::TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE conversations ALTER COLUMN contents TYPE JSONB USING contents::JSONB;
+ALTER TABLE conversations ADD COLUMN schema_version INTEGER NOT NULL DEFAULT 1;
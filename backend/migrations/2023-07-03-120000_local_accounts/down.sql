@@ -0,0 +1,2 @@
+DROP TABLE magic_links;
+DROP TABLE local_accounts;
@@ -0,0 +1,15 @@
+CREATE TABLE local_accounts (
+  id TEXT PRIMARY KEY,
+  email TEXT NOT NULL UNIQUE,
+  password_hash TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE TABLE magic_links (
+  token_hash TEXT PRIMARY KEY,
+  email TEXT NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL,
+  used BOOLEAN NOT NULL DEFAULT FALSE,
+  -- Password chosen when registering, the account gets it once the emailed link is opened
+  password_hash TEXT
+);
+CREATE INDEX magic_links_email_index ON magic_links (email);
@@ -0,0 +1,11 @@
+CREATE TABLE api_tokens (
+  id TEXT PRIMARY KEY,
+  user_id TEXT NOT NULL,
+  name TEXT NOT NULL,
+  token_hash TEXT NOT NULL UNIQUE,
+  scopes TEXT[] NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  last_used_at TIMESTAMPTZ,
+  revoked BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE INDEX api_tokens_user_id_index ON api_tokens (user_id);
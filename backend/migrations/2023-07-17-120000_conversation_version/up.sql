@@ -0,0 +1,2 @@
+-- Bumped on every change, clients send it back in If-Match
+ALTER TABLE conversations ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,399 @@
+// Logging in and out, local accounts, API tokens and server metrics
+
+use super::errors::ApiError;
+use super::AppState;
+use crate::auth::identity::{AuthError, AuthenticatedUser};
+use crate::auth::{accounts, tokens};
+use crate::storage::{self, DbPool, LocalError};
+use actix_session::Session;
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use log::info;
+
+/// Check if user is authenticated
+// This endpoint does not perform authentication.
+// Respond with 200 if authenticated, 401 if not
+#[post("/authenticated")]
+pub(super) async fn authenticated(
+    user: Option<AuthenticatedUser>,
+) -> Result<impl Responder, ApiError> {
+    info!("Checking credentials");
+    if let Some(user) = user {
+        info!("user_id is {}", user.user_id);
+        return Ok(HttpResponse::Ok().body("Authenticated"));
+    }
+    info!("Credentials check failed");
+    Err(AuthError::Missing.into())
+}
+
+/// Server metrics in Prometheus text format
+#[get("/metrics")]
+pub(super) async fn get_metrics(state: web::Data<AppState>) -> Result<impl Responder, ApiError> {
+    let access_tokens = state.auth.access_token_cache_stats();
+    let pages = state.page_cache.stats();
+    let body = format!(
+        "# HELP access_token_cache_hits_total Access tokens found in the validation cache\n\
+         # TYPE access_token_cache_hits_total counter\n\
+         access_token_cache_hits_total {}\n\
+         # HELP access_token_cache_misses_total Access tokens that had to be validated by the provider\n\
+         # TYPE access_token_cache_misses_total counter\n\
+         access_token_cache_misses_total {}\n\
+         # HELP access_token_cache_entries Access tokens currently in the validation cache\n\
+         # TYPE access_token_cache_entries gauge\n\
+         access_token_cache_entries {}\n\
+         # HELP access_token_cache_hit_rate Fraction of access token lookups served from the cache\n\
+         # TYPE access_token_cache_hit_rate gauge\n\
+         access_token_cache_hit_rate {}\n\
+         # HELP page_cache_hits_total Conversation pages served from the cache\n\
+         # TYPE page_cache_hits_total counter\n\
+         page_cache_hits_total {}\n\
+         # HELP page_cache_misses_total Conversation pages that had to be read and rendered\n\
+         # TYPE page_cache_misses_total counter\n\
+         page_cache_misses_total {}\n\
+         # HELP page_cache_entries Conversations currently in the page cache\n\
+         # TYPE page_cache_entries gauge\n\
+         page_cache_entries {}\n",
+        access_tokens.hits,
+        access_tokens.misses,
+        access_tokens.entries,
+        access_tokens.hit_rate(),
+        pages.hits,
+        pages.misses,
+        pages.entries,
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
+/// Log out user
+#[post("/logout")]
+pub(super) async fn logout(session: Session) -> Result<impl Responder, ApiError> {
+    session.purge();
+    Ok(HttpResponse::Ok().body("Logged out"))
+}
+
+/// Authenticate user
+// Client provides identity token (from GIS button thingy for Google)
+// Sends that token as "authorization: Bearer ..."
+// The provider is picked from the issuer of the token.
+// If it works, response will be 200 with "set-cookie: ..."
+// The session cookie is a JWT token that represents the authenticated session
+#[post("/authenticate")]
+pub(super) async fn authenticate(
+    auth: BearerAuth,
+    state: web::Data<AppState>,
+    session: Session,
+) -> Result<impl Responder, ApiError> {
+    if let Some(session_user_id) = session.get::<String>("user_id")? {
+        info!("user_id is {}", session_user_id);
+        return Ok(HttpResponse::Ok().body("Authenticated"));
+    }
+    info!("Starting authentication");
+    let token = auth.token();
+    let user_id = match state.auth.validate_identity_token(token).await {
+        Err(err) => {
+            info!("Identity token not accepted, err={:?}", err);
+            return Err(AuthError::Token(err).into());
+        }
+        Ok(uid) => uid,
+    };
+    info!("Setting session to have user_id={}", user_id);
+    session.insert("user_id", user_id)?;
+    info!("Session inserted");
+    Ok(HttpResponse::Ok().body("Authenticated"))
+}
+
+// Hash of a new password, after checking it is long enough
+async fn new_password_hash(password: String) -> Result<String, ApiError> {
+    if password.chars().count() < accounts::MIN_PASSWORD_LENGTH {
+        return Err(ApiError::invalid(
+            "password_too_short",
+            "Password too short",
+        ));
+    }
+    Ok(web::block(move || accounts::hash_password(&password)).await??)
+}
+
+// Where a magic link token is opened, in mails and on the confirm page
+fn magic_link_url(state: &AppState, token: &str) -> String {
+    format!("{}/api/local/magic-link/{}", state.public_url, token)
+}
+
+// Mail a magic link with a line saying what it is for
+async fn send_magic_link(
+    state: web::Data<AppState>,
+    email: String,
+    token: String,
+    subject: &'static str,
+    purpose: &'static str,
+) -> Result<(), ApiError> {
+    // Sending mail is blocking
+    web::block(move || -> Result<(), LocalError> {
+        let link = magic_link_url(&state, &token);
+        state.mailer.send(
+            &email,
+            subject,
+            &format!(
+                "{}\n\n{}\n\nThe link works once and expires in 15 minutes.",
+                purpose, link
+            ),
+        )?;
+        Ok(())
+    })
+    .await?
+    .map_err(|err| {
+        info!("Sending login link failed: {}", err);
+        err.into()
+    })
+}
+
+// Local account login
+// Sets the same session user_id as /authenticate so everything else works unchanged
+// Registering mails a link and logs in nobody, the account is made when the
+// link is opened so nobody can take an address they can't read mail for.
+// Addresses that have an account get a login link, the answer is the same.
+// So does an address that got a link moments ago, but nothing is mailed.
+#[post("/local/register")]
+pub(super) async fn local_register(
+    pool: web::Data<DbPool>,
+    state: web::Data<AppState>,
+    form: web::Json<accounts::Credentials>,
+) -> Result<impl Responder, ApiError> {
+    let email = match accounts::normalize_email(&form.email) {
+        Some(email) => email,
+        None => return Err(ApiError::invalid("email_invalid", "Invalid email address")),
+    };
+    let hash = new_password_hash(form.into_inner().password).await?;
+    let cooldown = chrono::Duration::seconds(state.magic_link_cooldown_secs);
+    let registration = storage::with_retries(&pool, |conn| {
+        accounts::register(conn, &email, &hash, cooldown).scope_boxed()
+    })
+    .await?;
+    match registration {
+        Some(accounts::Registration::New(token)) => {
+            info!("Sending registration link to {}", email);
+            send_magic_link(
+                state,
+                email,
+                token,
+                "Confirm your ShareConversation account",
+                "Open this link to confirm your email address and finish making your ShareConversation account. If you did not ask for an account, ignore this mail.",
+            )
+            .await?
+        }
+        Some(accounts::Registration::Existing(token)) => {
+            info!("{} registered again, sending login link", email);
+            send_magic_link(
+                state,
+                email,
+                token,
+                "Your ShareConversation login link",
+                "Someone tried to make a ShareConversation account for this address, which already has one. Open this link to log in; a password can be added once logged in. If it wasn't you, ignore this mail.",
+            )
+            .await?
+        }
+        None => info!("{} got a link moments ago, not sending another", email),
+    }
+    Ok(HttpResponse::Accepted().body("Confirmation link sent"))
+}
+
+// Add a password to an account made by login link
+// Needs the session cookie, which proves the address like the link did.
+#[post("/local/password")]
+pub(super) async fn local_set_password(
+    pool: web::Data<DbPool>,
+    form: web::Json<accounts::NewPassword>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    user.require_session()?;
+    let account = match accounts::account_id(&user.user_id) {
+        Some(account) => account.to_string(),
+        None => {
+            return Err(ApiError::invalid(
+                "account_not_local",
+                "Only local accounts have passwords",
+            ))
+        }
+    };
+    let hash = new_password_hash(form.into_inner().password).await?;
+    storage::with_retries(&pool, |conn| {
+        accounts::set_password(conn, &account, &hash).scope_boxed()
+    })
+    .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/local/login")]
+pub(super) async fn local_login(
+    pool: web::Data<DbPool>,
+    form: web::Json<accounts::Credentials>,
+    session: Session,
+) -> Result<impl Responder, ApiError> {
+    let email = match accounts::normalize_email(&form.email) {
+        Some(email) => email,
+        None => return Err(AuthError::LoginFailed.into()),
+    };
+    let form = form.into_inner();
+    let account = storage::with_retries(&pool, |conn| {
+        accounts::find_account_by_email(conn, &email).scope_boxed()
+    })
+    .await?;
+    match web::block(move || accounts::check_password(account, &form.password)).await? {
+        Ok(account) => {
+            session.insert("user_id", account.user_id())?;
+            Ok(HttpResponse::Ok().body("Authenticated"))
+        }
+        Err(LocalError::AuthorizationProblem) => Err(AuthError::LoginFailed.into()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Email a login link
+// Always answers the same way so it can't be used to find out who has an account,
+// or whether the address got a link moments ago and nothing was mailed.
+#[post("/local/magic-link")]
+pub(super) async fn local_magic_link(
+    pool: web::Data<DbPool>,
+    state: web::Data<AppState>,
+    form: web::Json<accounts::MagicLinkRequest>,
+) -> Result<impl Responder, ApiError> {
+    let email = match accounts::normalize_email(&form.email) {
+        Some(email) => email,
+        None => return Err(ApiError::invalid("email_invalid", "Invalid email address")),
+    };
+    let cooldown = chrono::Duration::seconds(state.magic_link_cooldown_secs);
+    let token = storage::with_retries(&pool, |conn| {
+        accounts::create_magic_link(conn, &email, cooldown).scope_boxed()
+    })
+    .await
+    .map_err(|err| {
+        info!("Creating login link failed: {}", err);
+        err
+    })?;
+    match token {
+        Some(token) => {
+            send_magic_link(
+                state,
+                email,
+                token,
+                "Your ShareConversation login link",
+                "Open this link to log in to ShareConversation:",
+            )
+            .await?
+        }
+        None => info!("{} got a link moments ago, not sending another", email),
+    }
+    Ok(HttpResponse::Ok().body("Login link sent"))
+}
+
+// Page with a button that logs in with the link
+// Mail scanners and link previews open links too, so opening one uses up
+// nothing; only the button's POST does.
+#[get("/local/magic-link/{token}")]
+pub(super) async fn local_magic_link_page(
+    state: web::Data<AppState>,
+    token_path: web::Path<(String,)>,
+) -> Result<impl Responder, ApiError> {
+    let action = handlebars::html_escape(&magic_link_url(&state, &token_path.0));
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            "<!DOCTYPE html>\n\
+             <html>\n\
+             <head><meta charset=\"utf-8\"><title>Log in to ShareConversation</title></head>\n\
+             <body>\n\
+             <form method=\"post\" action=\"{}\">\n\
+             <button type=\"submit\">Log in to ShareConversation</button>\n\
+             </form>\n\
+             </body>\n\
+             </html>\n",
+            action
+        )))
+}
+
+#[post("/local/magic-link/{token}")]
+pub(super) async fn local_magic_link_login(
+    pool: web::Data<DbPool>,
+    state: web::Data<AppState>,
+    token_path: web::Path<(String,)>,
+    session: Session,
+) -> Result<impl Responder, ApiError> {
+    let token = token_path.0.clone();
+    match storage::with_retries(&pool, |conn| {
+        accounts::consume_magic_link(conn, &token).scope_boxed()
+    })
+    .await
+    {
+        Ok(account) => {
+            info!("Magic link login for {}", account.email);
+            session.insert("user_id", account.user_id())?;
+            Ok(HttpResponse::SeeOther()
+                .insert_header(("Location", format!("{}/", state.public_url)))
+                .finish())
+        }
+        Err(LocalError::AuthorizationProblem) => Err(AuthError::MagicLinkInvalid.into()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Personal API tokens
+// Managing tokens needs the session cookie, tokens can't be used to make more tokens.
+#[get("/tokens")]
+pub(super) async fn get_my_tokens(
+    pool: web::Data<DbPool>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    user.require_session()?;
+    let uid = user.user_id;
+    let user_tokens = storage::with_retries(&pool, |conn| {
+        tokens::find_tokens_by_user(conn, &uid).scope_boxed()
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(user_tokens))
+}
+
+#[post("/tokens")]
+pub(super) async fn post_token(
+    pool: web::Data<DbPool>,
+    form: web::Json<tokens::NewApiToken>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    user.require_session()?;
+    let uid = user.user_id;
+    let name_length = form.name.trim().chars().count();
+    if name_length == 0 || name_length > tokens::MAX_TOKEN_NAME_LENGTH {
+        return Err(ApiError::invalid(
+            "token_name_invalid",
+            "Invalid token name",
+        ));
+    }
+    if form.scopes.is_empty() {
+        return Err(ApiError::invalid(
+            "token_scopes_missing",
+            "Token needs at least one scope",
+        ));
+    }
+    let created = storage::with_retries(&pool, |conn| {
+        tokens::create_token(conn, &uid, &form).scope_boxed()
+    })
+    .await?;
+    Ok(HttpResponse::Created().json(created))
+}
+
+#[delete("/tokens/{id}")]
+pub(super) async fn delete_token(
+    pool: web::Data<DbPool>,
+    tokenid_path: web::Path<(String,)>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    let tokenid = tokenid_path.0.clone();
+    user.require_session()?;
+    let uid = user.user_id;
+    storage::with_retries(&pool, |conn| {
+        tokens::revoke_token(conn, &uid, &tokenid).scope_boxed()
+    })
+    .await?;
+    Ok(HttpResponse::Ok().finish())
+}
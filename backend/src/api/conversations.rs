@@ -0,0 +1,376 @@
+// Conversation endpoints: sharing, reading, listing, changing and deleting
+
+use super::errors::ApiError;
+use super::page_cache::{CachedPage, PageKind};
+use super::AppState;
+use crate::auth::identity::AuthenticatedUser;
+use crate::auth::tokens::Scope;
+use crate::models::*;
+use crate::storage::{self, *};
+use actix_web::http::header::{self, EntityTag};
+use actix_web::{delete, get, patch, post, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use log::info;
+
+const MAX_BULK_IDS: usize = 500;
+
+// What GET /conversation/json returns
+fn conversation_info(conv: &Conversation) -> Result<ConversationInfo, ApiError> {
+    Ok(ConversationInfo {
+        id: conv.id.clone(),
+        contents: read_contents(conv.schema_version, &conv.contents)?,
+        metadata: conv.metadata(),
+        public: conv.public,
+        research: conv.research,
+        deleted: conv.deleted,
+        hmac: conv.hmac.clone(),
+        version: conv.version,
+    })
+}
+
+// ETag of the JSON page, clients send it back in If-Match
+fn version_etag(version: i32) -> EntityTag {
+    EntityTag::new_strong(version.to_string())
+}
+
+// Answer to a change made to an older version, with the current one
+fn stale(conv: &Conversation) -> Result<ApiError, ApiError> {
+    Ok(ApiError::Stale {
+        etag: version_etag(conv.version).to_string(),
+        current: serde_json::to_value(conversation_info(conv)?)?,
+    })
+}
+
+// Only strong tags count, weak ones don't promise the same contents
+fn version_matches(if_match: &header::IfMatch, version: i32) -> bool {
+    match if_match {
+        header::IfMatch::Any => true,
+        header::IfMatch::Items(etags) => etags
+            .iter()
+            .any(|etag| etag.strong_eq(&version_etag(version))),
+    }
+}
+
+#[get("/conversation/json/{id}")]
+pub(super) async fn get_conversation_json(
+    store: web::Data<dyn ConversationStore>,
+    state: web::Data<AppState>,
+    id: web::Path<(String,)>,
+    req: HttpRequest,
+) -> Result<impl Responder, ApiError> {
+    let uid = id.into_inner().0;
+    if let Some(page) = state.page_cache.get(&uid, PageKind::Json) {
+        return Ok(page.respond_to(&req));
+    }
+    let generation = state.page_cache.generation();
+    let conversation = store.find(&uid, /*deleted=*/ false).await?;
+    match conversation {
+        Some(conv) => {
+            let body = serde_json::to_vec(&conversation_info(&conv)?).map_err(ApiError::from)?;
+            let page = CachedPage::with_etag(
+                PageKind::Json,
+                body,
+                version_etag(conv.version),
+                conv.updated_at,
+            );
+            state
+                .page_cache
+                .insert(&conv.id, &conv.hmac, generation, page.clone());
+            Ok(page.respond_to(&req))
+        }
+        None => Err(LocalError::NotFound.into()),
+    }
+}
+
+#[get("/conversation/html/{id}")]
+pub(super) async fn get_conversation_html(
+    store: web::Data<dyn ConversationStore>,
+    state: web::Data<AppState>,
+    id: web::Path<(String,)>,
+    req: HttpRequest,
+) -> Result<impl Responder, ApiError> {
+    let uid = id.into_inner().0;
+    if let Some(page) = state.page_cache.get(&uid, PageKind::Html) {
+        return Ok(page.respond_to(&req));
+    }
+    let generation = state.page_cache.generation();
+    let conversation = store.find(&uid, /*deleted=*/ false).await?;
+    match conversation {
+        Some(conv) => {
+            let body = state.pages.conversation_html(&conv)?;
+            let page = CachedPage::new(PageKind::Html, body, conv.updated_at);
+            state
+                .page_cache
+                .insert(&conv.id, &conv.hmac, generation, page.clone());
+            Ok(page.respond_to(&req))
+        }
+        None => Err(LocalError::NotFound.into()),
+    }
+}
+
+#[post("/conversations")]
+pub(super) async fn get_my_conversations(
+    store: web::Data<dyn ConversationStore>,
+    filter: web::Query<ConversationFilter>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    user.require(Scope::Read)?;
+    let user_id = user.user_id;
+    let cursor = match filter.cursor.as_deref().map(PageCursor::decode) {
+        None => None,
+        Some(Some(cursor)) if cursor.sort == filter.sort.unwrap_or(SortKey::Date) => Some(cursor),
+        Some(_) => return Err(ApiError::bad_request("cursor_invalid", "Invalid cursor")),
+    };
+    let conversations = store.list(&user_id, &filter, cursor.as_ref()).await?;
+    Ok(HttpResponse::Ok().json(conversations))
+}
+
+#[get("/conversation/count")]
+pub(super) async fn get_conversation_count_user(
+    store: web::Data<dyn ConversationStore>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    user.require(Scope::Read)?;
+    let user_id = user.user_id;
+    let count = store.count(&user_id).await?;
+    Ok(HttpResponse::Ok().json(count))
+}
+
+#[post("/conversation/")]
+pub(super) async fn post_conversation(
+    user: AuthenticatedUser,
+    store: web::Data<dyn ConversationStore>,
+    state: web::Data<AppState>,
+    form: web::Json<NewConversation>,
+) -> Result<impl Responder, ApiError> {
+    user.require(Scope::Write)?;
+    let userid = user.user_id;
+    // Free users can only share so many
+    let max_count = (!form.paiduser).then_some(state.max_free_user_count);
+    let json_contents = serde_json::to_value(&form.contents)?;
+    let now = chrono::Utc::now();
+    let meta_data = ConversationMetadata {
+        title: form.title.clone(),
+        openaiid: form.openaiid.clone(),
+        model: form.model.clone(),
+        creationdate: now.into(),
+        length: form.contents.dialog.len(),
+    };
+    let digest = compute_digest(&form.contents, &meta_data, &userid);
+    // Same conversation shared again gives back the id it already has
+    let inner_convo_id = store
+        .create(
+            &Conversation {
+                id: uuid::Uuid::new_v4().simple().to_string(),
+                hmac: digest,
+                contents: json_contents,
+                public: form.public,
+                research: form.research,
+                user_id: userid,
+                deleted: false,
+                folder_id: None,
+                created_at: now,
+                title: meta_data.title,
+                model: meta_data.model,
+                openaiid: meta_data.openaiid,
+                updated_at: now,
+                length: meta_data.length as i32,
+                schema_version: CONTENTS_SCHEMA_VERSION,
+                version: 1,
+            },
+            max_count,
+        )
+        .await?;
+    Ok(HttpResponse::Created().json(inner_convo_id))
+}
+
+// Body is a merge patch (application/json or application/merge-patch+json) or
+// a JSON Patch (application/json-patch+json) of what GET /conversation/json returns
+#[patch("/conversation/{id}")]
+pub(super) async fn patch_conversation(
+    store: web::Data<dyn ConversationStore>,
+    state: web::Data<AppState>,
+    id: web::Path<(String,)>,
+    body: web::Json<serde_json::Value>,
+    user: AuthenticatedUser,
+    req: HttpRequest,
+) -> Result<impl Responder, ApiError> {
+    user.require(Scope::Write)?;
+    let userid = user.user_id;
+    let id = id.into_inner().0;
+    let patch = match req.content_type() {
+        "application/json-patch+json" => ConversationPatch::Json(
+            serde_json::from_value(body.into_inner())
+                .map_err(|err| ApiError::bad_request("patch_invalid", err.to_string()))?,
+        ),
+        _ => ConversationPatch::Merge(body.into_inner()),
+    };
+    // Changes have to name the version they were made to
+    if !req.headers().contains_key(header::IF_MATCH) {
+        return Err(ApiError::PreconditionRequired);
+    }
+    let if_match = req
+        .get_header::<header::IfMatch>()
+        .ok_or_else(|| ApiError::bad_request("if_match_invalid", "Invalid If-Match header"))?;
+    let conv = match store.find(&id, /*deleted=*/ false).await? {
+        Some(conv) => conv,
+        None => {
+            info!("Conversation to patch not found");
+            return Err(LocalError::NotFound.into());
+        }
+    };
+    if conv.user_id != userid {
+        info!("Conversation to patch owner does not match requestor");
+        return Err(LocalError::AuthorizationProblem.into());
+    }
+    if !version_matches(&if_match, conv.version) {
+        return Err(stale(&conv)?);
+    }
+    let current = conversation_info(&conv)?;
+    let patched = patch.apply(&current)?;
+    // Stored contents and digest stay as they are unless the patch touched them
+    let changes = if patched.contents == current.contents
+        && patched.metadata.title == conv.title
+        && patched.metadata.model == conv.model
+        && patched.metadata.openaiid == conv.openaiid
+    {
+        ConversationChanges {
+            contents: conv.contents.clone(),
+            schema_version: conv.schema_version,
+            title: conv.title.clone(),
+            model: conv.model.clone(),
+            openaiid: conv.openaiid.clone(),
+            length: conv.length,
+            public: patched.public,
+            research: patched.research,
+            hmac: conv.hmac.clone(),
+        }
+    } else {
+        let metadata = ConversationMetadata {
+            length: patched.contents.dialog.len(),
+            ..patched.metadata
+        };
+        ConversationChanges {
+            contents: serde_json::to_value(&patched.contents)?,
+            schema_version: CONTENTS_SCHEMA_VERSION,
+            hmac: compute_digest(&patched.contents, &metadata, &userid),
+            title: metadata.title,
+            model: metadata.model,
+            openaiid: metadata.openaiid,
+            length: metadata.length as i32,
+            public: patched.public,
+            research: patched.research,
+        }
+    };
+    let version = match store.update(&id, &changes, conv.version).await {
+        Ok(version) => version,
+        // Someone else got there between reading and writing
+        Err(LocalError::VersionMismatch) => {
+            return match store.find(&id, /*deleted=*/ false).await? {
+                Some(conv) => Err(stale(&conv)?),
+                None => Err(LocalError::NotFound.into()),
+            };
+        }
+        Err(err) => return Err(err.into()),
+    };
+    state.page_cache.invalidate(&id);
+    Ok(HttpResponse::Ok()
+        .insert_header(header::ETag(version_etag(version)))
+        .finish())
+}
+
+#[post("/conversation/undelete/{id}")]
+pub(super) async fn undelete_conversation(
+    store: web::Data<dyn ConversationStore>,
+    state: web::Data<AppState>,
+    postid_path: web::Path<(String,)>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    let postid = postid_path.0.clone();
+    user.require(Scope::Write)?;
+    let uid = user.user_id;
+    async {
+        let conversation = store.find(&postid, /*deleted=*/ true).await?;
+        match conversation {
+            Some(conv) => {
+                if conv.user_id != uid {
+                    info!("Conversation to undelete owner does not match requestor");
+                    return Err(LocalError::AuthorizationProblem);
+                }
+                store.set_deleted(&postid, false).await
+            }
+            None => {
+                info!("Conversation to undelete not found");
+                Err(LocalError::NotFound)
+            }
+        }
+    }
+    .await?;
+    state.page_cache.invalidate(&postid_path.0);
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("/conversation/{id}")]
+pub(super) async fn delete_conversation(
+    store: web::Data<dyn ConversationStore>,
+    state: web::Data<AppState>,
+    postid_path: web::Path<(String,)>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    let postid = postid_path.0.clone();
+    user.require(Scope::Write)?;
+    let uid = user.user_id;
+    async {
+        let conversation = store.find(&postid, /*deleted=*/ false).await?;
+        match conversation {
+            Some(conv) => {
+                if conv.user_id != uid {
+                    info!("Conversation to delete owner does not match requestor");
+                    return Err(LocalError::AuthorizationProblem);
+                }
+                store.set_deleted(&postid, true).await
+            }
+            None => {
+                info!("Conversation to delete not found");
+                Err(LocalError::NotFound)
+            }
+        }
+    }
+    .await?;
+    state.page_cache.invalidate(&postid_path.0);
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/conversations/bulk")]
+pub(super) async fn bulk_conversations(
+    pool: web::Data<DbPool>,
+    state: web::Data<AppState>,
+    form: web::Json<BulkRequest>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    user.require(Scope::Write)?;
+    let uid = user.user_id;
+    if form.ids.len() > MAX_BULK_IDS {
+        return Err(ApiError::invalid(
+            "too_many_ids",
+            "Too many conversations in bulk request",
+        ));
+    }
+    let mut form = form.into_inner();
+    // Same names as tags made with POST /tags
+    if let BulkOperation::AddTag(tag_name) = &mut form.operation {
+        *tag_name = match validate_label_name(tag_name) {
+            Some(tag_name) => tag_name,
+            None => return Err(ApiError::invalid("tag_name_invalid", "Invalid tag name")),
+        };
+    }
+    let ids = form.ids.clone();
+    let results = storage::with_retries(&pool, |conn| {
+        apply_bulk_operation(conn, &uid, &form.ids, &form.operation).scope_boxed()
+    })
+    .await?;
+    for id in &ids {
+        state.page_cache.invalidate(id);
+    }
+    Ok(HttpResponse::Ok().json(results))
+}
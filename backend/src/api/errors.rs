@@ -0,0 +1,309 @@
+// Errors returned by the API
+//
+// Handlers return `Result<_, ApiError>`. Every error is answered with a JSON
+// problem details body (RFC 7807) like
+//   {"title": "Not Found", "status": 404, "detail": "Not found",
+//    "code": "not_found", "request_id": "..."}
+// `code` is meant for programs, `detail` for people. The request id is also
+// sent as the X-Request-Id header and is logged with server errors, so a
+// report from a user can be matched with the log.
+
+use crate::auth::identity::AuthError;
+use crate::models::PatchError;
+use crate::render::RenderError;
+use crate::storage::{DbError, LocalError};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, ETAG, RETRY_AFTER, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use log::info;
+use std::future::Future;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+// Longest request id accepted from a client (or proxy)
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+// Told to clients with every 503, the database problems behind them pass quickly
+const RETRY_AFTER_SECS: u32 = 1;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// Id of the request being handled, if any
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+// Use the id sent by the client if it looks sane, otherwise make one up
+fn request_id_for(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LENGTH
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string())
+}
+
+// Middleware (for App::wrap_fn) that gives every request an id
+// The id is visible to error responses through `current_request_id` and is
+// returned in the X-Request-Id header.
+pub fn with_request_id<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let request_id = request_id_for(&req);
+    let header = HeaderValue::from_str(&request_id).expect("request id is a valid header value");
+    let fut = REQUEST_ID.scope(request_id, srv.call(req));
+    async move {
+        let mut res = fut.await?;
+        res.headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), header);
+        Ok(res)
+    }
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    // Missing or unacceptable credentials
+    Auth(AuthError),
+    Local(LocalError),
+    // Request could not be understood (bad JSON, bad query string, ...)
+    BadRequest(&'static str, String),
+    // Request was understood but some value in it is not acceptable
+    Invalid(&'static str, String),
+    // Endpoint needs Postgres and conversations are kept somewhere else
+    NotAvailable,
+    // Change without If-Match, the client has to say which version it changes
+    PreconditionRequired,
+    // If-Match names an older version, the body carries the current one
+    Stale {
+        etag: String,
+        current: serde_json::Value,
+    },
+    // Something unexpected, already logged
+    Internal,
+}
+
+impl ApiError {
+    pub fn bad_request(code: &'static str, detail: impl Into<String>) -> ApiError {
+        ApiError::BadRequest(code, detail.into())
+    }
+
+    pub fn invalid(code: &'static str, detail: impl Into<String>) -> ApiError {
+        ApiError::Invalid(code, detail.into())
+    }
+
+    // Log what went wrong, the client only learns that something did
+    pub fn internal(err: impl std::fmt::Display) -> ApiError {
+        info!(
+            "Internal error in request {}: {}",
+            current_request_id().unwrap_or_default(),
+            err
+        );
+        ApiError::Internal
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::Auth(err) => err.code(),
+            ApiError::Local(err) => match err {
+                LocalError::DbConnectionProblem => "database_unavailable",
+                LocalError::DbContention => "database_busy",
+                LocalError::DbTimeout => "database_timeout",
+                LocalError::SerializationFailed | LocalError::UnsupportedContentsVersion(_) => {
+                    "serialization_failed"
+                }
+                LocalError::DbError => "database_error",
+                LocalError::AuthorizationProblem => "forbidden",
+                LocalError::NotFound => "not_found",
+                LocalError::MaxCount => "share_limit_reached",
+                LocalError::AlreadyExists => "already_exists",
+                LocalError::VersionMismatch => "version_mismatch",
+                LocalError::MailFailed => "mail_failed",
+                LocalError::PasswordHash => "password_hash_failed",
+            },
+            ApiError::BadRequest(code, _) | ApiError::Invalid(code, _) => code,
+            ApiError::NotAvailable => "not_available",
+            ApiError::PreconditionRequired => "if_match_missing",
+            ApiError::Stale { .. } => "version_mismatch",
+            ApiError::Internal => "internal_error",
+        }
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            ApiError::Auth(err) => err.to_string(),
+            ApiError::Local(err) => match err {
+                LocalError::DbConnectionProblem => "Database is unavailable".to_string(),
+                LocalError::DbContention => "Database is busy, try again".to_string(),
+                LocalError::DbTimeout => "Database is overloaded, try again later".to_string(),
+                LocalError::AuthorizationProblem => "Not allowed".to_string(),
+                LocalError::NotFound => "Not found".to_string(),
+                LocalError::MaxCount => "Maximum free sharing count reached".to_string(),
+                LocalError::AlreadyExists => "Already exists".to_string(),
+                LocalError::VersionMismatch => {
+                    "Conversation was changed since it was read".to_string()
+                }
+                LocalError::MailFailed => "Could not send mail".to_string(),
+                LocalError::SerializationFailed
+                | LocalError::UnsupportedContentsVersion(_)
+                | LocalError::PasswordHash
+                | LocalError::DbError => "Something went wrong on the server".to_string(),
+            },
+            ApiError::BadRequest(_, detail) | ApiError::Invalid(_, detail) => detail.clone(),
+            ApiError::NotAvailable => "Not available on this server".to_string(),
+            ApiError::PreconditionRequired => {
+                "Send the version being changed in If-Match".to_string()
+            }
+            ApiError::Stale { .. } => "Conversation was changed since it was read".to_string(),
+            ApiError::Internal => "Something went wrong on the server".to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({})", self.detail(), self.code())
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Auth(err) => err.status_code(),
+            ApiError::Local(err) => match err {
+                LocalError::DbConnectionProblem
+                | LocalError::DbContention
+                | LocalError::DbTimeout => StatusCode::SERVICE_UNAVAILABLE,
+                LocalError::AuthorizationProblem | LocalError::MaxCount => StatusCode::FORBIDDEN,
+                LocalError::NotFound => StatusCode::NOT_FOUND,
+                LocalError::AlreadyExists => StatusCode::CONFLICT,
+                LocalError::VersionMismatch => StatusCode::PRECONDITION_FAILED,
+                LocalError::SerializationFailed
+                | LocalError::UnsupportedContentsVersion(_)
+                | LocalError::DbError
+                | LocalError::MailFailed
+                | LocalError::PasswordHash => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            ApiError::BadRequest(_, _) => StatusCode::BAD_REQUEST,
+            ApiError::Invalid(_, _) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::NotAvailable => StatusCode::NOT_IMPLEMENTED,
+            ApiError::PreconditionRequired => StatusCode::PRECONDITION_REQUIRED,
+            ApiError::Stale { .. } => StatusCode::PRECONDITION_FAILED,
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let request_id = current_request_id();
+        if status.is_server_error() {
+            info!(
+                "Request {} failed: {}",
+                request_id.as_deref().unwrap_or_default(),
+                self
+            );
+        }
+        let mut body = serde_json::json!({
+            "title": status.canonical_reason().unwrap_or("Error"),
+            "status": status.as_u16(),
+            "detail": self.detail(),
+            "code": self.code(),
+        });
+        if let Some(request_id) = request_id {
+            body["request_id"] = request_id.into();
+        }
+        let mut res = HttpResponse::build(status);
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            res.insert_header((RETRY_AFTER, RETRY_AFTER_SECS));
+        }
+        // Enough to try again without reading the conversation first
+        if let ApiError::Stale { etag, current } = self {
+            res.insert_header((ETAG, etag.as_str()));
+            body["current"] = current.clone();
+        }
+        // Bearer token problems also use the names from RFC 6750
+        if let ApiError::Auth(err) = self {
+            if let Some(challenge) = err.challenge() {
+                res.insert_header((WWW_AUTHENTICATE, challenge));
+            }
+            if let Some(bearer_error) = err.bearer_error() {
+                body["error"] = bearer_error.into();
+                body["error_description"] = self.detail().into();
+            }
+        }
+        res.content_type("application/problem+json")
+            .body(body.to_string())
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(err: AuthError) -> ApiError {
+        ApiError::Auth(err)
+    }
+}
+impl From<LocalError> for ApiError {
+    fn from(err: LocalError) -> ApiError {
+        ApiError::Local(err)
+    }
+}
+impl From<DbError> for ApiError {
+    fn from(err: DbError) -> ApiError {
+        ApiError::Local(LocalError::from(err))
+    }
+}
+impl From<serde_json::Error> for ApiError {
+    fn from(err: serde_json::Error) -> ApiError {
+        info!("Stored conversation could not be read: {}", err);
+        ApiError::Local(LocalError::SerializationFailed)
+    }
+}
+impl From<RenderError> for ApiError {
+    fn from(err: RenderError) -> ApiError {
+        match err {
+            RenderError::Contents(err) => ApiError::from(err),
+            RenderError::Site(err) => ApiError::internal(err),
+            RenderError::Template(err) => ApiError::internal(err),
+        }
+    }
+}
+impl From<PatchError> for ApiError {
+    fn from(err: PatchError) -> ApiError {
+        match err {
+            PatchError::Failed(detail) => ApiError::invalid("patch_failed", detail),
+            PatchError::Invalid(detail) => ApiError::invalid("patch_result_invalid", detail),
+            PatchError::ReadOnly(field) => {
+                ApiError::invalid("field_read_only", format!("{} can't be changed", field))
+            }
+        }
+    }
+}
+impl From<actix_web::error::BlockingError> for ApiError {
+    fn from(err: actix_web::error::BlockingError) -> ApiError {
+        ApiError::internal(err)
+    }
+}
+impl From<actix_session::SessionGetError> for ApiError {
+    fn from(err: actix_session::SessionGetError) -> ApiError {
+        ApiError::internal(err)
+    }
+}
+impl From<actix_session::SessionInsertError> for ApiError {
+    fn from(err: actix_session::SessionInsertError) -> ApiError {
+        ApiError::internal(err)
+    }
+}
+impl From<actix_web::Error> for ApiError {
+    fn from(err: actix_web::Error) -> ApiError {
+        ApiError::internal(err)
+    }
+}
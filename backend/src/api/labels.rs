@@ -0,0 +1,201 @@
+// Tag and folder endpoints
+
+use super::errors::ApiError;
+use crate::auth::identity::AuthenticatedUser;
+use crate::auth::tokens::Scope;
+use crate::models::*;
+use crate::storage::{self, *};
+use actix_web::{delete, get, patch, post, put, web, HttpResponse, Responder};
+use diesel_async::scoped_futures::ScopedFutureExt;
+
+#[get("/tags")]
+pub(super) async fn get_my_tags(
+    pool: web::Data<DbPool>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    user.require(Scope::Read)?;
+    let uid = user.user_id;
+    let user_tags =
+        storage::with_retries(&pool, |conn| find_tags_by_user(conn, &uid).scope_boxed()).await?;
+    Ok(HttpResponse::Ok().json(user_tags))
+}
+
+#[post("/tags")]
+pub(super) async fn post_tag(
+    pool: web::Data<DbPool>,
+    form: web::Json<LabelName>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    user.require(Scope::Write)?;
+    let uid = user.user_id;
+    let tag_name = match validate_label_name(&form.name) {
+        Some(tag_name) => tag_name,
+        None => return Err(ApiError::invalid("tag_name_invalid", "Invalid tag name")),
+    };
+    let tag = storage::with_retries(&pool, |conn| {
+        create_tag(conn, &uid, tag_name.clone()).scope_boxed()
+    })
+    .await?;
+    Ok(HttpResponse::Created().json(tag))
+}
+
+#[patch("/tags/{id}")]
+pub(super) async fn patch_tag(
+    pool: web::Data<DbPool>,
+    tagid_path: web::Path<(String,)>,
+    form: web::Json<LabelName>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    let tagid = tagid_path.0.clone();
+    user.require(Scope::Write)?;
+    let uid = user.user_id;
+    let tag_name = match validate_label_name(&form.name) {
+        Some(tag_name) => tag_name,
+        None => return Err(ApiError::invalid("tag_name_invalid", "Invalid tag name")),
+    };
+    storage::with_retries(&pool, |conn| {
+        rename_tag(conn, &uid, &tagid, tag_name.clone()).scope_boxed()
+    })
+    .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("/tags/{id}")]
+pub(super) async fn delete_tag_user(
+    pool: web::Data<DbPool>,
+    tagid_path: web::Path<(String,)>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    let tagid = tagid_path.0.clone();
+    user.require(Scope::Write)?;
+    let uid = user.user_id;
+    storage::with_retries(&pool, |conn| delete_tag(conn, &uid, &tagid).scope_boxed()).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[get("/folders")]
+pub(super) async fn get_my_folders(
+    pool: web::Data<DbPool>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    user.require(Scope::Read)?;
+    let uid = user.user_id;
+    let user_folders =
+        storage::with_retries(&pool, |conn| find_folders_by_user(conn, &uid).scope_boxed()).await?;
+    Ok(HttpResponse::Ok().json(user_folders))
+}
+
+#[post("/folders")]
+pub(super) async fn post_folder(
+    pool: web::Data<DbPool>,
+    form: web::Json<LabelName>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    user.require(Scope::Write)?;
+    let uid = user.user_id;
+    let folder_name = match validate_label_name(&form.name) {
+        Some(folder_name) => folder_name,
+        None => {
+            return Err(ApiError::invalid(
+                "folder_name_invalid",
+                "Invalid folder name",
+            ))
+        }
+    };
+    let folder = storage::with_retries(&pool, |conn| {
+        create_folder(conn, &uid, folder_name.clone()).scope_boxed()
+    })
+    .await?;
+    Ok(HttpResponse::Created().json(folder))
+}
+
+#[patch("/folders/{id}")]
+pub(super) async fn patch_folder(
+    pool: web::Data<DbPool>,
+    folderid_path: web::Path<(String,)>,
+    form: web::Json<LabelName>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    let folderid = folderid_path.0.clone();
+    user.require(Scope::Write)?;
+    let uid = user.user_id;
+    let folder_name = match validate_label_name(&form.name) {
+        Some(folder_name) => folder_name,
+        None => {
+            return Err(ApiError::invalid(
+                "folder_name_invalid",
+                "Invalid folder name",
+            ))
+        }
+    };
+    storage::with_retries(&pool, |conn| {
+        rename_folder(conn, &uid, &folderid, folder_name.clone()).scope_boxed()
+    })
+    .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("/folders/{id}")]
+pub(super) async fn delete_folder_user(
+    pool: web::Data<DbPool>,
+    folderid_path: web::Path<(String,)>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    let folderid = folderid_path.0.clone();
+    user.require(Scope::Write)?;
+    let uid = user.user_id;
+    storage::with_retries(&pool, |conn| {
+        delete_folder(conn, &uid, &folderid).scope_boxed()
+    })
+    .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[put("/conversation/{id}/folder")]
+pub(super) async fn put_conversation_folder(
+    pool: web::Data<DbPool>,
+    postid_path: web::Path<(String,)>,
+    form: web::Json<ConversationFolder>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    let postid = postid_path.0.clone();
+    user.require(Scope::Write)?;
+    let uid = user.user_id;
+    storage::with_retries(&pool, |conn| {
+        set_conversation_folder(conn, &uid, &postid, form.folder_id.as_ref()).scope_boxed()
+    })
+    .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[put("/conversation/{id}/tags/{tag_id}")]
+pub(super) async fn put_conversation_tag(
+    pool: web::Data<DbPool>,
+    path: web::Path<(String, String)>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    let (postid, tagid) = path.into_inner();
+    user.require(Scope::Write)?;
+    let uid = user.user_id;
+    storage::with_retries(&pool, |conn| {
+        set_conversation_tag(conn, &uid, &postid, &tagid, /*attached=*/ true).scope_boxed()
+    })
+    .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("/conversation/{id}/tags/{tag_id}")]
+pub(super) async fn delete_conversation_tag(
+    pool: web::Data<DbPool>,
+    path: web::Path<(String, String)>,
+    user: AuthenticatedUser,
+) -> Result<impl Responder, ApiError> {
+    let (postid, tagid) = path.into_inner();
+    user.require(Scope::Write)?;
+    let uid = user.user_id;
+    storage::with_retries(&pool, |conn| {
+        set_conversation_tag(conn, &uid, &postid, &tagid, /*attached=*/ false).scope_boxed()
+    })
+    .await?;
+    Ok(HttpResponse::Ok().finish())
+}
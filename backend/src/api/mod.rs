@@ -0,0 +1,110 @@
+// HTTP API
+//
+// Handlers live in the submodules by topic, `configure` registers all of them.
+// Every handler answers errors with an `ApiError` (see errors.rs).
+// Conversations go through a ConversationStore, everything else (tags, folders,
+// bulk operations, local accounts, API tokens) needs the Postgres pool. Without
+// one those endpoints answer 501.
+
+use actix_web::{web, HttpResponse};
+use errors::ApiError;
+
+mod accounts;
+mod conversations;
+pub mod errors;
+mod labels;
+pub mod page_cache;
+
+// Main AppData
+pub struct AppState {
+    pub auth: crate::auth::AuthProviders,
+    pub mailer: Box<dyn crate::mailer::Mailer>,
+    // Where the site is served from, used to build links in emails
+    pub public_url: String,
+    // Conversations a user without a paid plan can share
+    pub max_free_user_count: i64,
+    // How long the session cookie lasts
+    pub session_ttl_days: i64,
+    // Least time between links mailed to one address
+    pub magic_link_cooldown_secs: i64,
+    // Shared conversation pages
+    pub pages: crate::render::PageRenderer,
+    // Conversations already rendered as JSON and HTML
+    pub page_cache: page_cache::PageCache,
+}
+
+// Paths of the endpoints that need the Postgres pool
+const DATABASE_PATHS: &[&str] = &[
+    "/local/register",
+    "/local/login",
+    "/local/password",
+    "/local/magic-link",
+    "/local/magic-link/{token}",
+    "/tokens",
+    "/tokens/{id}",
+    "/conversations/bulk",
+    "/tags",
+    "/tags/{id}",
+    "/folders",
+    "/folders/{id}",
+    "/conversation/{id}/folder",
+    "/conversation/{id}/tags/{tag_id}",
+];
+
+async fn not_available() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::NotAvailable)
+}
+
+// Register all API endpoints, `database` tells if there is a Postgres pool
+pub fn configure(cfg: &mut web::ServiceConfig, database: bool) {
+    // Bodies and query strings that don't parse get the same JSON errors as the rest
+    cfg.app_data(
+        web::JsonConfig::default().error_handler(|err, _req| {
+            ApiError::bad_request("body_invalid", err.to_string()).into()
+        }),
+    )
+    .app_data(
+        web::QueryConfig::default().error_handler(|err, _req| {
+            ApiError::bad_request("query_invalid", err.to_string()).into()
+        }),
+    );
+    cfg.service(conversations::get_conversation_json)
+        .service(conversations::get_conversation_html)
+        .service(conversations::post_conversation)
+        .service(conversations::delete_conversation)
+        .service(conversations::undelete_conversation)
+        .service(conversations::get_my_conversations)
+        .service(conversations::get_conversation_count_user)
+        .service(conversations::patch_conversation)
+        .service(accounts::authenticate)
+        .service(accounts::authenticated)
+        .service(accounts::logout)
+        .service(accounts::get_metrics);
+    if !database {
+        for path in DATABASE_PATHS {
+            cfg.route(path, web::route().to(not_available));
+        }
+        return;
+    }
+    cfg.service(accounts::local_register)
+        .service(accounts::local_set_password)
+        .service(accounts::local_login)
+        .service(accounts::local_magic_link)
+        .service(accounts::local_magic_link_page)
+        .service(accounts::local_magic_link_login)
+        .service(accounts::get_my_tokens)
+        .service(accounts::post_token)
+        .service(accounts::delete_token)
+        .service(conversations::bulk_conversations)
+        .service(labels::get_my_tags)
+        .service(labels::post_tag)
+        .service(labels::patch_tag)
+        .service(labels::delete_tag_user)
+        .service(labels::get_my_folders)
+        .service(labels::post_folder)
+        .service(labels::patch_folder)
+        .service(labels::delete_folder_user)
+        .service(labels::put_conversation_folder)
+        .service(labels::put_conversation_tag)
+        .service(labels::delete_conversation_tag);
+}
@@ -0,0 +1,221 @@
+// Rendered conversations kept in memory
+//
+// Popular shared conversations are viewed over and over, so the JSON and HTML
+// bodies are kept per conversation id together with the content digest (hmac)
+// they were made from. Handlers that change a conversation invalidate its
+// entry. Every body has an ETag (hash of the body, or the conversation version
+// for JSON) and a Last-Modified time so browsers and the nginx microcache can
+// revalidate with a 304.
+//
+// A page read from the store before an invalidation is not cached after it:
+// `generation` is taken before reading and `insert` drops the page when any
+// invalidation happened in between.
+
+use actix_web::http::header::{self, EntityTag, HttpDate};
+use actix_web::web::Bytes;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageKind {
+    Json,
+    Html,
+}
+
+impl PageKind {
+    fn content_type(&self) -> &'static str {
+        match self {
+            PageKind::Json => "application/json",
+            PageKind::Html => "text/html; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedPage {
+    pub kind: PageKind,
+    pub body: Bytes,
+    pub etag: EntityTag,
+    pub last_modified: DateTime<Utc>,
+}
+
+impl CachedPage {
+    pub fn new(kind: PageKind, body: impl Into<Bytes>, last_modified: DateTime<Utc>) -> CachedPage {
+        let body = body.into();
+        let etag = EntityTag::new_strong(format!("{:x}", Sha256::digest(&body)));
+        CachedPage::with_etag(kind, body, etag, last_modified)
+    }
+
+    // Page whose ETag means something to clients, like a version for If-Match
+    pub fn with_etag(
+        kind: PageKind,
+        body: impl Into<Bytes>,
+        etag: EntityTag,
+        last_modified: DateTime<Utc>,
+    ) -> CachedPage {
+        let body = body.into();
+        CachedPage {
+            kind,
+            body,
+            etag,
+            // Headers only carry whole seconds, compare with what clients send back
+            last_modified: last_modified.trunc_subsecs(0),
+        }
+    }
+
+    // 304 when the client already has this page, the page otherwise
+    pub fn respond_to(&self, req: &HttpRequest) -> HttpResponse {
+        let last_modified = HttpDate::from(std::time::SystemTime::from(self.last_modified));
+        let not_modified = match req.get_header::<header::IfNoneMatch>() {
+            Some(header::IfNoneMatch::Any) => true,
+            Some(header::IfNoneMatch::Items(etags)) => {
+                etags.iter().any(|etag| etag.weak_eq(&self.etag))
+            }
+            // If-Modified-Since only counts without If-None-Match
+            None => req
+                .get_header::<header::IfModifiedSince>()
+                .is_some_and(|since| since.0 >= last_modified),
+        };
+        let mut resp = if not_modified {
+            HttpResponse::NotModified()
+        } else {
+            HttpResponse::Ok()
+        };
+        resp.insert_header(header::ETag(self.etag.clone()))
+            .insert_header(header::LastModified(last_modified))
+            // Caches may keep it but have to ask again before using it
+            .insert_header(header::CacheControl(vec![header::CacheDirective::NoCache]));
+        if not_modified {
+            resp.finish()
+        } else {
+            resp.content_type(self.kind.content_type())
+                .body(self.body.clone())
+        }
+    }
+}
+
+struct Entry {
+    digest: String,
+    json: Option<CachedPage>,
+    html: Option<CachedPage>,
+    last_used: Instant,
+}
+
+impl Entry {
+    fn page(&mut self, kind: PageKind) -> &mut Option<CachedPage> {
+        match kind {
+            PageKind::Json => &mut self.json,
+            PageKind::Html => &mut self.html,
+        }
+    }
+}
+
+// When full, the conversation used least recently is dropped
+pub struct PageCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    capacity: usize,
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PageCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> PageCache {
+        PageCache {
+            entries: Mutex::new(HashMap::new()),
+            capacity,
+            generation: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, HashMap<String, Entry>> {
+        // Entries are replaced whole, a panic elsewhere can't leave one half made
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn get(&self, id: &str, kind: PageKind) -> Option<CachedPage> {
+        let mut entries = self.entries();
+        let found = entries.get_mut(id).and_then(|entry| {
+            entry.last_used = Instant::now();
+            entry.page(kind).clone()
+        });
+        match found {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        found
+    }
+
+    // Take before reading the conversation, pass to `insert`
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    // Keep page made from the conversation with this digest
+    pub fn insert(&self, id: &str, digest: &str, generation: u64, page: CachedPage) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries();
+        // Checked under the lock, invalidations take it too
+        if self.generation() != generation {
+            return;
+        }
+        if !entries.contains_key(id) && entries.len() >= self.capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = oldest {
+                entries.remove(&key);
+            }
+        }
+        let entry = entries.entry(id.to_string()).or_insert_with(|| Entry {
+            digest: digest.to_string(),
+            json: None,
+            html: None,
+            last_used: Instant::now(),
+        });
+        // Pages of other contents are useless now
+        if entry.digest != digest {
+            entry.digest = digest.to_string();
+            entry.json = None;
+            entry.html = None;
+        }
+        entry.last_used = Instant::now();
+        let kind = page.kind;
+        *entry.page(kind) = Some(page);
+    }
+
+    // Forget pages of a conversation that was changed
+    pub fn invalidate(&self, id: &str) {
+        let mut entries = self.entries();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        entries.remove(id);
+    }
+
+    pub fn stats(&self) -> PageCacheStats {
+        PageCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.entries().len(),
+        }
+    }
+}
@@ -0,0 +1,311 @@
+// Local accounts for people who can't (or don't want to) log in with Google
+//
+// Accounts are keyed by email address. Passwords are optional and stored as
+// argon2 hashes. Magic links are single use tokens sent by email; only the
+// sha256 of the token is stored so a leaked table can't be used to log in.
+// Nothing is done for an address until a link sent to it is opened: registering
+// only sends a link carrying the new password, and the account is made (or
+// given the password) when it is used.
+
+use crate::schema::{local_accounts, magic_links};
+use crate::storage::{DbConnection, LocalError};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::Engine;
+use chrono::offset::Utc;
+use chrono::DateTime;
+use diesel::prelude::*;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+const MAGIC_LINK_MINUTES: i64 = 15;
+const MAGIC_LINK_TOKEN_BYTES: usize = 32;
+
+#[derive(Queryable, Insertable)]
+#[diesel(table_name = local_accounts)]
+pub struct LocalAccount {
+    pub id: String,
+    pub email: String,
+    pub password_hash: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl LocalAccount {
+    // Session user id, prefixed so it never collides with Google or OIDC ids
+    pub fn user_id(&self) -> String {
+        format!("local:{}", self.id)
+    }
+}
+
+// Account id in a session user id made by `LocalAccount::user_id`
+pub fn account_id(user_id: &str) -> Option<&str> {
+    user_id.strip_prefix("local:")
+}
+
+#[derive(Queryable, Insertable)]
+#[diesel(table_name = magic_links)]
+struct MagicLink {
+    token_hash: String,
+    email: String,
+    expires_at: DateTime<Utc>,
+    used: bool,
+    password_hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Credentials {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewPassword {
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkRequest {
+    pub email: String,
+}
+
+// Trim and lowercase, reject things that obviously aren't addresses
+pub fn normalize_email(email: &str) -> Option<String> {
+    let email = email.trim().to_lowercase();
+    match email.split_once('@') {
+        Some((local, domain))
+            if !local.is_empty()
+                && !domain.is_empty()
+                && !email.contains(char::is_whitespace)
+                && email.len() <= 254 =>
+        {
+            Some(email)
+        }
+        _ => None,
+    }
+}
+
+// Slow on purpose, run it inside web::block
+pub fn hash_password(password: &str) -> Result<String, LocalError> {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).map_err(|_| LocalError::PasswordHash)?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| LocalError::PasswordHash)
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub async fn find_account_by_email(
+    conn: &mut DbConnection,
+    address: &str,
+) -> Result<Option<LocalAccount>, LocalError> {
+    use crate::schema::local_accounts::dsl::*;
+    Ok(local_accounts
+        .filter(email.eq(address))
+        .first::<LocalAccount>(conn)
+        .await
+        .optional()?)
+}
+
+async fn create_account(
+    conn: &mut DbConnection,
+    address: &str,
+    hash: Option<String>,
+) -> Result<LocalAccount, LocalError> {
+    let account = LocalAccount {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        email: address.to_string(),
+        password_hash: hash,
+        created_at: Utc::now(),
+    };
+    diesel::insert_into(local_accounts::table)
+        .values(&account)
+        .execute(conn)
+        .await?;
+    Ok(account)
+}
+
+// Link to mail after a registration
+pub enum Registration {
+    // Opening it makes the account with the password
+    New(String),
+    // The address has an account already: a plain login link for its owner,
+    // the password is dropped
+    Existing(String),
+}
+
+// Start registering with password (hashed by `hash_password`)
+// Returns the token of the magic link to send, opening it makes the account.
+// Existing accounts, with a password or not, can't be registered again:
+// passwords are added to them with `set_password` after logging in. They get
+// a login link instead, so the answer doesn't tell who has an account.
+// Nothing when the address got a link less than `cooldown` ago.
+pub async fn register(
+    conn: &mut DbConnection,
+    address: &str,
+    hash: &str,
+    cooldown: chrono::Duration,
+) -> Result<Option<Registration>, LocalError> {
+    if recently_sent(conn, address, cooldown).await? {
+        return Ok(None);
+    }
+    if find_account_by_email(conn, address).await?.is_some() {
+        return Ok(Some(Registration::Existing(
+            insert_magic_link(conn, address, None).await?,
+        )));
+    }
+    Ok(Some(Registration::New(
+        insert_magic_link(conn, address, Some(hash.to_string())).await?,
+    )))
+}
+
+// Give an account without password one
+// The caller is logged in to the account, so opened a link sent to it.
+// Changing a password would need the old one, so that is refused.
+pub async fn set_password(
+    conn: &mut DbConnection,
+    account: &str,
+    hash: &str,
+) -> Result<(), LocalError> {
+    use crate::schema::local_accounts::dsl::*;
+    let updated = diesel::update(
+        local_accounts
+            .filter(id.eq(account))
+            .filter(password_hash.is_null()),
+    )
+    .set(password_hash.eq(hash))
+    .execute(conn)
+    .await?;
+    if updated == 0 {
+        return Err(LocalError::AlreadyExists);
+    }
+    Ok(())
+}
+
+// Account found by `find_account_by_email` if the password is right
+// Slow on purpose like `hash_password`, run it inside web::block.
+pub fn check_password(
+    account: Option<LocalAccount>,
+    password: &str,
+) -> Result<LocalAccount, LocalError> {
+    match account {
+        Some(account)
+            if account
+                .password_hash
+                .as_deref()
+                .is_some_and(|hash| verify_password(password, hash)) =>
+        {
+            Ok(account)
+        }
+        _ => Err(LocalError::AuthorizationProblem),
+    }
+}
+
+// Make new magic link token for email address, returns the token to send
+// Nothing when the address got a link less than `cooldown` ago.
+pub async fn create_magic_link(
+    conn: &mut DbConnection,
+    address: &str,
+    cooldown: chrono::Duration,
+) -> Result<Option<String>, LocalError> {
+    if recently_sent(conn, address, cooldown).await? {
+        return Ok(None);
+    }
+    Ok(Some(insert_magic_link(conn, address, None).await?))
+}
+
+// Whether a link for the address was made less than `cooldown` ago
+// Links don't keep when they were made, but they expire MAGIC_LINK_MINUTES after it.
+async fn recently_sent(
+    conn: &mut DbConnection,
+    address: &str,
+    cooldown: chrono::Duration,
+) -> Result<bool, LocalError> {
+    use crate::schema::magic_links::dsl::*;
+    let made_after = Utc::now() - cooldown;
+    Ok(diesel::select(diesel::dsl::exists(
+        magic_links
+            .filter(email.eq(address))
+            .filter(expires_at.gt(made_after + chrono::Duration::minutes(MAGIC_LINK_MINUTES))),
+    ))
+    .get_result::<bool>(conn)
+    .await?)
+}
+
+async fn insert_magic_link(
+    conn: &mut DbConnection,
+    address: &str,
+    hash: Option<String>,
+) -> Result<String, LocalError> {
+    let mut bytes = [0u8; MAGIC_LINK_TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+    let link = MagicLink {
+        token_hash: hash_token(&token),
+        email: address.to_string(),
+        expires_at: Utc::now() + chrono::Duration::minutes(MAGIC_LINK_MINUTES),
+        used: false,
+        password_hash: hash,
+    };
+    diesel::insert_into(magic_links::table)
+        .values(&link)
+        .execute(conn)
+        .await?;
+    Ok(token)
+}
+
+// Use up magic link token, find or create the account for its email address
+// A link sent when registering gives the account its password, unless it got
+// one in the meantime.
+pub async fn consume_magic_link(
+    conn: &mut DbConnection,
+    token: &str,
+) -> Result<LocalAccount, LocalError> {
+    use crate::schema::magic_links::dsl::*;
+    conn.transaction(|conn| {
+        async move {
+            // Marking as used in the same statement that checks it makes the link single use
+            let (address, hash) = diesel::update(
+                magic_links
+                    .filter(token_hash.eq(hash_token(token)))
+                    .filter(used.eq(false))
+                    .filter(expires_at.gt(Utc::now())),
+            )
+            .set(used.eq(true))
+            .returning((email, password_hash))
+            .get_result::<(String, Option<String>)>(conn)
+            .await
+            .optional()?
+            .ok_or(LocalError::AuthorizationProblem)?;
+            match find_account_by_email(conn, &address).await? {
+                Some(mut account) => {
+                    if let (Some(hash), None) = (hash, &account.password_hash) {
+                        set_password(conn, &account.id, &hash).await?;
+                        account.password_hash = Some(hash);
+                    }
+                    Ok(account)
+                }
+                None => create_account(conn, &address, hash).await,
+            }
+        }
+        .scope_boxed()
+    })
+    .await
+}
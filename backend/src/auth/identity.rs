@@ -0,0 +1,210 @@
+// Who is making a request
+//
+// Handlers take an `AuthenticatedUser` argument instead of looking at the
+// session cookie or the Authorization header themselves. A user can be
+// authenticated by:
+//   - the session cookie set by /authenticate or the local account logins
+//   - a personal API token sent as "Authorization: Bearer sp_..."
+//   - a Google access token sent as "Authorization: Bearer ..." (extension)
+// A bearer token, when present, wins over the cookie. Requests without valid
+// credentials get the same 401 response everywhere.
+//
+// Failures become an `ApiError` with a machine readable `code` and a
+// WWW-Authenticate header as described in RFC 6750, so clients can tell
+// whether to log in again or whether something is wrong on the server.
+
+use crate::api::errors::ApiError;
+use crate::api::AppState;
+use crate::auth::tokens::{self, Scope};
+use crate::auth::TokenError;
+use crate::storage::{self, DbPool};
+use actix_session::Session;
+use actix_web::http::StatusCode;
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use log::info;
+use std::future::Future;
+use std::pin::Pin;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    Session,
+    ApiToken,
+    AccessToken,
+}
+
+pub struct AuthenticatedUser {
+    pub user_id: String,
+    pub method: AuthMethod,
+    scopes: Vec<Scope>,
+}
+
+const REALM: &str = "shareconversation";
+
+#[derive(Debug)]
+pub enum AuthError {
+    // No credentials at all (or an unusable session cookie)
+    Missing,
+    // Bearer or identity token that was not accepted
+    Token(TokenError),
+    UnknownApiToken,
+    InsufficientScope(Scope),
+    SessionRequired,
+    // Wrong email or password for a local account
+    LoginFailed,
+    MagicLinkInvalid,
+}
+
+impl AuthError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::Missing => "credentials_missing",
+            AuthError::Token(err) => err.code(),
+            AuthError::UnknownApiToken => "api_token_invalid",
+            AuthError::InsufficientScope(_) => "insufficient_scope",
+            AuthError::SessionRequired => "session_required",
+            AuthError::LoginFailed => "login_failed",
+            AuthError::MagicLinkInvalid => "magic_link_invalid",
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Token(err) if err.is_server_problem() => StatusCode::SERVICE_UNAVAILABLE,
+            AuthError::InsufficientScope(_) | AuthError::SessionRequired => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    // Error code from RFC 6750 section 3.1, if there is one that fits
+    pub fn bearer_error(&self) -> Option<&'static str> {
+        match self {
+            AuthError::Missing
+            | AuthError::SessionRequired
+            | AuthError::LoginFailed
+            | AuthError::MagicLinkInvalid => None,
+            AuthError::Token(err) if err.is_server_problem() => None,
+            AuthError::Token(_) | AuthError::UnknownApiToken => Some("invalid_token"),
+            AuthError::InsufficientScope(_) => Some("insufficient_scope"),
+        }
+    }
+
+    // Value of the WWW-Authenticate header, none when the problem is on our side
+    pub fn challenge(&self) -> Option<String> {
+        if self.status_code() == StatusCode::SERVICE_UNAVAILABLE {
+            return None;
+        }
+        let mut challenge = format!("Bearer realm=\"{}\"", REALM);
+        if let Some(bearer_error) = self.bearer_error() {
+            challenge.push_str(&format!(
+                ", error=\"{}\", error_description=\"{}\"",
+                bearer_error, self
+            ));
+        }
+        if let AuthError::InsufficientScope(scope) = self {
+            challenge.push_str(&format!(", scope=\"{}\"", scope.as_str()));
+        }
+        Some(challenge)
+    }
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "Authentication is required"),
+            AuthError::Token(err) => write!(f, "{}", err),
+            AuthError::UnknownApiToken => write!(f, "The API token is unknown or revoked"),
+            AuthError::InsufficientScope(scope) => {
+                write!(f, "The token does not have the {} scope", scope.as_str())
+            }
+            AuthError::SessionRequired => write!(f, "This requires logging in on the website"),
+            AuthError::LoginFailed => write!(f, "Wrong email or password"),
+            AuthError::MagicLinkInvalid => write!(f, "Login link is invalid or expired"),
+        }
+    }
+}
+
+impl AuthenticatedUser {
+    // Fail with 403 unless the credentials allow `scope`
+    pub fn require(&self, scope: Scope) -> Result<(), ApiError> {
+        if self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            Err(AuthError::InsufficientScope(scope).into())
+        }
+    }
+
+    // Fail with 403 unless the user logged in with the session cookie
+    // Used for things a token must not be able to do, like making more tokens.
+    pub fn require_session(&self) -> Result<(), ApiError> {
+        if self.method == AuthMethod::Session {
+            Ok(())
+        } else {
+            Err(AuthError::SessionRequired.into())
+        }
+    }
+}
+
+async fn authenticate_request(req: HttpRequest) -> Result<AuthenticatedUser, ApiError> {
+    let bearer = match BearerAuth::extract(&req).await {
+        Ok(bearer) => bearer,
+        Err(_) => {
+            let session = Session::extract(&req).await?;
+            return match session.get::<String>("user_id")? {
+                Some(user_id) => Ok(AuthenticatedUser {
+                    user_id,
+                    method: AuthMethod::Session,
+                    scopes: Scope::all(),
+                }),
+                None => Err(AuthError::Missing.into()),
+            };
+        }
+    };
+    let token = bearer.token().to_string();
+    if tokens::is_api_token(&token) {
+        // API tokens are kept in Postgres, there are none with the other stores
+        let Some(pool) = req.app_data::<web::Data<DbPool>>().cloned() else {
+            info!("API token used without a Postgres database");
+            return Err(AuthError::UnknownApiToken.into());
+        };
+        let found = storage::with_retries(&pool, |conn| {
+            tokens::check_token(conn, &token).scope_boxed()
+        })
+        .await?;
+        return match found {
+            Some((user_id, scopes)) => Ok(AuthenticatedUser {
+                user_id,
+                method: AuthMethod::ApiToken,
+                scopes,
+            }),
+            None => {
+                info!("Unknown or revoked API token");
+                Err(AuthError::UnknownApiToken.into())
+            }
+        };
+    }
+    let state = req
+        .app_data::<web::Data<AppState>>()
+        .expect("AppState should be in app data");
+    match state.auth.validate_access_token(&token).await {
+        Ok(user_id) => Ok(AuthenticatedUser {
+            user_id,
+            method: AuthMethod::AccessToken,
+            scopes: Scope::all(),
+        }),
+        Err(err) => {
+            info!("Access token not accepted, err={:?}", err);
+            Err(AuthError::Token(err).into())
+        }
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = ApiError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        Box::pin(authenticate_request(req.clone()))
+    }
+}
@@ -0,0 +1,191 @@
+// Public keys (JWKS) of an identity token issuer
+//
+// Reads never wait on the network or on a lock: the current key set is swapped
+// in atomically whenever a fetch succeeds. A background task refetches the keys
+// when the max-age from the Cache-Control header runs out. A token signed with a
+// key we don't know yet triggers an immediate refetch, but not more often than
+// every MIN_REFETCH_SECONDS. If fetching fails the old keys keep being used.
+
+use arc_swap::ArcSwap;
+use log::info;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+// How long to wait before trying again after a failed fetch
+const RETRY_SECONDS: u64 = 60;
+// Rate limit for fetches caused by unknown key ids
+const MIN_REFETCH_SECONDS: u64 = 30;
+
+// How long fetched keys are used before fetching them again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JwksExpiry {
+    // When the issuer does not send a usable max-age
+    pub default_secs: u64,
+    // Bounds for the max-age sent by the issuer
+    pub min_secs: u64,
+    pub max_secs: u64,
+}
+
+impl Default for JwksExpiry {
+    fn default() -> JwksExpiry {
+        JwksExpiry {
+            default_secs: 60 * 60 * 5,
+            min_secs: 60,
+            max_secs: 60 * 60 * 24,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonWebKey {
+    kid: String,
+    n: String,
+    e: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonWebKeySetResponse {
+    keys: Vec<JsonWebKey>,
+}
+
+#[derive(Debug)]
+pub enum JWKSError {
+    Retrieval,
+    DecodingKeyError,
+    NotFound,
+}
+
+pub struct JwksCache {
+    url: String,
+    expiry: JwksExpiry,
+    keys: ArcSwap<std::collections::HashMap<String, JsonWebKey>>,
+    // Held while fetching so concurrent misses cause one fetch, not many
+    fetching: tokio::sync::Mutex<()>,
+    last_fetch: AtomicU64,
+    // When the background task should fetch next (epoch seconds)
+    refresh_at: AtomicU64,
+    refresh_started: AtomicBool,
+}
+
+fn get_epoch_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards from UNIX_EPOCH")
+        .as_secs()
+}
+
+// Find max-age in Cache-Control header value
+fn parse_max_age(cache_control: &str) -> Option<u64> {
+    cache_control
+        .split(',')
+        .filter_map(|directive| directive.trim().strip_prefix("max-age="))
+        .find_map(|seconds| seconds.trim().parse().ok())
+}
+
+impl JwksCache {
+    pub fn new(url: String, expiry: JwksExpiry) -> Arc<JwksCache> {
+        Arc::new(JwksCache {
+            url,
+            expiry,
+            keys: ArcSwap::from_pointee(std::collections::HashMap::new()),
+            fetching: tokio::sync::Mutex::new(()),
+            last_fetch: AtomicU64::new(0),
+            refresh_at: AtomicU64::new(0),
+            refresh_started: AtomicBool::new(false),
+        })
+    }
+
+    // Get keys from the issuer and swap them in
+    // Must be called with the fetching lock held.
+    async fn fetch(&self) -> Result<(), JWKSError> {
+        info!("Refreshing public keys from {}", self.url);
+        let now = get_epoch_time();
+        self.last_fetch.store(now, Ordering::Relaxed);
+        // Until this fetch succeeds, try again after a short wait
+        self.refresh_at
+            .store(now + RETRY_SECONDS, Ordering::Relaxed);
+        let client = awc::Client::new();
+        let mut res = client.get(&self.url).send().await.map_err(|err| {
+            info!("Could not fetch public keys from {}, err={}", self.url, err);
+            JWKSError::Retrieval
+        })?;
+        if !res.status().is_success() {
+            info!("Fetching public keys gave status {}", res.status());
+            return Err(JWKSError::Retrieval);
+        }
+        let max_age = res
+            .headers()
+            .get("cache-control")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_max_age)
+            .map(|seconds| seconds.clamp(self.expiry.min_secs, self.expiry.max_secs))
+            .unwrap_or(self.expiry.default_secs);
+        let payload = res.json::<JsonWebKeySetResponse>().await.map_err(|err| {
+            info!("Could not parse public keys, err={}", err);
+            JWKSError::Retrieval
+        })?;
+        let keys = payload
+            .keys
+            .into_iter()
+            .map(|key| (key.kid.clone(), key))
+            .collect::<std::collections::HashMap<_, _>>();
+        info!("Got {} public keys, good for {}s", keys.len(), max_age);
+        self.keys.store(Arc::new(keys));
+        self.refresh_at.store(now + max_age, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Keep keys fresh until the runtime shuts down
+    fn start_refresh(self: &Arc<Self>) {
+        if self.refresh_started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let cache = Arc::clone(self);
+        actix_web::rt::spawn(async move {
+            loop {
+                let wait = cache
+                    .refresh_at
+                    .load(Ordering::Relaxed)
+                    .saturating_sub(get_epoch_time());
+                if wait > 0 {
+                    actix_web::rt::time::sleep(std::time::Duration::from_secs(wait)).await;
+                    continue;
+                }
+                let _fetching = cache.fetching.lock().await;
+                // Someone else may have fetched while we waited for the lock
+                if cache.refresh_at.load(Ordering::Relaxed) <= get_epoch_time() {
+                    let _ = cache.fetch().await;
+                }
+            }
+        });
+    }
+
+    // Fetch again because a key id was missing, unless that was just done
+    async fn refetch(&self) {
+        let _fetching = self.fetching.lock().await;
+        let since = get_epoch_time().saturating_sub(self.last_fetch.load(Ordering::Relaxed));
+        if since < MIN_REFETCH_SECONDS {
+            return;
+        }
+        let _ = self.fetch().await;
+    }
+
+    pub async fn key(self: &Arc<Self>, kid: &str) -> Result<jsonwebtoken::DecodingKey, JWKSError> {
+        self.start_refresh();
+        if !self.keys.load().contains_key(kid) {
+            info!("No public key with kid={}, fetching keys", kid);
+            self.refetch().await;
+        }
+        let keys = self.keys.load();
+        // With no keys at all the issuer was never reachable, that is not the
+        // token's fault
+        let key = keys.get(kid).ok_or(if keys.is_empty() {
+            JWKSError::Retrieval
+        } else {
+            JWKSError::NotFound
+        })?;
+        jsonwebtoken::DecodingKey::from_rsa_components(&key.n, &key.e)
+            .map_err(|_err| JWKSError::DecodingKeyError)
+    }
+}
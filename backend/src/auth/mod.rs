@@ -0,0 +1,589 @@
+// Authentication providers
+// Each provider knows how to turn a bearer token into a user_id.
+// Google is the default provider, generic OIDC issuers can be configured too.
+
+pub mod accounts;
+pub mod identity;
+pub mod jwks;
+pub mod tokens;
+
+use async_trait::async_trait;
+use jwks::{JWKSError, JwksCache, JwksExpiry};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const GOOGLE_ISSUERS: [&str; 2] = ["https://accounts.google.com", "accounts.google.com"];
+const GOOGLE_KEYS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+const GOOGLE_TOKENINFO_URL: &str = "https://www.googleapis.com/oauth2/v1/tokeninfo";
+
+// How many validated access tokens to remember
+const ACCESS_TOKEN_CACHE_SIZE: usize = 10_000;
+// Never trust a cached access token for longer than this, whatever expires_in says
+const ACCESS_TOKEN_CACHE_MAX_SECONDS: u64 = 60 * 60;
+
+// Audience can be a single string or a list of strings
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, audience: &str) -> bool {
+        match self {
+            Audience::One(aud) => aud == audience,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == audience),
+        }
+    }
+}
+
+// JWT stuff
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    iss: String,
+    aud: Audience,
+    sub: String,
+    nbf: Option<u64>,
+    exp: u64,
+}
+
+// Only the issuer, read before we know which provider should check the token
+#[derive(Debug, Deserialize)]
+struct UnverifiedClaims {
+    iss: String,
+}
+
+#[derive(Debug)]
+pub enum TokenError {
+    Invalid,
+    DecodeError,
+    NotValidBefore,
+    Expired,
+    AudienceMismatch,
+    Issuer,
+    UnknownKey,
+    JWKSProblem,
+    ProviderUnavailable,
+}
+
+impl TokenError {
+    // Machine readable code sent to clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            TokenError::Invalid => "token_invalid",
+            TokenError::DecodeError => "token_signature_invalid",
+            TokenError::NotValidBefore => "token_not_yet_valid",
+            TokenError::Expired => "token_expired",
+            TokenError::AudienceMismatch => "token_audience_mismatch",
+            TokenError::Issuer => "token_issuer_unknown",
+            TokenError::UnknownKey => "token_key_unknown",
+            TokenError::JWKSProblem => "keys_unavailable",
+            TokenError::ProviderUnavailable => "provider_unavailable",
+        }
+    }
+
+    // Problems on our side (or the identity provider's), getting a new token won't help
+    pub fn is_server_problem(&self) -> bool {
+        matches!(
+            self,
+            TokenError::JWKSProblem | TokenError::ProviderUnavailable
+        )
+    }
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TokenError::Invalid => write!(f, "The token is malformed or was not accepted"),
+            TokenError::DecodeError => write!(f, "The token signature is not valid"),
+            TokenError::NotValidBefore => write!(f, "The token is not valid yet"),
+            TokenError::Expired => write!(f, "The token has expired"),
+            TokenError::AudienceMismatch => write!(f, "The token was issued for another audience"),
+            TokenError::Issuer => write!(f, "The token issuer is not accepted"),
+            TokenError::UnknownKey => write!(f, "The token was signed with an unknown key"),
+            TokenError::JWKSProblem => write!(f, "The identity provider keys are unavailable"),
+            TokenError::ProviderUnavailable => write!(f, "The identity provider is unavailable"),
+        }
+    }
+}
+
+fn get_epoch_time() -> u64 {
+    let start = std::time::SystemTime::now();
+    start
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards from UNIX_EPOCH")
+        .as_secs()
+}
+
+// Extract needed key id from token without validating anything
+fn get_kid(token: &str) -> Result<String, TokenError> {
+    let header = jsonwebtoken::decode_header(token).map_err(|_err| TokenError::Invalid)?;
+    match header.kid {
+        Some(key_id) => Ok(key_id),
+        None => Err(TokenError::Invalid),
+    }
+}
+
+// Extract issuer from token without validating anything
+fn get_issuer(token: &str) -> Result<String, TokenError> {
+    let mut validation = jsonwebtoken::Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.required_spec_claims.clear();
+    let token_message = jsonwebtoken::decode::<UnverifiedClaims>(
+        token,
+        &jsonwebtoken::DecodingKey::from_secret(&[]),
+        &validation,
+    )
+    .map_err(|_err| TokenError::Invalid)?;
+    Ok(token_message.claims.iss)
+}
+
+// Validates signed JWT identity tokens against the keys of one issuer
+struct JwtValidator {
+    issuers: Vec<String>,
+    audience: String,
+    jwks: std::sync::Arc<JwksCache>,
+}
+
+impl JwtValidator {
+    fn new(
+        issuers: Vec<String>,
+        audience: String,
+        jwks_url: String,
+        jwks_expiry: JwksExpiry,
+    ) -> JwtValidator {
+        JwtValidator {
+            issuers,
+            audience,
+            jwks: JwksCache::new(jwks_url, jwks_expiry),
+        }
+    }
+
+    fn handles_issuer(&self, issuer: &str) -> bool {
+        self.issuers.iter().any(|iss| iss == issuer)
+    }
+
+    // Validate identity token and return the subject
+    async fn validate(&self, token: &str) -> Result<String, TokenError> {
+        let kid = get_kid(token)?;
+        let decoding_key = self.jwks.key(&kid).await.map_err(|err| {
+            info!("Could not get public key, err={:?}", err);
+            match err {
+                JWKSError::NotFound => TokenError::UnknownKey,
+                _ => TokenError::JWKSProblem,
+            }
+        })?;
+        // Only check the signature here, the claims are checked below so that
+        // the caller learns why a token was refused
+        let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::RS256);
+        validation.validate_exp = false;
+        let token_message = jsonwebtoken::decode::<Claims>(token, &decoding_key, &validation)
+            .map_err(|_err| TokenError::DecodeError)?;
+        info!(
+            "Decoded claims in JWT, user_id={}",
+            token_message.claims.sub
+        );
+        let since_the_epoch = get_epoch_time();
+        let slop = 2; // Allow a few seconds of wiggle room for clock skew
+        if let Some(nbf) = token_message.claims.nbf {
+            if since_the_epoch + slop < nbf {
+                info!(
+                    "Current time is too early for bearer token, nbf={} time={}",
+                    nbf, since_the_epoch
+                );
+                return Err(TokenError::NotValidBefore);
+            }
+        }
+        if since_the_epoch > token_message.claims.exp {
+            info!(
+                "Current time is too late for bearer token, exp={} time={}",
+                token_message.claims.exp, since_the_epoch
+            );
+            return Err(TokenError::Expired);
+        }
+        if !token_message.claims.aud.contains(&self.audience) {
+            info!(
+                "Audience does not match token audience, aud={:?} audience={}",
+                token_message.claims.aud, self.audience
+            );
+            return Err(TokenError::AudienceMismatch);
+        }
+        if !self.handles_issuer(&token_message.claims.iss) {
+            info!(
+                "Token issuer was not one of {:?}, iss={}",
+                self.issuers, token_message.claims.iss
+            );
+            return Err(TokenError::Issuer);
+        }
+        Ok(token_message.claims.sub)
+    }
+}
+
+// Owner of a validated access token
+// expires_in is the remaining lifetime in seconds, when the provider tells us.
+pub struct AccessToken {
+    pub user_id: String,
+    pub expires_in: Option<u64>,
+}
+
+// Something that can tell us who the owner of a bearer token is
+#[async_trait(?Send)]
+pub trait AuthProvider: Send + Sync {
+    // Short name used in logs
+    fn name(&self) -> &str;
+
+    // Whether identity tokens with this `iss` claim are for this provider
+    fn handles_issuer(&self, issuer: &str) -> bool;
+
+    // Validate identity token (JWT) and return user_id
+    async fn validate_identity_token(&self, token: &str) -> Result<String, TokenError>;
+
+    // Validate opaque access token and return who it belongs to
+    // Providers without a way to check access tokens reject them.
+    async fn validate_access_token(&self, _token: &str) -> Result<AccessToken, TokenError> {
+        Err(TokenError::Invalid)
+    }
+}
+
+// Google accounts
+// Website sends id tokens (from GIS button), extension sends access tokens
+// (from chrome.identity.getAuthToken()).
+pub struct GoogleProvider {
+    jwt: JwtValidator,
+    tokeninfo_url: String,
+}
+
+// Where Google lives and who tokens should be for
+// Defaults are the real Google endpoints, tests point them at a local server.
+#[derive(Debug, Clone)]
+pub struct GoogleConfig {
+    pub issuers: Vec<String>,
+    pub audience: String,
+    pub keys_url: String,
+    pub tokeninfo_url: String,
+    pub jwks_expiry: JwksExpiry,
+}
+
+impl GoogleConfig {
+    pub fn new(project_id: String) -> GoogleConfig {
+        GoogleConfig {
+            issuers: GOOGLE_ISSUERS.iter().map(|iss| iss.to_string()).collect(),
+            audience: project_id,
+            keys_url: GOOGLE_KEYS_URL.to_string(),
+            tokeninfo_url: GOOGLE_TOKENINFO_URL.to_string(),
+            jwks_expiry: JwksExpiry::default(),
+        }
+    }
+}
+
+impl GoogleProvider {
+    pub fn new(config: GoogleConfig) -> GoogleProvider {
+        GoogleProvider {
+            jwt: JwtValidator::new(
+                config.issuers,
+                config.audience,
+                config.keys_url,
+                config.jwks_expiry,
+            ),
+            tokeninfo_url: config.tokeninfo_url,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GoogleTokenCheckResponse {
+    user_id: String,
+    expires_in: Option<u64>,
+}
+
+#[async_trait(?Send)]
+impl AuthProvider for GoogleProvider {
+    fn name(&self) -> &str {
+        "google"
+    }
+
+    fn handles_issuer(&self, issuer: &str) -> bool {
+        self.jwt.handles_issuer(issuer)
+    }
+
+    async fn validate_identity_token(&self, token: &str) -> Result<String, TokenError> {
+        self.jwt.validate(token).await
+    }
+
+    async fn validate_access_token(&self, token: &str) -> Result<AccessToken, TokenError> {
+        let client = awc::Client::new();
+        let mut res = client
+            .get(&self.tokeninfo_url)
+            .query(&[("access_token", token)])
+            .map_err(|_err| TokenError::Invalid)?
+            .send()
+            .await
+            .map_err(|err| {
+                info!("Could not reach tokeninfo, err={}", err);
+                TokenError::ProviderUnavailable
+            })?;
+        if res.status().is_server_error() {
+            info!("Tokeninfo gave status {}", res.status());
+            return Err(TokenError::ProviderUnavailable);
+        }
+        match res.json::<GoogleTokenCheckResponse>().await {
+            Ok(resok) => Ok(AccessToken {
+                user_id: resok.user_id,
+                expires_in: resok.expires_in,
+            }),
+            Err(_) => Err(TokenError::Invalid),
+        }
+    }
+}
+
+// Any OpenID Connect issuer that publishes its keys as JWKS
+// User ids are prefixed with the provider name so they can't collide with
+// Google user ids (which are stored without prefix).
+pub struct OidcProvider {
+    name: String,
+    jwt: JwtValidator,
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub name: String,
+    pub issuer: String,
+    pub audience: String,
+    pub jwks_url: String,
+    pub jwks_expiry: JwksExpiry,
+}
+
+impl OidcProvider {
+    pub fn new(config: OidcConfig) -> OidcProvider {
+        OidcProvider {
+            name: config.name,
+            jwt: JwtValidator::new(
+                vec![config.issuer],
+                config.audience,
+                config.jwks_url,
+                config.jwks_expiry,
+            ),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl AuthProvider for OidcProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn handles_issuer(&self, issuer: &str) -> bool {
+        self.jwt.handles_issuer(issuer)
+    }
+
+    async fn validate_identity_token(&self, token: &str) -> Result<String, TokenError> {
+        let sub = self.jwt.validate(token).await?;
+        Ok(format!("{}:{}", self.name, sub))
+    }
+}
+
+struct CachedAccessToken {
+    user_id: String,
+    expires: std::time::Instant,
+}
+
+// Remembers validated access tokens so repeated uploads don't each ask the provider
+// Keyed by sha256 of the token so the tokens themselves are not kept in memory.
+// When full, expired entries are dropped first, then the ones expiring soonest.
+pub struct AccessTokenCache {
+    entries: std::sync::Mutex<std::collections::HashMap<String, CachedAccessToken>>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct AccessTokenCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+impl AccessTokenCacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+impl AccessTokenCache {
+    pub fn new(capacity: usize) -> AccessTokenCache {
+        AccessTokenCache {
+            entries: std::sync::Mutex::new(std::collections::HashMap::new()),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn key(token: &str) -> String {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
+    }
+
+    // A panic elsewhere while holding the lock leaves whole entries behind, so
+    // the cache keeps working instead of failing every later request
+    fn lock(
+        &self,
+    ) -> std::sync::MutexGuard<'_, std::collections::HashMap<String, CachedAccessToken>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn get(&self, token: &str) -> Option<String> {
+        let key = AccessTokenCache::key(token);
+        let mut entries = self.lock();
+        let found = match entries.get(&key) {
+            Some(entry) if entry.expires > std::time::Instant::now() => Some(entry.user_id.clone()),
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        };
+        match found {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        found
+    }
+
+    pub fn insert(&self, token: &str, user_id: &str, ttl: std::time::Duration) {
+        if self.capacity == 0 || ttl.is_zero() {
+            return;
+        }
+        let now = std::time::Instant::now();
+        let mut entries = self.lock();
+        if entries.len() >= self.capacity {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        while entries.len() >= self.capacity {
+            let soonest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone());
+            match soonest {
+                Some(key) => entries.remove(&key),
+                None => break,
+            };
+        }
+        entries.insert(
+            AccessTokenCache::key(token),
+            CachedAccessToken {
+                user_id: user_id.to_string(),
+                expires: now + ttl,
+            },
+        );
+    }
+
+    pub fn stats(&self) -> AccessTokenCacheStats {
+        AccessTokenCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.lock().len(),
+        }
+    }
+}
+
+// All configured providers
+pub struct AuthProviders {
+    providers: Vec<Box<dyn AuthProvider>>,
+    access_tokens: AccessTokenCache,
+}
+
+impl AuthProviders {
+    pub fn new(providers: Vec<Box<dyn AuthProvider>>) -> AuthProviders {
+        AuthProviders {
+            providers,
+            access_tokens: AccessTokenCache::new(ACCESS_TOKEN_CACHE_SIZE),
+        }
+    }
+
+    // Setup the providers that are configured (see config.rs)
+    pub fn from_config(google: Option<GoogleConfig>, oidc: Option<OidcConfig>) -> AuthProviders {
+        let mut providers: Vec<Box<dyn AuthProvider>> = Vec::new();
+        if let Some(google) = google {
+            info!("Enabling Google authentication");
+            providers.push(Box::new(GoogleProvider::new(google)));
+        }
+        if let Some(oidc) = oidc {
+            info!(
+                "Enabling OIDC authentication {} for {}",
+                oidc.name, oidc.issuer
+            );
+            providers.push(Box::new(OidcProvider::new(oidc)));
+        }
+        if providers.is_empty() {
+            info!("No authentication providers configured, logins will fail");
+        }
+        AuthProviders::new(providers)
+    }
+
+    // Validate identity token with the provider of its issuer and return user_id
+    pub async fn validate_identity_token(&self, token: &str) -> Result<String, TokenError> {
+        let issuer = get_issuer(token)?;
+        match self.providers.iter().find(|p| p.handles_issuer(&issuer)) {
+            Some(provider) => {
+                info!(
+                    "Validating identity token with provider {}",
+                    provider.name()
+                );
+                provider.validate_identity_token(token).await
+            }
+            None => {
+                info!("No provider for token issuer, iss={}", issuer);
+                Err(TokenError::Issuer)
+            }
+        }
+    }
+
+    // Validate access token with each provider in turn and return user_id
+    // Results are cached until the token expires (as reported by the provider).
+    pub async fn validate_access_token(&self, token: &str) -> Result<String, TokenError> {
+        if let Some(user_id) = self.access_tokens.get(token) {
+            return Ok(user_id);
+        }
+        let mut result = Err(TokenError::Invalid);
+        for provider in &self.providers {
+            match provider.validate_access_token(token).await {
+                Ok(access_token) => {
+                    result = Ok(access_token);
+                    break;
+                }
+                // Keep outages over plain rejections, clients handle them differently
+                Err(err) if !matches!(&result, Err(prev) if prev.is_server_problem()) => {
+                    result = Err(err)
+                }
+                Err(_) => {}
+            }
+        }
+        let access_token = result?;
+        if let Some(expires_in) = access_token.expires_in {
+            let ttl = expires_in.min(ACCESS_TOKEN_CACHE_MAX_SECONDS);
+            self.access_tokens.insert(
+                token,
+                &access_token.user_id,
+                std::time::Duration::from_secs(ttl),
+            );
+        }
+        Ok(access_token.user_id)
+    }
+
+    pub fn access_token_cache_stats(&self) -> AccessTokenCacheStats {
+        self.access_tokens.stats()
+    }
+}
@@ -0,0 +1,174 @@
+// Personal API tokens for scripts and command line tools
+//
+// Tokens are sent as "Authorization: Bearer sp_...". The prefix tells them apart
+// from Google access tokens. Only the sha256 of a token is stored, the token
+// itself is shown once when it is created.
+
+use crate::schema::api_tokens;
+use crate::storage::{DbConnection, LocalError};
+use base64::Engine;
+use chrono::offset::Utc;
+use chrono::DateTime;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const TOKEN_PREFIX: &str = "sp_";
+const TOKEN_BYTES: usize = 32;
+pub const MAX_TOKEN_NAME_LENGTH: usize = 100;
+
+// What a token is allowed to do
+// Read covers listing and counting, write covers everything that changes data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Read,
+    Write,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Scope> {
+        match scope {
+            "read" => Some(Scope::Read),
+            "write" => Some(Scope::Write),
+            _ => None,
+        }
+    }
+
+    // Everything, for users logged in some other way than with an API token
+    pub fn all() -> Vec<Scope> {
+        vec![Scope::Read, Scope::Write]
+    }
+}
+
+#[derive(Queryable, Insertable, Serialize)]
+#[diesel(table_name = api_tokens)]
+pub struct ApiToken {
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub user_id: String,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked: bool,
+}
+
+#[derive(Deserialize)]
+pub struct NewApiToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+// Response for newly created token, the only time the token is ever returned
+#[derive(Serialize)]
+pub struct CreatedApiToken {
+    #[serde(flatten)]
+    pub info: ApiToken,
+    pub token: String,
+}
+
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub async fn create_token(
+    conn: &mut DbConnection,
+    uid: &str,
+    form: &NewApiToken,
+) -> Result<CreatedApiToken, LocalError> {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = format!(
+        "{}{}",
+        TOKEN_PREFIX,
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    );
+    let mut scopes = form
+        .scopes
+        .iter()
+        .map(|scope| scope.as_str().to_string())
+        .collect::<Vec<String>>();
+    scopes.sort();
+    scopes.dedup();
+    let info = ApiToken {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        user_id: uid.to_string(),
+        name: form.name.trim().to_string(),
+        token_hash: hash_token(&token),
+        scopes,
+        created_at: Utc::now(),
+        last_used_at: None,
+        revoked: false,
+    };
+    diesel::insert_into(api_tokens::table)
+        .values(&info)
+        .execute(conn)
+        .await?;
+    Ok(CreatedApiToken { info, token })
+}
+
+pub async fn find_tokens_by_user(
+    conn: &mut DbConnection,
+    uid: &str,
+) -> Result<Vec<ApiToken>, LocalError> {
+    use crate::schema::api_tokens::dsl::*;
+    Ok(api_tokens
+        .filter(user_id.eq(uid))
+        .order(created_at.asc())
+        .load::<ApiToken>(conn)
+        .await?)
+}
+
+pub async fn revoke_token(conn: &mut DbConnection, uid: &str, tid: &str) -> Result<(), LocalError> {
+    use crate::schema::api_tokens::dsl::*;
+    let updated = diesel::update(api_tokens.filter(id.eq(tid)).filter(user_id.eq(uid)))
+        .set(revoked.eq(true))
+        .execute(conn)
+        .await?;
+    if updated == 0 {
+        return Err(LocalError::NotFound);
+    }
+    Ok(())
+}
+
+// Look up bearer token and return its user and scopes
+// Records when the token was last used.
+pub async fn check_token(
+    conn: &mut DbConnection,
+    token: &str,
+) -> Result<Option<(String, Vec<Scope>)>, LocalError> {
+    use crate::schema::api_tokens::dsl::*;
+    let found = diesel::update(
+        api_tokens
+            .filter(token_hash.eq(hash_token(token)))
+            .filter(revoked.eq(false)),
+    )
+    .set(last_used_at.eq(Utc::now()))
+    .returning((user_id, scopes))
+    .get_result::<(String, Vec<String>)>(conn)
+    .await
+    .optional()?;
+    Ok(found.map(|(uid, token_scopes)| {
+        let token_scopes = token_scopes
+            .iter()
+            .filter_map(|scope| Scope::parse(scope))
+            .collect();
+        (uid, token_scopes)
+    }))
+}
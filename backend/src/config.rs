@@ -0,0 +1,628 @@
+// Server settings
+//
+// Everything main() needs is read once at startup into `Config`: from the
+// environment (and the .env file) and, when CONFIG_FILE names one, a TOML file.
+// Environment variables win over the file. Every setting is checked before
+// the server starts and all problems are reported together.
+//
+// Settings, as environment variable / key in the file:
+//   BIND_ADDRESS / bind                          address to listen on (0.0.0.0:9090)
+//   PUBLIC_URL / public_url                      where the site is served from
+//   SECRET / secret                              signs session cookies, at least 32 bytes
+//   SESSION_TTL_DAYS / session_ttl_days          how long a login lasts (30)
+//   MAX_FREE_USER_COUNT / max_free_user_count    conversations a free user can share
+//   MAGIC_LINK_COOLDOWN_SECS / magic_link_cooldown_secs
+//                                                time between login links to one address (60)
+//   SITE_DIR / site_dir                          files overriding the built-in page template
+//   PAGE_CACHE_SIZE / page_cache_size            conversations kept rendered (1000)
+//   DEV_MODE / dev_mode                          read SITE_DIR again for every page (false)
+//   CONVERSATION_STORE / database.store          postgres, sqlite or memory
+//   DATABASE_URL / database.url                  Postgres connection URL
+//   SQLITE_PATH / database.sqlite_path           SQLite file
+//   DATABASE_POOL_SIZE / database.pool_size      Postgres connections at most (10)
+//   DATABASE_CONNECTION_TIMEOUT_MS / database.connection_timeout_ms
+//                                                wait for a free connection (5000)
+//   DATABASE_STATEMENT_TIMEOUT_MS / database.statement_timeout_ms
+//                                                longest query, 0 for no limit (30000)
+//   GOOGLE_PROJECT_ID / google.project_id        enables Google logins
+//   GOOGLE_AUDIENCE, GOOGLE_ISSUERS, GOOGLE_KEYS_URL, GOOGLE_TOKENINFO_URL
+//     / google.audience, .issuers, .keys_url, .tokeninfo_url
+//   OIDC_ISSUER / oidc.issuer                    enables a generic OIDC provider
+//   OIDC_NAME, OIDC_AUDIENCE, OIDC_JWKS_URL / oidc.name, .audience, .jwks_url
+//   JWKS_DEFAULT_MAX_AGE_SECS, JWKS_MIN_MAX_AGE_SECS, JWKS_MAX_MAX_AGE_SECS
+//     / jwks.default_max_age_secs, .min_max_age_secs, .max_max_age_secs
+//   MAILER / mail.mailer                         stdout, file:<path> or sendmail[:<cmd>]
+//   MAIL_FROM / mail.from                        sender of login links
+
+use crate::auth::jwks::JwksExpiry;
+use crate::auth::{GoogleConfig, OidcConfig};
+use crate::mailer::{MailerConfig, MailerKind};
+use crate::storage::{PoolConfig, StoreConfig};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_BIND: &str = "0.0.0.0:9090";
+const DEFAULT_PUBLIC_URL: &str = "https://shareconversation.com";
+const DEFAULT_SESSION_TTL_DAYS: i64 = 30;
+const DEFAULT_MAGIC_LINK_COOLDOWN_SECS: i64 = 60;
+const DEFAULT_PAGE_CACHE_SIZE: usize = 1000;
+// Key::derive_from needs at least this much
+const MIN_SECRET_LENGTH: usize = 32;
+// Files of the site directory that replace the built-in ones
+pub const SITE_FILES: [&str; 5] = [
+    "index.hbs",
+    "index.css",
+    "main.js",
+    "chatgpt.png",
+    "logo-128.png",
+];
+
+pub struct Config {
+    pub bind: SocketAddr,
+    pub public_url: String,
+    pub secret: String,
+    pub session_ttl_days: i64,
+    pub max_free_user_count: i64,
+    pub magic_link_cooldown_secs: i64,
+    pub site_dir: Option<PathBuf>,
+    pub dev_mode: bool,
+    pub page_cache_size: usize,
+    pub store: StoreConfig,
+    pub pool: PoolConfig,
+    pub google: Option<GoogleConfig>,
+    pub oidc: Option<OidcConfig>,
+    pub mailer: MailerConfig,
+}
+
+// Everything wrong with the settings, one line each
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// The TOML file, every key optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<String>,
+    public_url: Option<String>,
+    secret: Option<String>,
+    session_ttl_days: Option<i64>,
+    max_free_user_count: Option<i64>,
+    magic_link_cooldown_secs: Option<i64>,
+    site_dir: Option<String>,
+    dev_mode: Option<bool>,
+    page_cache_size: Option<usize>,
+    database: DatabaseSection,
+    google: GoogleSection,
+    oidc: OidcSection,
+    jwks: JwksSection,
+    mail: MailSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DatabaseSection {
+    store: Option<String>,
+    url: Option<String>,
+    sqlite_path: Option<String>,
+    pool_size: Option<u32>,
+    connection_timeout_ms: Option<u64>,
+    statement_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct GoogleSection {
+    project_id: Option<String>,
+    audience: Option<String>,
+    issuers: Option<Vec<String>>,
+    keys_url: Option<String>,
+    tokeninfo_url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct OidcSection {
+    issuer: Option<String>,
+    name: Option<String>,
+    audience: Option<String>,
+    jwks_url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct JwksSection {
+    default_max_age_secs: Option<u64>,
+    min_max_age_secs: Option<u64>,
+    max_max_age_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MailSection {
+    mailer: Option<String>,
+    from: Option<String>,
+}
+
+// Reads settings from the environment falling back to the file, collecting problems
+struct Reader<'a> {
+    env: &'a dyn Fn(&str) -> Option<String>,
+    problems: Vec<String>,
+}
+
+impl Reader<'_> {
+    fn problem(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    fn text(&self, var: &str, file: Option<String>) -> Option<String> {
+        (self.env)(var)
+            .map(|value| value.trim().to_string())
+            .or(file)
+            .filter(|value| !value.is_empty())
+    }
+
+    fn number<T: FromStr>(&mut self, var: &str, key: &str, file: Option<T>) -> Option<T> {
+        match (self.env)(var) {
+            Some(value) => match value.trim().parse() {
+                Ok(number) => Some(number),
+                Err(_) => {
+                    self.problem(format!(
+                        "{} ({}) should be a whole number, not {:?}",
+                        var, key, value
+                    ));
+                    None
+                }
+            },
+            None => file,
+        }
+    }
+
+    fn flag(&mut self, var: &str, key: &str, file: Option<bool>) -> Option<bool> {
+        match (self.env)(var) {
+            Some(value) => match value.trim().to_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Some(true),
+                "0" | "false" | "no" | "off" => Some(false),
+                _ => {
+                    self.problem(format!(
+                        "{} ({}) should be true or false, not {:?}",
+                        var, key, value
+                    ));
+                    None
+                }
+            },
+            None => file,
+        }
+    }
+
+    // Comma separated in the environment, a list in the file
+    fn list(&self, var: &str, file: Option<Vec<String>>) -> Option<Vec<String>> {
+        match (self.env)(var) {
+            Some(value) => Some(
+                value
+                    .split(',')
+                    .map(|item| item.trim().to_string())
+                    .filter(|item| !item.is_empty())
+                    .collect(),
+            ),
+            None => file,
+        }
+    }
+
+    // Absolute http(s) URL
+    fn url(&mut self, var: &str, key: &str, value: &str) {
+        let valid = value
+            .parse::<actix_web::http::Uri>()
+            .map(|uri| {
+                matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some()
+            })
+            .unwrap_or(false);
+        if !valid {
+            self.problem(format!(
+                "{} ({}) should be an http or https URL, not {:?}",
+                var, key, value
+            ));
+        }
+    }
+}
+
+impl Config {
+    // Settings from the process environment and the file named by CONFIG_FILE
+    pub fn load() -> Result<Config, ConfigError> {
+        let env = |var: &str| std::env::var(var).ok();
+        let file = match env("CONFIG_FILE") {
+            Some(path) => Some(std::fs::read_to_string(&path).map_err(|err| {
+                ConfigError(vec![format!(
+                    "CONFIG_FILE {} could not be read: {}",
+                    path, err
+                )])
+            })?),
+            None => None,
+        };
+        Config::from_sources(file.as_deref(), env)
+    }
+
+    // Settings from a TOML document and a stand-in for the environment
+    pub fn from_sources(
+        toml: Option<&str>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, ConfigError> {
+        let file: FileConfig = match toml {
+            Some(toml) => toml::from_str(toml)
+                .map_err(|err| ConfigError(vec![format!("CONFIG_FILE: {}", err)]))?,
+            None => FileConfig::default(),
+        };
+        let mut reader = Reader {
+            env: &env,
+            problems: Vec::new(),
+        };
+
+        let bind_setting = reader
+            .text("BIND_ADDRESS", file.bind)
+            .unwrap_or_else(|| DEFAULT_BIND.to_string());
+        let bind = match bind_setting.parse::<SocketAddr>() {
+            Ok(bind) => bind,
+            Err(_) => {
+                reader.problem(format!(
+                    "BIND_ADDRESS (bind) should be an IP address and port like {}, not {:?}",
+                    DEFAULT_BIND, bind_setting
+                ));
+                DEFAULT_BIND.parse().expect("default bind address")
+            }
+        };
+
+        let public_url = reader
+            .text("PUBLIC_URL", file.public_url)
+            .unwrap_or_else(|| DEFAULT_PUBLIC_URL.to_string())
+            .trim_end_matches('/')
+            .to_string();
+        reader.url("PUBLIC_URL", "public_url", &public_url);
+
+        let secret = reader.text("SECRET", file.secret).unwrap_or_default();
+        if secret.is_empty() {
+            reader.problem("SECRET (secret) should be set".to_string());
+        } else if secret.len() < MIN_SECRET_LENGTH {
+            reader.problem(format!(
+                "SECRET (secret) should be at least {} bytes long, it is {}",
+                MIN_SECRET_LENGTH,
+                secret.len()
+            ));
+        }
+
+        let session_ttl_days = reader
+            .number(
+                "SESSION_TTL_DAYS",
+                "session_ttl_days",
+                file.session_ttl_days,
+            )
+            .unwrap_or(DEFAULT_SESSION_TTL_DAYS);
+        if session_ttl_days < 1 {
+            reader.problem(format!(
+                "SESSION_TTL_DAYS (session_ttl_days) should be at least 1, not {}",
+                session_ttl_days
+            ));
+        }
+
+        let max_free_user_count = match reader.number(
+            "MAX_FREE_USER_COUNT",
+            "max_free_user_count",
+            file.max_free_user_count,
+        ) {
+            Some(count) if count < 0 => {
+                reader.problem(format!(
+                    "MAX_FREE_USER_COUNT (max_free_user_count) should not be negative, not {}",
+                    count
+                ));
+                0
+            }
+            Some(count) => count,
+            None => {
+                if (reader.env)("MAX_FREE_USER_COUNT").is_none() {
+                    reader
+                        .problem("MAX_FREE_USER_COUNT (max_free_user_count) should be set".into());
+                }
+                0
+            }
+        };
+
+        let magic_link_cooldown_secs = reader
+            .number(
+                "MAGIC_LINK_COOLDOWN_SECS",
+                "magic_link_cooldown_secs",
+                file.magic_link_cooldown_secs,
+            )
+            .unwrap_or(DEFAULT_MAGIC_LINK_COOLDOWN_SECS);
+        if magic_link_cooldown_secs < 0 {
+            reader.problem(format!(
+                "MAGIC_LINK_COOLDOWN_SECS (magic_link_cooldown_secs) should not be negative, not {}",
+                magic_link_cooldown_secs
+            ));
+        }
+
+        let site_dir = reader.text("SITE_DIR", file.site_dir).map(PathBuf::from);
+        match &site_dir {
+            Some(dir) if !dir.is_dir() => reader.problem(format!(
+                "SITE_DIR (site_dir) {} is not a directory",
+                dir.display()
+            )),
+            // Most likely the wrong directory
+            Some(dir) if !SITE_FILES.iter().any(|name| dir.join(name).is_file()) => {
+                reader.problem(format!(
+                    "SITE_DIR (site_dir) {} has none of {}",
+                    dir.display(),
+                    SITE_FILES.join(", ")
+                ))
+            }
+            _ => (),
+        }
+        let dev_mode = reader
+            .flag("DEV_MODE", "dev_mode", file.dev_mode)
+            .unwrap_or(false);
+        if dev_mode && site_dir.is_none() {
+            reader.problem(
+                "DEV_MODE (dev_mode) reloads files from SITE_DIR (site_dir), which is not set"
+                    .to_string(),
+            );
+        }
+
+        let page_cache_size = reader
+            .number("PAGE_CACHE_SIZE", "page_cache_size", file.page_cache_size)
+            .unwrap_or(DEFAULT_PAGE_CACHE_SIZE);
+
+        let pool = read_pool(&mut reader, &file.database);
+        let store = read_store(&mut reader, file.database);
+        let jwks_expiry = read_jwks_expiry(&mut reader, file.jwks);
+        let google = read_google(&mut reader, file.google, jwks_expiry);
+        let oidc = read_oidc(&mut reader, file.oidc, jwks_expiry);
+
+        let mailer_setting = reader
+            .text("MAILER", file.mail.mailer)
+            .unwrap_or_else(|| "stdout".to_string());
+        let kind = MailerKind::parse(&mailer_setting).unwrap_or_else(|| {
+            reader.problem(format!(
+                "MAILER (mail.mailer) should be stdout, file:<path>, sendmail or \
+                 sendmail:<command>, not {:?}",
+                mailer_setting
+            ));
+            MailerKind::Stdout
+        });
+        let mut mailer = MailerConfig {
+            kind,
+            ..Default::default()
+        };
+        if let Some(from) = reader.text("MAIL_FROM", file.mail.from) {
+            mailer.from = from;
+        }
+
+        if !reader.problems.is_empty() {
+            return Err(ConfigError(reader.problems));
+        }
+        Ok(Config {
+            bind,
+            public_url,
+            secret,
+            session_ttl_days,
+            max_free_user_count,
+            magic_link_cooldown_secs,
+            site_dir,
+            dev_mode,
+            page_cache_size,
+            store,
+            pool,
+            google,
+            oidc,
+            mailer,
+        })
+    }
+}
+
+fn read_store(reader: &mut Reader, file: DatabaseSection) -> StoreConfig {
+    let url = reader.text("DATABASE_URL", file.url);
+    let sqlite_path = reader.text("SQLITE_PATH", file.sqlite_path);
+    let store = reader
+        .text("CONVERSATION_STORE", file.store)
+        .unwrap_or_else(|| "postgres".to_string());
+    match store.as_str() {
+        "postgres" => {
+            if url.is_none() {
+                reader.problem("DATABASE_URL (database.url) should be set".to_string());
+            }
+            StoreConfig::Postgres(url.unwrap_or_default())
+        }
+        "sqlite" => {
+            if sqlite_path.is_none() {
+                reader.problem(
+                    "SQLITE_PATH (database.sqlite_path) should be set for the sqlite store"
+                        .to_string(),
+                );
+            }
+            StoreConfig::Sqlite(sqlite_path.unwrap_or_default())
+        }
+        "memory" => StoreConfig::Memory,
+        other => {
+            reader.problem(format!(
+                "CONVERSATION_STORE (database.store) should be postgres, sqlite or memory, \
+                 not {:?}",
+                other
+            ));
+            StoreConfig::Memory
+        }
+    }
+}
+
+fn read_pool(reader: &mut Reader, file: &DatabaseSection) -> PoolConfig {
+    let defaults = PoolConfig::default();
+    let size = reader
+        .number("DATABASE_POOL_SIZE", "database.pool_size", file.pool_size)
+        .unwrap_or(defaults.size);
+    if size < 1 {
+        reader.problem("DATABASE_POOL_SIZE (database.pool_size) should be at least 1".to_string());
+    }
+    let connection_timeout_ms = reader
+        .number(
+            "DATABASE_CONNECTION_TIMEOUT_MS",
+            "database.connection_timeout_ms",
+            file.connection_timeout_ms,
+        )
+        .unwrap_or(defaults.connection_timeout.as_millis() as u64);
+    // bb8 refuses a zero timeout
+    if connection_timeout_ms < 1 {
+        reader.problem(
+            "DATABASE_CONNECTION_TIMEOUT_MS (database.connection_timeout_ms) should be at least 1"
+                .to_string(),
+        );
+    }
+    let statement_timeout_ms = reader
+        .number(
+            "DATABASE_STATEMENT_TIMEOUT_MS",
+            "database.statement_timeout_ms",
+            file.statement_timeout_ms,
+        )
+        .unwrap_or(defaults.statement_timeout.as_millis() as u64);
+    PoolConfig {
+        size: size.max(1),
+        connection_timeout: Duration::from_millis(connection_timeout_ms.max(1)),
+        statement_timeout: Duration::from_millis(statement_timeout_ms),
+    }
+}
+
+fn read_jwks_expiry(reader: &mut Reader, file: JwksSection) -> JwksExpiry {
+    let defaults = JwksExpiry::default();
+    let expiry = JwksExpiry {
+        default_secs: reader
+            .number(
+                "JWKS_DEFAULT_MAX_AGE_SECS",
+                "jwks.default_max_age_secs",
+                file.default_max_age_secs,
+            )
+            .unwrap_or(defaults.default_secs),
+        min_secs: reader
+            .number(
+                "JWKS_MIN_MAX_AGE_SECS",
+                "jwks.min_max_age_secs",
+                file.min_max_age_secs,
+            )
+            .unwrap_or(defaults.min_secs),
+        max_secs: reader
+            .number(
+                "JWKS_MAX_MAX_AGE_SECS",
+                "jwks.max_max_age_secs",
+                file.max_max_age_secs,
+            )
+            .unwrap_or(defaults.max_secs),
+    };
+    if expiry.min_secs < 1
+        || expiry.min_secs > expiry.default_secs
+        || expiry.default_secs > expiry.max_secs
+    {
+        reader.problem(format!(
+            "JWKS key expiry should be 1 <= JWKS_MIN_MAX_AGE_SECS ({}) <= \
+             JWKS_DEFAULT_MAX_AGE_SECS ({}) <= JWKS_MAX_MAX_AGE_SECS ({})",
+            expiry.min_secs, expiry.default_secs, expiry.max_secs
+        ));
+    }
+    expiry
+}
+
+fn read_google(
+    reader: &mut Reader,
+    file: GoogleSection,
+    jwks_expiry: JwksExpiry,
+) -> Option<GoogleConfig> {
+    let audience = reader.text("GOOGLE_AUDIENCE", file.audience);
+    let issuers = reader.list("GOOGLE_ISSUERS", file.issuers);
+    let keys_url = reader.text("GOOGLE_KEYS_URL", file.keys_url);
+    let tokeninfo_url = reader.text("GOOGLE_TOKENINFO_URL", file.tokeninfo_url);
+    let Some(project_id) = reader.text("GOOGLE_PROJECT_ID", file.project_id) else {
+        if audience.is_some() || issuers.is_some() || keys_url.is_some() || tokeninfo_url.is_some()
+        {
+            reader.problem(
+                "GOOGLE_PROJECT_ID (google.project_id) should be set to use the other Google \
+                 settings"
+                    .to_string(),
+            );
+        }
+        return None;
+    };
+    let mut config = GoogleConfig::new(project_id);
+    config.jwks_expiry = jwks_expiry;
+    if let Some(audience) = audience {
+        config.audience = audience;
+    }
+    if let Some(issuers) = issuers {
+        if issuers.is_empty() {
+            reader.problem("GOOGLE_ISSUERS (google.issuers) should not be empty".to_string());
+        }
+        config.issuers = issuers;
+    }
+    if let Some(keys_url) = keys_url {
+        config.keys_url = keys_url;
+    }
+    if let Some(tokeninfo_url) = tokeninfo_url {
+        config.tokeninfo_url = tokeninfo_url;
+    }
+    reader.url("GOOGLE_KEYS_URL", "google.keys_url", &config.keys_url);
+    reader.url(
+        "GOOGLE_TOKENINFO_URL",
+        "google.tokeninfo_url",
+        &config.tokeninfo_url,
+    );
+    Some(config)
+}
+
+fn read_oidc(
+    reader: &mut Reader,
+    file: OidcSection,
+    jwks_expiry: JwksExpiry,
+) -> Option<OidcConfig> {
+    let name = reader.text("OIDC_NAME", file.name);
+    let audience = reader.text("OIDC_AUDIENCE", file.audience);
+    let jwks_url = reader.text("OIDC_JWKS_URL", file.jwks_url);
+    let Some(issuer) = reader.text("OIDC_ISSUER", file.issuer) else {
+        if name.is_some() || audience.is_some() || jwks_url.is_some() {
+            reader.problem(
+                "OIDC_ISSUER (oidc.issuer) should be set to use the other OIDC settings"
+                    .to_string(),
+            );
+        }
+        return None;
+    };
+    // User ids are stored as `name:sub`, next to `local:<account id>`
+    if let Some(name) = &name {
+        if name == "local" || name == "google" || name.contains(':') {
+            reader.problem(format!(
+                "OIDC_NAME (oidc.name) can't be local or google or contain ':', not {:?}",
+                name
+            ));
+        }
+    }
+    if audience.is_none() {
+        reader.problem("OIDC_AUDIENCE (oidc.audience) should be set with OIDC_ISSUER".to_string());
+    }
+    match &jwks_url {
+        Some(jwks_url) => reader.url("OIDC_JWKS_URL", "oidc.jwks_url", jwks_url),
+        None => reader
+            .problem("OIDC_JWKS_URL (oidc.jwks_url) should be set with OIDC_ISSUER".to_string()),
+    }
+    Some(OidcConfig {
+        name: name.unwrap_or_else(|| "oidc".to_string()),
+        issuer,
+        audience: audience.unwrap_or_default(),
+        jwks_url: jwks_url.unwrap_or_default(),
+        jwks_expiry,
+    })
+}
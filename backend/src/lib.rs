@@ -0,0 +1,74 @@
+// Backend for shareconversation.com
+//
+// The library has everything needed to serve the API, the binary in main.rs
+// only reads the configuration, prepares the database and calls `app`.
+//   - config: settings from the environment and a TOML file, checked at startup
+//   - models: data exchanged with clients and stored in the database
+//   - storage: connection pool, migrations and queries
+//   - auth: identity providers, local accounts, API tokens and who is asking
+//   - render: HTML page of a shared conversation
+//   - api: the HTTP handlers and their errors
+// Tests can build the same app with `app` and call it with actix_web::test.
+
+extern crate diesel;
+#[macro_use]
+extern crate lazy_static;
+
+pub mod api;
+pub mod auth;
+pub mod config;
+pub mod mailer;
+pub mod models;
+pub mod render;
+pub mod schema;
+pub mod storage;
+
+use actix_session::{config::PersistentSession, storage::CookieSessionStore, SessionMiddleware};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{cookie::time::Duration, cookie::Key, middleware, web, App};
+
+pub use api::AppState;
+use std::sync::Arc;
+pub use storage::{ConversationStore, DbPool};
+
+// The whole application with its middleware
+// Conversations are kept in `store`. Everything else needs `pool`, without it
+// (when conversations are kept in SQLite or memory) only the conversation
+// endpoints work. `secret_key` signs the session cookie, it must be the same
+// for every worker. The session lasts `state.session_ttl_days`.
+pub fn app(
+    pool: Option<DbPool>,
+    store: Arc<dyn ConversationStore>,
+    state: web::Data<AppState>,
+    secret_key: Key,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let database = pool.is_some();
+    let mut app = App::new();
+    if let Some(pool) = pool {
+        app = app.app_data(web::Data::new(pool));
+    }
+    app.wrap_fn(api::errors::with_request_id)
+        .wrap(middleware::Logger::new(
+            r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}o"#,
+        ))
+        .wrap(
+            SessionMiddleware::builder(CookieSessionStore::default(), secret_key)
+                .session_lifecycle(
+                    PersistentSession::default()
+                        .session_ttl(Duration::days(state.session_ttl_days)),
+                )
+                .build(),
+        )
+        .app_data(web::Data::from(store))
+        .app_data(state)
+        .configure(|cfg| api::configure(cfg, database))
+}
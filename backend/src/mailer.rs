@@ -0,0 +1,155 @@
+// Outgoing email for local accounts (magic links)
+//
+// Which mailer is used is picked with the MAILER setting (see config.rs):
+//   stdout (default)   - print messages to the log, handy for development
+//   file:<path>        - append messages to a file, handy for tests
+//   sendmail[:<cmd>]   - pipe messages into sendmail (or a compatible command)
+
+use log::info;
+use std::io::Write;
+
+#[derive(Debug)]
+pub enum MailError {
+    Io(std::io::Error),
+    Rejected,
+}
+
+impl std::fmt::Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MailError::Io(err) => write!(f, "could not send mail: {}", err),
+            MailError::Rejected => write!(f, "mail command rejected the message"),
+        }
+    }
+}
+
+impl std::error::Error for MailError {}
+
+impl std::convert::From<std::io::Error> for MailError {
+    fn from(err: std::io::Error) -> MailError {
+        MailError::Io(err)
+    }
+}
+
+pub trait Mailer: Send + Sync {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MailError>;
+}
+
+// Format a plain text message with minimal headers
+fn format_message(from: &str, to: &str, subject: &str, body: &str) -> String {
+    format!(
+        "From: {}\nTo: {}\nSubject: {}\n\n{}\n",
+        from, to, subject, body
+    )
+}
+
+pub struct StdoutMailer {
+    from: String,
+}
+
+impl Mailer for StdoutMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MailError> {
+        info!(
+            "Mail message:\n{}",
+            format_message(&self.from, to, subject, body)
+        );
+        Ok(())
+    }
+}
+
+pub struct FileMailer {
+    from: String,
+    path: std::path::PathBuf,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MailError> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(format_message(&self.from, to, subject, body).as_bytes())?;
+        Ok(())
+    }
+}
+
+pub struct SendmailMailer {
+    from: String,
+    command: String,
+}
+
+impl Mailer for SendmailMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), MailError> {
+        let mut child = std::process::Command::new(&self.command)
+            .arg("-t")
+            .stdin(std::process::Stdio::piped())
+            .spawn()?;
+        if let Some(stdin) = child.stdin.as_mut() {
+            stdin.write_all(format_message(&self.from, to, subject, body).as_bytes())?;
+        }
+        if !child.wait()?.success() {
+            return Err(MailError::Rejected);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MailerKind {
+    Stdout,
+    File(std::path::PathBuf),
+    Sendmail(String),
+}
+
+impl MailerKind {
+    // None when the setting is not one of the above
+    pub fn parse(setting: &str) -> Option<MailerKind> {
+        if let Some(path) = setting.strip_prefix("file:") {
+            return Some(MailerKind::File(path.into()));
+        }
+        if setting == "sendmail" || setting.starts_with("sendmail:") {
+            let command = setting.strip_prefix("sendmail:").unwrap_or("sendmail");
+            return Some(MailerKind::Sendmail(command.to_string()));
+        }
+        (setting == "stdout").then_some(MailerKind::Stdout)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MailerConfig {
+    pub kind: MailerKind,
+    pub from: String,
+}
+
+impl Default for MailerConfig {
+    fn default() -> MailerConfig {
+        MailerConfig {
+            kind: MailerKind::Stdout,
+            from: "ShareConversation <noreply@shareconversation.com>".to_string(),
+        }
+    }
+}
+
+pub fn mailer(config: &MailerConfig) -> Box<dyn Mailer> {
+    let from = config.from.clone();
+    match &config.kind {
+        MailerKind::File(path) => {
+            info!("Sending mail to file {}", path.display());
+            Box::new(FileMailer {
+                from,
+                path: path.clone(),
+            })
+        }
+        MailerKind::Sendmail(command) => {
+            info!("Sending mail with {}", command);
+            Box::new(SendmailMailer {
+                from,
+                command: command.clone(),
+            })
+        }
+        MailerKind::Stdout => {
+            info!("Sending mail to the log");
+            Box::new(StdoutMailer { from })
+        }
+    }
+}
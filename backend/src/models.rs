@@ -0,0 +1,448 @@
+// Data exchanged with clients and stored in the database
+//
+// Types here are plain data with serde and diesel derives, plus the rules about
+// their contents that don't need a database: the stored contents schema
+// version, label names, the conversation digest used to spot duplicates and
+// how PATCH bodies apply to a conversation.
+
+use crate::schema::{conversation_tags, conversations, folders, tags};
+use crate::storage::LocalError;
+use base64::Engine;
+use chrono::offset::Utc;
+use chrono::DateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+
+const MAX_LABEL_LENGTH: usize = 100;
+
+// Model for conversations in the database with all fields
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = conversations)]
+pub struct Conversation {
+    pub id: String,
+    pub hmac: String,
+    pub contents: serde_json::Value, // JSON for ConversationContents
+    pub public: bool,
+    pub research: bool,
+    pub deleted: bool,
+    pub user_id: String,
+    pub folder_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub title: String,
+    pub model: String,
+    pub openaiid: String,
+    pub updated_at: DateTime<Utc>,
+    pub length: i32,
+    pub schema_version: i32, // shape of contents, see CONTENTS_UPGRADES
+    pub version: i32,        // bumped on every change, for If-Match
+}
+
+impl Conversation {
+    pub fn metadata(&self) -> ConversationMetadata {
+        ConversationMetadata {
+            title: self.title.clone(),
+            openaiid: self.openaiid.clone(),
+            model: self.model.clone(),
+            creationdate: self.created_at.into(),
+            length: self.length as usize,
+        }
+    }
+
+    pub fn summary(&self) -> ConversationSummary {
+        ConversationSummary {
+            id: self.id.clone(),
+            hmac: self.hmac.clone(),
+            title: self.title.clone(),
+            model: self.model.clone(),
+            openaiid: self.openaiid.clone(),
+            created_at: self.created_at,
+            length: self.length,
+            public: self.public,
+            research: self.research,
+            deleted: self.deleted,
+            folder_id: self.folder_id.clone(),
+        }
+    }
+}
+
+// New contents and metadata for an existing conversation
+#[derive(Debug, Clone, AsChangeset)]
+#[diesel(table_name = conversations)]
+pub struct ConversationChanges {
+    pub contents: serde_json::Value,
+    pub schema_version: i32,
+    pub title: String,
+    pub model: String,
+    pub openaiid: String,
+    pub length: i32,
+    pub public: bool,
+    pub research: bool,
+    pub hmac: String,
+}
+
+// Conversation fields needed for listing, everything except the contents
+#[derive(Debug, Clone, Queryable)]
+pub struct ConversationSummary {
+    pub id: String,
+    pub hmac: String,
+    pub title: String,
+    pub model: String,
+    pub openaiid: String,
+    pub created_at: DateTime<Utc>,
+    pub length: i32,
+    pub public: bool,
+    pub research: bool,
+    pub deleted: bool,
+    pub folder_id: Option<String>,
+}
+
+impl ConversationSummary {
+    pub fn metadata(&self) -> ConversationMetadata {
+        ConversationMetadata {
+            title: self.title.clone(),
+            openaiid: self.openaiid.clone(),
+            model: self.model.clone(),
+            creationdate: self.created_at.into(),
+            length: self.length as usize,
+        }
+    }
+
+    pub fn into_info(self, tags: Vec<Tag>) -> ShortConversationInfo {
+        ShortConversationInfo {
+            metadata: self.metadata(),
+            id: self.id,
+            public: self.public,
+            research: self.research,
+            deleted: self.deleted,
+            hmac: self.hmac,
+            folder_id: self.folder_id,
+            tags,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct Utterance {
+    pub who: String, // either "gpt" or "human"
+    pub what: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct ConversationContents {
+    pub avatar: String, // data URL of avatar, (may be anonymized)
+    pub dialog: Vec<Utterance>,
+}
+
+pub type ContentsUpgrade = fn(serde_json::Value) -> serde_json::Value;
+
+// Steps to upgrade stored contents to the current shape
+// Entry i takes contents from schema version i + 1 to version i + 2.
+// Append a step here whenever ConversationContents changes shape.
+pub const CONTENTS_UPGRADES: &[ContentsUpgrade] = &[];
+pub const CONTENTS_SCHEMA_VERSION: i32 = CONTENTS_UPGRADES.len() as i32 + 1;
+
+// Bring stored contents up to the current shape and parse them
+pub fn read_contents(
+    version: i32,
+    value: &serde_json::Value,
+) -> Result<ConversationContents, LocalError> {
+    read_contents_with(CONTENTS_UPGRADES, version, value)
+}
+
+// Same with the given upgrade steps instead of CONTENTS_UPGRADES
+// Versions from 1 to one past the last step are readable, anything else was
+// written by a newer server or is broken.
+pub fn read_contents_with(
+    upgrades: &[ContentsUpgrade],
+    version: i32,
+    value: &serde_json::Value,
+) -> Result<ConversationContents, LocalError> {
+    let steps = usize::try_from(version)
+        .ok()
+        .and_then(|version| version.checked_sub(1))
+        .and_then(|skipped| upgrades.get(skipped..))
+        .ok_or(LocalError::UnsupportedContentsVersion(version))?;
+    let mut value = value.clone();
+    for upgrade in steps {
+        value = upgrade(value);
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConversationMetadata {
+    pub title: String,
+    pub openaiid: String,
+    pub model: String,
+    pub creationdate: std::time::SystemTime,
+    pub length: usize,
+}
+
+impl std::hash::Hash for ConversationMetadata {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.title.hash(state);
+        self.openaiid.hash(state);
+        self.model.hash(state);
+        // Ignore creationdate for hash
+        self.length.hash(state);
+    }
+}
+
+// Information that is required when making a new conversation
+#[derive(Serialize, Deserialize)]
+pub struct NewConversation {
+    pub openaiid: String,
+    pub title: String,
+    pub contents: ConversationContents,
+    pub model: String,
+    pub public: bool,
+    pub research: bool,
+    pub paiduser: bool,
+}
+
+// Information returned from GET
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConversationInfo {
+    pub id: String,
+    pub contents: ConversationContents,
+    pub metadata: ConversationMetadata,
+    pub public: bool,
+    pub research: bool,
+    pub deleted: bool,
+    pub hmac: String,
+    // Send back in If-Match when patching
+    pub version: i32,
+}
+
+// Body of a PATCH, applied to the conversation as GET returns it
+pub enum ConversationPatch {
+    // RFC 7396, fields in the body replace those of the conversation
+    Merge(serde_json::Value),
+    // RFC 6902, for changes inside the dialog
+    Json(json_patch::Patch),
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    // An operation could not be applied (failed test, missing path, ...)
+    Failed(String),
+    // Result is not a conversation any more
+    Invalid(String),
+    // Patch changed a field that only the server sets
+    ReadOnly(&'static str),
+}
+
+impl ConversationPatch {
+    // Patched conversation, with id, hmac, deleted and version as they were
+    // The creation date and the length are ignored, one is set when sharing
+    // and the other follows the dialog.
+    pub fn apply(&self, current: &ConversationInfo) -> Result<ConversationInfo, PatchError> {
+        let mut doc =
+            serde_json::to_value(current).map_err(|err| PatchError::Invalid(err.to_string()))?;
+        match self {
+            ConversationPatch::Merge(patch) => json_patch::merge(&mut doc, patch),
+            ConversationPatch::Json(patch) => json_patch::patch(&mut doc, patch)
+                .map_err(|err| PatchError::Failed(err.to_string()))?,
+        }
+        let patched: ConversationInfo =
+            serde_json::from_value(doc).map_err(|err| PatchError::Invalid(err.to_string()))?;
+        // Clients sending back the whole conversation send these unchanged
+        for (field, unchanged) in [
+            ("id", patched.id == current.id),
+            ("hmac", patched.hmac == current.hmac),
+            ("deleted", patched.deleted == current.deleted),
+            ("version", patched.version == current.version),
+        ] {
+            if !unchanged {
+                return Err(PatchError::ReadOnly(field));
+            }
+        }
+        Ok(patched)
+    }
+}
+
+// Information returned from GET for list of conversations
+#[derive(Debug, Serialize)]
+pub struct ShortConversationInfo {
+    pub id: String,
+    pub metadata: ConversationMetadata,
+    pub public: bool,
+    pub research: bool,
+    pub deleted: bool,
+    pub hmac: String,
+    pub folder_id: Option<String>,
+    pub tags: Vec<Tag>,
+}
+
+// Model for user-defined tags in the database
+#[derive(Debug, Clone, Serialize, Queryable, Insertable)]
+#[diesel(table_name = tags)]
+pub struct Tag {
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub user_id: String,
+    pub name: String,
+}
+
+// Model for user-defined folders in the database
+#[derive(Debug, Clone, Serialize, Queryable, Insertable)]
+#[diesel(table_name = folders)]
+pub struct Folder {
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub user_id: String,
+    pub name: String,
+}
+
+// Information that is required when creating or renaming a tag or folder
+#[derive(Serialize, Deserialize)]
+pub struct LabelName {
+    pub name: String,
+}
+
+// Information that is required when moving a conversation into a folder
+// A folder_id of null takes the conversation out of its folder
+#[derive(Serialize, Deserialize)]
+pub struct ConversationFolder {
+    pub folder_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    Date,
+    Title,
+    Model,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl SortKey {
+    // SQL expression to sort by and the type to cast cursor values to
+    pub fn sql(&self) -> (&'static str, &'static str) {
+        match self {
+            SortKey::Date => ("conversations.created_at", "TIMESTAMPTZ"),
+            SortKey::Title => ("lower(conversations.title)", "TEXT"),
+            SortKey::Model => ("conversations.model", "TEXT"),
+        }
+    }
+}
+
+// Optional query parameters for filtering, sorting and paging the list of conversations
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConversationFilter {
+    pub tag: Option<String>,
+    pub folder: Option<String>,
+    pub model: Option<String>,
+    pub title: Option<String>, // case insensitive substring match
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub deleted: Option<bool>,
+    pub public: Option<bool>,
+    pub research: Option<bool>,
+    pub sort: Option<SortKey>,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+// Position after the last conversation of a page
+// Handed to clients as an opaque string
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageCursor {
+    pub sort: SortKey,
+    pub key: String, // value of the sort expression as text
+    pub id: String,
+}
+
+impl PageCursor {
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json)
+    }
+
+    pub fn decode(cursor: &str) -> Option<PageCursor> {
+        let json = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+// Information returned from listing conversations, one page at a time
+#[derive(Debug, Serialize)]
+pub struct ConversationPage {
+    pub conversations: Vec<ShortConversationInfo>,
+    pub next_cursor: Option<String>,
+}
+
+// Model for the association between conversations and tags
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = conversation_tags)]
+pub struct ConversationTag {
+    pub conversation_id: String,
+    pub tag_id: String,
+}
+
+// Operation to apply to every conversation in a bulk request
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", content = "value", rename_all = "snake_case")]
+pub enum BulkOperation {
+    Delete,
+    Undelete,
+    SetPublic(bool),
+    SetResearch(bool),
+    AddTag(String),
+}
+
+// Information that is required for a bulk operation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BulkRequest {
+    pub ids: Vec<String>,
+    pub operation: BulkOperation,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkItemStatus {
+    Ok,
+    NotFound,
+    Forbidden,
+}
+
+// Per-conversation outcome returned from a bulk operation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BulkItemResult {
+    pub id: String,
+    pub status: BulkItemStatus,
+}
+
+// Check that a tag or folder name is usable, returns trimmed name
+pub fn validate_label_name(label: &str) -> Option<String> {
+    let trimmed = label.trim();
+    if trimmed.is_empty() || trimmed.chars().count() > MAX_LABEL_LENGTH {
+        None
+    } else {
+        Some(trimmed.to_string())
+    }
+}
+
+pub fn compute_digest(
+    contents: &ConversationContents,
+    metadata: &ConversationMetadata,
+    userid: &String,
+) -> String {
+    let mut h = std::collections::hash_map::DefaultHasher::new();
+    contents.hash(&mut h);
+    metadata.hash(&mut h);
+    userid.hash(&mut h);
+    format!("{:#x}", h.finish())
+}
@@ -0,0 +1,250 @@
+// Server side rendering of shared conversations
+//
+// The page template, its style sheet, script and images are compiled into the
+// binary. A site directory can be configured whose files override them, and in
+// development mode they are read from it again for every page. Otherwise the
+// template is parsed and the images encoded once, by `PageRenderer::new`.
+
+use crate::models::{read_contents, Conversation};
+use crate::storage::LocalError;
+use base64::Engine;
+use chrono::offset::Utc;
+use chrono::DateTime;
+use handlebars::{handlebars_helper, Handlebars};
+use log::info;
+use std::path::{Path, PathBuf};
+
+// Site files compiled into the binary, build.rs picks them
+const EMBEDDED_INDEX_HBS: &str = include_str!(env!("SITE_INDEX_HBS"));
+const EMBEDDED_INDEX_CSS: &str = include_str!(env!("SITE_INDEX_CSS"));
+const EMBEDDED_MAIN_JS: &str = include_str!(env!("SITE_MAIN_JS"));
+const EMBEDDED_CHATGPT_PNG: &[u8] = include_bytes!(env!("SITE_CHATGPT_PNG"));
+const EMBEDDED_LOGO_PNG: &[u8] = include_bytes!(env!("SITE_LOGO_PNG"));
+
+// Name of index.hbs in the registry
+const PAGE_TEMPLATE: &str = "index";
+
+lazy_static! {
+    static ref MARKDOWN_OPTIONS: pulldown_cmark::Options = {
+        let mut options = pulldown_cmark::Options::empty();
+        options.insert(pulldown_cmark::Options::ENABLE_TABLES);
+        options.insert(pulldown_cmark::Options::ENABLE_FOOTNOTES);
+        options.insert(pulldown_cmark::Options::ENABLE_STRIKETHROUGH);
+        options.insert(pulldown_cmark::Options::ENABLE_TASKLISTS);
+        options
+    };
+}
+
+// Page template and everything it puts inline
+pub struct SiteAssets {
+    index_hbs: String,
+    index_css: String,
+    main_js: String,
+    chatgpt_png: Vec<u8>,
+    logo_png: Vec<u8>,
+}
+
+impl SiteAssets {
+    pub fn embedded() -> SiteAssets {
+        SiteAssets {
+            index_hbs: EMBEDDED_INDEX_HBS.to_string(),
+            index_css: EMBEDDED_INDEX_CSS.to_string(),
+            main_js: EMBEDDED_MAIN_JS.to_string(),
+            chatgpt_png: EMBEDDED_CHATGPT_PNG.to_vec(),
+            logo_png: EMBEDDED_LOGO_PNG.to_vec(),
+        }
+    }
+
+    // Files found in `dir` replace the embedded ones, the others are kept
+    pub fn load(dir: &Path) -> std::io::Result<SiteAssets> {
+        let text = |name: &str, embedded: &str| -> std::io::Result<String> {
+            let path = dir.join(name);
+            if path.is_file() {
+                std::fs::read_to_string(path)
+            } else {
+                Ok(embedded.to_string())
+            }
+        };
+        let bytes = |name: &str, embedded: &[u8]| -> std::io::Result<Vec<u8>> {
+            let path = dir.join(name);
+            if path.is_file() {
+                std::fs::read(path)
+            } else {
+                Ok(embedded.to_vec())
+            }
+        };
+        Ok(SiteAssets {
+            index_hbs: text("index.hbs", EMBEDDED_INDEX_HBS)?,
+            index_css: text("index.css", EMBEDDED_INDEX_CSS)?,
+            main_js: text("main.js", EMBEDDED_MAIN_JS)?,
+            chatgpt_png: bytes("chatgpt.png", EMBEDDED_CHATGPT_PNG)?,
+            logo_png: bytes("logo-128.png", EMBEDDED_LOGO_PNG)?,
+        })
+    }
+}
+
+// Site files ready for rendering: template parsed, images encoded
+struct Page {
+    registry: Handlebars<'static>,
+    style: String,
+    main_js: String,
+    chatgpt_uri: String,
+    logo_uri: String,
+}
+
+impl Page {
+    fn compile(assets: SiteAssets) -> Result<Page, RenderError> {
+        let mut registry = Handlebars::new();
+        register_helpers(&mut registry);
+        registry
+            .register_template_string(PAGE_TEMPLATE, assets.index_hbs)
+            .map_err(handlebars::TemplateRenderError::from)?;
+        Ok(Page {
+            registry,
+            style: assets.index_css,
+            main_js: assets.main_js,
+            chatgpt_uri: png_data_uri(&assets.chatgpt_png),
+            logo_uri: png_data_uri(&assets.logo_png),
+        })
+    }
+}
+
+// Renders conversation pages, made once at startup and shared by all workers
+// In development mode the site files are read and compiled again for every page.
+pub struct PageRenderer {
+    dir: Option<PathBuf>,
+    reload: bool,
+    page: Page,
+}
+
+impl PageRenderer {
+    // Only the files compiled into the binary
+    pub fn embedded() -> PageRenderer {
+        PageRenderer {
+            dir: None,
+            reload: false,
+            page: Page::compile(SiteAssets::embedded()).expect("built-in page template"),
+        }
+    }
+
+    // Files in `dir` override the built-in ones, with `reload` they are read
+    // again for every page so templates can be changed without a restart
+    pub fn new(dir: Option<PathBuf>, reload: bool) -> Result<PageRenderer, RenderError> {
+        let assets = match &dir {
+            Some(dir) => {
+                info!("Site files in {} override the built-in ones", dir.display());
+                SiteAssets::load(dir).map_err(RenderError::Site)?
+            }
+            None => SiteAssets::embedded(),
+        };
+        Ok(PageRenderer {
+            dir,
+            reload,
+            page: Page::compile(assets)?,
+        })
+    }
+
+    // Full HTML page for a conversation
+    pub fn conversation_html(&self, conv: &Conversation) -> Result<String, RenderError> {
+        let reloaded = match &self.dir {
+            Some(dir) if self.reload => {
+                match SiteAssets::load(dir)
+                    .map_err(RenderError::Site)
+                    .and_then(Page::compile)
+                {
+                    Ok(page) => Some(page),
+                    Err(err) => {
+                        // Keep serving pages while a file is being edited
+                        info!("Could not reload site files, using earlier ones: {}", err);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let page = reloaded.as_ref().unwrap_or(&self.page);
+        let contents = read_contents(conv.schema_version, &conv.contents)?;
+        let metadata = conv.metadata();
+        let timestamp: DateTime<Utc> = metadata.creationdate.into();
+        let timestamp_str: String = format!("{}", timestamp.format("%Y/%m/%d %T UTC"));
+        let body = page
+            .registry
+            .render(
+                PAGE_TEMPLATE,
+                &serde_json::json!({
+                    "style": page.style,
+                    "main_js": page.main_js,
+                    "title": metadata.title,
+                    "model": metadata.model,
+                    "openaiid": metadata.openaiid,
+                    "avatar": contents.avatar,
+                    "dialog": contents.dialog,
+                    "chatgpt_uri": page.chatgpt_uri,
+                    "logo_uri": page.logo_uri,
+                    "timestamp": timestamp_str,
+                    "hmac": &conv.hmac,
+                    "public": &conv.public,
+                    "research": &conv.research,
+                }),
+            )
+            .map_err(handlebars::TemplateRenderError::from)?;
+        Ok(body)
+    }
+}
+
+fn png_data_uri(png: &[u8]) -> String {
+    format!(
+        "data:image/png;base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(png)
+    )
+}
+
+// Check for string equality
+handlebars_helper!(string_equal: |*args| args[0] == args[1]);
+// Handle markdown
+handlebars_helper!(markdown: |*args| {
+    let txt = match args[0] {
+        serde_json::Value::String(s) => s,
+        _ => "Invalid JSON value for markdown string",
+    };
+    let parser = pulldown_cmark::Parser::new_ext(txt, *MARKDOWN_OPTIONS);
+    let mut html_output = String::new();
+    pulldown_cmark::html::push_html(&mut html_output, parser);
+    html_output
+});
+
+// Helpers the page template uses
+pub fn register_helpers(registry: &mut Handlebars) {
+    registry.register_helper("string_equal", Box::new(string_equal));
+    registry.register_helper("markdown", Box::new(markdown));
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    // Stored contents could not be read
+    Contents(LocalError),
+    // Site files could not be read
+    Site(std::io::Error),
+    Template(Box<handlebars::TemplateRenderError>),
+}
+
+impl std::fmt::Display for RenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RenderError::Contents(err) => write!(f, "stored contents: {}", err),
+            RenderError::Site(err) => write!(f, "site files: {}", err),
+            RenderError::Template(err) => write!(f, "template: {}", err),
+        }
+    }
+}
+
+impl From<LocalError> for RenderError {
+    fn from(err: LocalError) -> RenderError {
+        RenderError::Contents(err)
+    }
+}
+impl From<handlebars::TemplateRenderError> for RenderError {
+    fn from(err: handlebars::TemplateRenderError) -> RenderError {
+        RenderError::Template(Box::new(err))
+    }
+}
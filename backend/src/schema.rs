@@ -1,14 +1,91 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_tokens (id) {
+        id -> Text,
+        user_id -> Text,
+        name -> Text,
+        token_hash -> Text,
+        scopes -> Array<Text>,
+        created_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+        revoked -> Bool,
+    }
+}
+
+diesel::table! {
+    conversation_tags (conversation_id, tag_id) {
+        conversation_id -> Text,
+        tag_id -> Text,
+    }
+}
+
 diesel::table! {
     conversations (id) {
         id -> Text,
         hmac -> Text,
-        contents -> Text,
-        metadata -> Text,
+        contents -> Jsonb,
         public -> Bool,
         research -> Bool,
         deleted -> Bool,
         user_id -> Text,
+        folder_id -> Nullable<Text>,
+        created_at -> Timestamptz,
+        title -> Text,
+        model -> Text,
+        openaiid -> Text,
+        updated_at -> Timestamptz,
+        length -> Int4,
+        schema_version -> Int4,
+        version -> Int4,
+    }
+}
+
+diesel::table! {
+    folders (id) {
+        id -> Text,
+        user_id -> Text,
+        name -> Text,
     }
 }
+
+diesel::table! {
+    local_accounts (id) {
+        id -> Text,
+        email -> Text,
+        password_hash -> Nullable<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    magic_links (token_hash) {
+        token_hash -> Text,
+        email -> Text,
+        expires_at -> Timestamptz,
+        used -> Bool,
+        password_hash -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    tags (id) {
+        id -> Text,
+        user_id -> Text,
+        name -> Text,
+    }
+}
+
+diesel::joinable!(conversation_tags -> conversations (conversation_id));
+diesel::joinable!(conversation_tags -> tags (tag_id));
+diesel::joinable!(conversations -> folders (folder_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    conversation_tags,
+    conversations,
+    folders,
+    local_accounts,
+    magic_links,
+    tags,
+);
@@ -0,0 +1,186 @@
+// Conversations in memory, gone when the process stops
+// Meant for tests, everything is behind one lock.
+
+use super::store::{date_key, ConversationStore};
+use super::{next_cursor, page_size, LocalError};
+use crate::models::*;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Default)]
+pub struct MemoryStore {
+    conversations: Mutex<HashMap<String, Conversation>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    fn conversations(&self) -> MutexGuard<'_, HashMap<String, Conversation>> {
+        // Nothing can be left half changed, so a panic elsewhere doesn't matter
+        self.conversations
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// Value sorted on, as text like the other stores put it in the cursor
+fn sort_key(conv: &Conversation, sort: SortKey) -> String {
+    match sort {
+        SortKey::Date => date_key(&conv.created_at),
+        SortKey::Title => conv.title.to_lowercase(),
+        SortKey::Model => conv.model.clone(),
+    }
+}
+
+fn matches(conv: &Conversation, user_id: &str, filter: &ConversationFilter) -> bool {
+    conv.user_id == user_id
+        // No tags outside Postgres, so no conversation has the tag
+        && filter.tag.is_none()
+        && filter
+            .folder
+            .as_ref()
+            .is_none_or(|folder| conv.folder_id.as_ref() == Some(folder))
+        && filter.model.as_ref().is_none_or(|model| &conv.model == model)
+        && filter.title.as_ref().is_none_or(|title| {
+            conv.title.to_lowercase().contains(&title.to_lowercase())
+        })
+        && filter.since.is_none_or(|since| conv.created_at >= since)
+        && filter.until.is_none_or(|until| conv.created_at <= until)
+        && filter.deleted.is_none_or(|deleted| conv.deleted == deleted)
+        && filter.public.is_none_or(|public| conv.public == public)
+        && filter.research.is_none_or(|research| conv.research == research)
+}
+
+#[async_trait]
+impl ConversationStore for MemoryStore {
+    async fn find(&self, id: &str, deleted: bool) -> Result<Option<Conversation>, LocalError> {
+        Ok(self
+            .conversations()
+            .get(id)
+            .filter(|conv| conv.deleted == deleted)
+            .cloned())
+    }
+
+    async fn list(
+        &self,
+        user_id: &str,
+        filter: &ConversationFilter,
+        cursor: Option<&PageCursor>,
+    ) -> Result<ConversationPage, LocalError> {
+        let sort = filter.sort.unwrap_or(SortKey::Date);
+        let page_size = page_size(filter);
+        let mut rows: Vec<(ConversationSummary, String)> = self
+            .conversations()
+            .values()
+            .filter(|conv| matches(conv, user_id, filter))
+            .map(|conv| (conv.summary(), sort_key(conv, sort)))
+            .collect();
+        rows.sort_by(|(a, a_key), (b, b_key)| (a_key, &a.id).cmp(&(b_key, &b.id)));
+        if filter.order == SortOrder::Desc {
+            rows.reverse();
+        }
+        if let Some(cursor) = cursor {
+            let after = (&cursor.key, &cursor.id);
+            rows.retain(|(conv, key)| match filter.order {
+                SortOrder::Asc => (key, &conv.id) > after,
+                SortOrder::Desc => (key, &conv.id) < after,
+            });
+        }
+        rows.truncate(page_size + 1);
+        let next_cursor = next_cursor(&mut rows, sort, page_size);
+        Ok(ConversationPage {
+            conversations: rows
+                .into_iter()
+                .map(|(conv, _)| conv.into_info(Vec::new()))
+                .collect(),
+            next_cursor,
+        })
+    }
+
+    async fn count(&self, user_id: &str) -> Result<i64, LocalError> {
+        Ok(self
+            .conversations()
+            .values()
+            .filter(|conv| conv.user_id == user_id && !conv.deleted)
+            .count() as i64)
+    }
+
+    async fn exists(&self, user_id: &str, hmac: &str) -> Result<Option<String>, LocalError> {
+        Ok(self
+            .conversations()
+            .values()
+            .find(|conv| conv.user_id == user_id && conv.hmac == hmac && !conv.deleted)
+            .map(|conv| conv.id.clone()))
+    }
+
+    async fn insert(&self, conversation: &Conversation) -> Result<(), LocalError> {
+        let mut conversations = self.conversations();
+        if conversations.contains_key(&conversation.id) {
+            return Err(LocalError::AlreadyExists);
+        }
+        conversations.insert(conversation.id.clone(), conversation.clone());
+        Ok(())
+    }
+
+    async fn create(
+        &self,
+        conversation: &Conversation,
+        max_count: Option<i64>,
+    ) -> Result<String, LocalError> {
+        // Checked and inserted under the same lock
+        let mut conversations = self.conversations();
+        let live = || {
+            conversations
+                .values()
+                .filter(|conv| conv.user_id == conversation.user_id && !conv.deleted)
+        };
+        if let Some(existing) = live().find(|conv| conv.hmac == conversation.hmac) {
+            return Ok(existing.id.clone());
+        }
+        if max_count.is_some_and(|max_count| live().count() as i64 >= max_count) {
+            return Err(LocalError::MaxCount);
+        }
+        if conversations.contains_key(&conversation.id) {
+            return Err(LocalError::AlreadyExists);
+        }
+        conversations.insert(conversation.id.clone(), conversation.clone());
+        Ok(conversation.id.clone())
+    }
+
+    async fn update(
+        &self,
+        id: &str,
+        changes: &ConversationChanges,
+        version: i32,
+    ) -> Result<i32, LocalError> {
+        let mut conversations = self.conversations();
+        let conv = conversations.get_mut(id).ok_or(LocalError::NotFound)?;
+        if conv.version != version {
+            return Err(LocalError::VersionMismatch);
+        }
+        conv.contents = changes.contents.clone();
+        conv.schema_version = changes.schema_version;
+        conv.title = changes.title.clone();
+        conv.model = changes.model.clone();
+        conv.openaiid = changes.openaiid.clone();
+        conv.length = changes.length;
+        conv.public = changes.public;
+        conv.research = changes.research;
+        conv.hmac = changes.hmac.clone();
+        conv.updated_at = chrono::Utc::now();
+        conv.version += 1;
+        Ok(conv.version)
+    }
+
+    async fn set_deleted(&self, id: &str, deleted: bool) -> Result<(), LocalError> {
+        let mut conversations = self.conversations();
+        let conv = conversations.get_mut(id).ok_or(LocalError::NotFound)?;
+        conv.deleted = deleted;
+        conv.updated_at = chrono::Utc::now();
+        conv.version += 1;
+        Ok(())
+    }
+}
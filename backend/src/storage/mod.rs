@@ -0,0 +1,843 @@
+// Database access
+//
+// Connection pool, migrations and the queries used by the API. Functions take a
+// connection and are async, handlers run them through `with_retries`.
+// Conversations themselves are reached through a `ConversationStore` (see
+// store.rs), which may keep them somewhere else.
+//
+// The pool hands out at most `PoolConfig::size` connections. A request that
+// can't get one within `connection_timeout` fails with DbTimeout (503) instead
+// of queueing forever, and Postgres cancels statements that run longer than
+// `statement_timeout`. Migrations run on a plain blocking connection,
+// diesel_migrations has no async version.
+
+use crate::mailer;
+use crate::models::*;
+use crate::schema::{conversation_tags, folders, tags};
+use diesel::prelude::*;
+use diesel_async::pooled_connection::{AsyncDieselConnectionManager, ManagerConfig};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, RunQueryDsl, SimpleAsyncConnection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use log::info;
+use std::time::Duration;
+
+mod memory;
+mod postgres;
+mod retry;
+mod sqlite;
+mod store;
+
+pub use memory::MemoryStore;
+pub use postgres::PgStore;
+pub use retry::{is_transient, with_retries, MAX_ATTEMPTS};
+pub use sqlite::SqliteStore;
+pub use store::{ConversationStore, StoreConfig};
+
+// Types related to Postgres connection to database
+pub type DbConnection = diesel_async::AsyncPgConnection;
+pub type DbConnectionManager = AsyncDieselConnectionManager<DbConnection>;
+pub type DbPool = diesel_async::pooled_connection::bb8::Pool<DbConnection>;
+pub type DbError = Box<dyn std::error::Error + Send + Sync>;
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 500;
+pub const CONTENTS_MIGRATION_BATCH: i64 = 500;
+
+// Connection pool settings, see config.rs for where they come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    // Most connections open at once
+    pub size: u32,
+    // Longest wait for a connection from the pool
+    pub connection_timeout: Duration,
+    // Longest a single statement may run, zero for no limit
+    pub statement_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            size: 10,
+            connection_timeout: Duration::from_secs(5),
+            statement_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+// Look in DB for specific ID an return DB Conversation if found
+pub async fn find_conversation_by_id(
+    conn: &mut DbConnection,
+    convo_id: &str,
+    deleted_entry: bool,
+) -> Result<Option<Conversation>, DbError> {
+    use crate::schema::conversations::dsl::*;
+    let results = conversations
+        .filter(id.eq(convo_id))
+        .filter(deleted.eq(deleted_entry))
+        .limit(1)
+        .load::<Conversation>(conn)
+        .await?;
+    if results.is_empty() {
+        Ok(None)
+    } else {
+        let result = results[0].clone();
+        Ok(Some(result))
+    }
+}
+
+// Get conversation count so we can limit free users
+// Only counts non-deleted posts
+pub async fn get_conversation_count(conn: &mut DbConnection, userid: &str) -> Result<i64, DbError> {
+    use crate::schema::conversations::dsl::*;
+    let results: i64 = conversations
+        .filter(user_id.eq(userid))
+        .filter(deleted.eq(false))
+        .count()
+        .get_result(conn)
+        .await?;
+    Ok(results)
+}
+
+// See if a conversation already exists (by hmac)
+// If exists, returns Some<id>, otherwise None
+pub async fn conversation_exists(
+    conn: &mut DbConnection,
+    uid: &str,
+    hmac_digest: &str,
+) -> Result<Option<String>, DbError> {
+    use crate::schema::conversations::dsl::*;
+    let results = conversations
+        .filter(user_id.eq(uid))
+        .filter(hmac.eq(hmac_digest))
+        .filter(deleted.eq(false))
+        .limit(1)
+        .load::<Conversation>(conn)
+        .await?;
+    if results.is_empty() {
+        Ok(None)
+    } else {
+        let result = results[0].id.clone();
+        Ok(Some(result))
+    }
+}
+
+// Add a conversation unless the user already has one with the same digest
+// Returns the id of the conversation that is kept. With `max_count` the user
+// may have at most that many. Creates of one user take turns on an advisory
+// lock, so parallel uploads can neither duplicate a conversation nor go past
+// the limit; each statement after the lock sees what the others committed.
+pub async fn create_conversation(
+    conn: &mut DbConnection,
+    conversation: &Conversation,
+    max_count: Option<i64>,
+) -> Result<String, LocalError> {
+    use diesel::sql_types::Text;
+    conn.transaction::<_, LocalError, _>(|conn| {
+        async move {
+            diesel::sql_query("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))")
+                .bind::<Text, _>(format!("create conversation {}", conversation.user_id))
+                .execute(conn)
+                .await?;
+            if let Some(existing) =
+                conversation_exists(conn, &conversation.user_id, &conversation.hmac).await?
+            {
+                return Ok(existing);
+            }
+            if let Some(max_count) = max_count {
+                if get_conversation_count(conn, &conversation.user_id).await? >= max_count {
+                    return Err(LocalError::MaxCount);
+                }
+            }
+            diesel::insert_into(crate::schema::conversations::table)
+                .values(conversation)
+                .execute(conn)
+                .await?;
+            Ok(conversation.id.clone())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+// Look in DB for all tags attached to conversations of a user
+// Returns map from conversation id to its tags, sorted by name
+pub async fn find_tags_by_conversation(
+    conn: &mut DbConnection,
+    uid: &str,
+    convo_ids: &[String],
+) -> Result<std::collections::HashMap<String, Vec<Tag>>, DbError> {
+    let rows = conversation_tags::table
+        .inner_join(tags::table)
+        .filter(tags::user_id.eq(uid))
+        .filter(conversation_tags::conversation_id.eq_any(convo_ids))
+        .order_by(tags::name)
+        .select((conversation_tags::conversation_id, tags::all_columns))
+        .load::<(String, Tag)>(conn)
+        .await?;
+    let mut result = std::collections::HashMap::<String, Vec<Tag>>::new();
+    for (convo_id, tag) in rows {
+        result.entry(convo_id).or_default().push(tag);
+    }
+    Ok(result)
+}
+
+// Escape a user string for use inside a LIKE pattern
+pub fn like_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// Number of conversations on one page
+pub(crate) fn page_size(filter: &ConversationFilter) -> usize {
+    filter
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE)
+}
+
+// Cut sorted rows (with their sort key as text) down to one page
+// One extra row is requested to know if there is a next page, when there is
+// the cursor points after the last row kept.
+pub(crate) fn next_cursor(
+    rows: &mut Vec<(ConversationSummary, String)>,
+    sort: SortKey,
+    page_size: usize,
+) -> Option<String> {
+    if rows.len() <= page_size {
+        return None;
+    }
+    rows.truncate(page_size);
+    rows.last().map(|(conv, key)| {
+        PageCursor {
+            sort,
+            key: key.clone(),
+            id: conv.id.clone(),
+        }
+        .encode()
+    })
+}
+
+// Look in DB for one page of conversations of a user
+// Only loads the columns needed for listing, never the contents
+pub async fn find_conversations_by_user(
+    conn: &mut DbConnection,
+    uid: &str,
+    filter: &ConversationFilter,
+    cursor: Option<&PageCursor>,
+) -> Result<ConversationPage, DbError> {
+    use crate::schema::conversations::dsl::*;
+    use diesel::dsl::sql;
+    use diesel::sql_types::{Bool, Text};
+    let sort = filter.sort.unwrap_or(SortKey::Date);
+    let (sort_expr, sort_type) = sort.sql();
+    let page_size = page_size(filter);
+    let mut query = conversations
+        .filter(user_id.eq(uid))
+        .select((
+            (
+                id, hmac, title, model, openaiid, created_at, length, public, research, deleted,
+                folder_id,
+            ),
+            sql::<Text>(&format!("({})::TEXT", sort_expr)),
+        ))
+        .into_boxed();
+    if let Some(folder) = &filter.folder {
+        query = query.filter(folder_id.eq(folder));
+    }
+    if let Some(tag) = &filter.tag {
+        query = query.filter(
+            id.eq_any(
+                conversation_tags::table
+                    .filter(conversation_tags::tag_id.eq(tag))
+                    .select(conversation_tags::conversation_id),
+            ),
+        );
+    }
+    if let Some(model_name) = &filter.model {
+        query = query.filter(model.eq(model_name));
+    }
+    if let Some(title_part) = &filter.title {
+        query = query.filter(title.ilike(format!("%{}%", like_escape(title_part))));
+    }
+    if let Some(since) = filter.since {
+        query = query.filter(created_at.ge(since));
+    }
+    if let Some(until) = filter.until {
+        query = query.filter(created_at.le(until));
+    }
+    if let Some(value) = filter.deleted {
+        query = query.filter(deleted.eq(value));
+    }
+    if let Some(value) = filter.public {
+        query = query.filter(public.eq(value));
+    }
+    if let Some(value) = filter.research {
+        query = query.filter(research.eq(value));
+    }
+    // Sort by chosen expression with id to break ties, keyset pagination on both
+    let (direction, comparison) = match filter.order {
+        SortOrder::Asc => ("ASC", ">"),
+        SortOrder::Desc => ("DESC", "<"),
+    };
+    if let Some(cursor) = cursor {
+        query = query.filter(
+            sql::<Bool>(&format!(
+                "({}, conversations.id) {} (CAST(",
+                sort_expr, comparison
+            ))
+            .bind::<Text, _>(cursor.key.clone())
+            .sql(&format!(" AS {}), ", sort_type))
+            .bind::<Text, _>(cursor.id.clone())
+            .sql(")"),
+        );
+    }
+    let mut rows = query
+        .order(sql::<Text>(&format!(
+            "{} {}, conversations.id {}",
+            sort_expr, direction, direction
+        )))
+        .limit(page_size as i64 + 1)
+        .load::<(ConversationSummary, String)>(conn)
+        .await?;
+    let next_cursor = next_cursor(&mut rows, sort, page_size);
+    let convo_ids: Vec<String> = rows.iter().map(|(conv, _)| conv.id.clone()).collect();
+    let mut tags_by_conversation = find_tags_by_conversation(conn, uid, &convo_ids).await?;
+    Ok(ConversationPage {
+        conversations: rows
+            .into_iter()
+            .map(|(conv, _)| {
+                let tags = tags_by_conversation.remove(&conv.id).unwrap_or_default();
+                conv.into_info(tags)
+            })
+            .collect(),
+        next_cursor,
+    })
+}
+
+// Look in DB for all tags of a user
+pub async fn find_tags_by_user(
+    conn: &mut DbConnection,
+    uid: &String,
+) -> Result<Vec<Tag>, LocalError> {
+    use crate::schema::tags::dsl::*;
+    Ok(tags
+        .filter(user_id.eq(uid))
+        .order_by(name)
+        .load::<Tag>(conn)
+        .await?)
+}
+
+pub async fn create_tag(
+    conn: &mut DbConnection,
+    uid: &str,
+    tag_name: String,
+) -> Result<Tag, LocalError> {
+    let tag = Tag {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        user_id: uid.to_string(),
+        name: tag_name,
+    };
+    diesel::insert_into(tags::table)
+        .values(&tag)
+        .execute(conn)
+        .await?;
+    Ok(tag)
+}
+
+pub async fn rename_tag(
+    conn: &mut DbConnection,
+    uid: &String,
+    tid: &String,
+    tag_name: String,
+) -> Result<(), LocalError> {
+    use crate::schema::tags::dsl::*;
+    let updated = diesel::update(tags.filter(id.eq(tid)).filter(user_id.eq(uid)))
+        .set(name.eq(tag_name))
+        .execute(conn)
+        .await?;
+    if updated == 0 {
+        return Err(LocalError::NotFound);
+    }
+    Ok(())
+}
+
+// Deleting a tag removes it from all conversations (cascade in DB)
+pub async fn delete_tag(
+    conn: &mut DbConnection,
+    uid: &String,
+    tid: &String,
+) -> Result<(), LocalError> {
+    use crate::schema::tags::dsl::*;
+    let deleted_rows = diesel::delete(tags.filter(id.eq(tid)).filter(user_id.eq(uid)))
+        .execute(conn)
+        .await?;
+    if deleted_rows == 0 {
+        return Err(LocalError::NotFound);
+    }
+    Ok(())
+}
+
+// Look in DB for all folders of a user
+pub async fn find_folders_by_user(
+    conn: &mut DbConnection,
+    uid: &String,
+) -> Result<Vec<Folder>, LocalError> {
+    use crate::schema::folders::dsl::*;
+    Ok(folders
+        .filter(user_id.eq(uid))
+        .order_by(name)
+        .load::<Folder>(conn)
+        .await?)
+}
+
+pub async fn create_folder(
+    conn: &mut DbConnection,
+    uid: &str,
+    folder_name: String,
+) -> Result<Folder, LocalError> {
+    let folder = Folder {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        user_id: uid.to_string(),
+        name: folder_name,
+    };
+    diesel::insert_into(folders::table)
+        .values(&folder)
+        .execute(conn)
+        .await?;
+    Ok(folder)
+}
+
+pub async fn rename_folder(
+    conn: &mut DbConnection,
+    uid: &String,
+    fid: &String,
+    folder_name: String,
+) -> Result<(), LocalError> {
+    use crate::schema::folders::dsl::*;
+    let updated = diesel::update(folders.filter(id.eq(fid)).filter(user_id.eq(uid)))
+        .set(name.eq(folder_name))
+        .execute(conn)
+        .await?;
+    if updated == 0 {
+        return Err(LocalError::NotFound);
+    }
+    Ok(())
+}
+
+// Deleting a folder leaves its conversations without a folder (set null in DB)
+pub async fn delete_folder(
+    conn: &mut DbConnection,
+    uid: &String,
+    fid: &String,
+) -> Result<(), LocalError> {
+    use crate::schema::folders::dsl::*;
+    let deleted_rows = diesel::delete(folders.filter(id.eq(fid)).filter(user_id.eq(uid)))
+        .execute(conn)
+        .await?;
+    if deleted_rows == 0 {
+        return Err(LocalError::NotFound);
+    }
+    Ok(())
+}
+
+// Make sure conversation exists (not deleted) and is owned by user
+pub async fn check_conversation_owner(
+    conn: &mut DbConnection,
+    uid: &String,
+    convo_id: &str,
+) -> Result<(), LocalError> {
+    match find_conversation_by_id(conn, convo_id, /*deleted=*/ false).await? {
+        Some(conv) if conv.user_id == *uid => Ok(()),
+        Some(_) => {
+            info!("Conversation to organize owner does not match requestor");
+            Err(LocalError::AuthorizationProblem)
+        }
+        None => Err(LocalError::NotFound),
+    }
+}
+
+pub async fn set_conversation_folder(
+    conn: &mut DbConnection,
+    uid: &String,
+    convo_id: &String,
+    fid: Option<&String>,
+) -> Result<(), LocalError> {
+    check_conversation_owner(conn, uid, convo_id).await?;
+    if let Some(fid) = fid {
+        folders::table
+            .filter(folders::id.eq(fid))
+            .filter(folders::user_id.eq(uid))
+            .select(folders::id)
+            .first::<String>(conn)
+            .await?;
+    }
+    use crate::schema::conversations::dsl::*;
+    diesel::update(conversations.filter(id.eq(convo_id)))
+        .set(folder_id.eq(fid))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+// Attach (or detach) a tag to a conversation, both must belong to user
+pub async fn set_conversation_tag(
+    conn: &mut DbConnection,
+    uid: &String,
+    convo_id: &String,
+    tid: &String,
+    attached: bool,
+) -> Result<(), LocalError> {
+    check_conversation_owner(conn, uid, convo_id).await?;
+    tags::table
+        .filter(tags::id.eq(tid))
+        .filter(tags::user_id.eq(uid))
+        .select(tags::id)
+        .first::<String>(conn)
+        .await?;
+    use crate::schema::conversation_tags::dsl::*;
+    if attached {
+        diesel::insert_into(conversation_tags)
+            .values(ConversationTag {
+                conversation_id: convo_id.clone(),
+                tag_id: tid.clone(),
+            })
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await?;
+    } else {
+        diesel::delete(
+            conversation_tags
+                .filter(conversation_id.eq(convo_id))
+                .filter(tag_id.eq(tid)),
+        )
+        .execute(conn)
+        .await?;
+    }
+    Ok(())
+}
+
+// Find tag by name for a user, creating it if it does not exist yet
+// Returns the tag id
+pub async fn find_or_create_tag(
+    conn: &mut DbConnection,
+    uid: &String,
+    tag_name: &String,
+) -> Result<String, diesel::result::Error> {
+    use crate::schema::tags::dsl::*;
+    let new_tag = Tag {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        user_id: uid.clone(),
+        name: tag_name.clone(),
+    };
+    diesel::insert_into(tags)
+        .values(&new_tag)
+        .on_conflict((user_id, name))
+        .do_nothing()
+        .execute(conn)
+        .await?;
+    tags.filter(user_id.eq(uid))
+        .filter(name.eq(tag_name))
+        .select(id)
+        .first::<String>(conn)
+        .await
+}
+
+// Apply one operation to many conversations inside a single transaction
+// Each id is checked for existence and ownership separately, failures are
+// reported per item and do not stop the other items from being updated.
+pub async fn apply_bulk_operation(
+    conn: &mut DbConnection,
+    uid: &String,
+    ids: &[String],
+    operation: &BulkOperation,
+) -> Result<Vec<BulkItemResult>, DbError> {
+    let results = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            async move {
+                let tag_id = match operation {
+                    BulkOperation::AddTag(tag_name) => {
+                        Some(find_or_create_tag(conn, uid, tag_name).await?)
+                    }
+                    _ => None,
+                };
+                // Undelete works on deleted conversations, everything else on live ones
+                let deleted_entry = matches!(operation, BulkOperation::Undelete);
+                let mut results = Vec::with_capacity(ids.len());
+                for convo_id in ids {
+                    use crate::schema::conversations::dsl::*;
+                    let owner = conversations
+                        .filter(id.eq(convo_id))
+                        .filter(deleted.eq(deleted_entry))
+                        .select(user_id)
+                        .first::<String>(conn)
+                        .await
+                        .optional()?;
+                    let status = match owner {
+                        None => BulkItemStatus::NotFound,
+                        Some(owner) if owner != *uid => {
+                            info!("Conversation in bulk request owner does not match requestor");
+                            BulkItemStatus::Forbidden
+                        }
+                        Some(_) => {
+                            let target = conversations.filter(id.eq(convo_id));
+                            match operation {
+                                BulkOperation::Delete => {
+                                    diesel::update(target)
+                                        .set((deleted.eq(true), version.eq(version + 1)))
+                                        .execute(conn)
+                                        .await?;
+                                }
+                                BulkOperation::Undelete => {
+                                    diesel::update(target)
+                                        .set((deleted.eq(false), version.eq(version + 1)))
+                                        .execute(conn)
+                                        .await?;
+                                }
+                                BulkOperation::SetPublic(value) => {
+                                    diesel::update(target)
+                                        .set((public.eq(value), version.eq(version + 1)))
+                                        .execute(conn)
+                                        .await?;
+                                }
+                                BulkOperation::SetResearch(value) => {
+                                    diesel::update(target)
+                                        .set((research.eq(value), version.eq(version + 1)))
+                                        .execute(conn)
+                                        .await?;
+                                }
+                                BulkOperation::AddTag(_) => {
+                                    let link = ConversationTag {
+                                        conversation_id: convo_id.clone(),
+                                        tag_id: tag_id.clone().unwrap_or_default(),
+                                    };
+                                    diesel::insert_into(conversation_tags::table)
+                                        .values(&link)
+                                        .on_conflict_do_nothing()
+                                        .execute(conn)
+                                        .await?;
+                                }
+                            }
+                            BulkItemStatus::Ok
+                        }
+                    };
+                    results.push(BulkItemResult {
+                        id: convo_id.clone(),
+                        status,
+                    });
+                }
+                Ok(results)
+            }
+            .scope_boxed()
+        })
+        .await?;
+    Ok(results)
+}
+
+#[derive(Debug)]
+pub enum LocalError {
+    DbConnectionProblem,
+    SerializationFailed,
+    DbError,
+    AuthorizationProblem,
+    NotFound,
+    MaxCount,
+    AlreadyExists,
+    // Stored contents have a schema version this server can't read
+    UnsupportedContentsVersion(i32),
+    MailFailed,
+    // Argon2 could not hash a password
+    PasswordHash,
+    // Transaction aborted because of a concurrent one, trying again may work
+    DbContention,
+    // No free connection in time or statement cancelled for running too long,
+    // the database is overloaded and trying again right away makes it worse
+    DbTimeout,
+    // Conversation changed since the version the client had
+    VersionMismatch,
+}
+
+impl std::fmt::Display for LocalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            LocalError::DbConnectionProblem => write!(f, "problem with db connection pool"),
+            LocalError::SerializationFailed => write!(f, "json serialization of contents failed"),
+            LocalError::DbError => write!(f, "problem with db connection"),
+            LocalError::AuthorizationProblem => write!(f, "authorization problem"),
+            LocalError::NotFound => write!(f, "conversation not found"),
+            LocalError::MaxCount => write!(f, "Maximum free share count reached"),
+            LocalError::AlreadyExists => write!(f, "name already in use"),
+            LocalError::UnsupportedContentsVersion(version) => {
+                write!(f, "unsupported contents schema version {}", version)
+            }
+            LocalError::MailFailed => write!(f, "could not send mail"),
+            LocalError::PasswordHash => write!(f, "could not hash password"),
+            LocalError::DbContention => write!(f, "transaction conflicted with another one"),
+            LocalError::DbTimeout => write!(f, "database did not answer in time"),
+            LocalError::VersionMismatch => write!(f, "conversation was changed since it was read"),
+        }
+    }
+}
+impl std::convert::From<diesel_async::pooled_connection::bb8::RunError> for LocalError {
+    fn from(err: diesel_async::pooled_connection::bb8::RunError) -> LocalError {
+        match err {
+            bb8::RunError::User(err) => {
+                info!("Could not get database connection: {}", err);
+                LocalError::DbConnectionProblem
+            }
+            bb8::RunError::TimedOut => {
+                info!("No database connection free in time");
+                LocalError::DbTimeout
+            }
+        }
+    }
+}
+impl std::convert::From<serde_json::Error> for LocalError {
+    fn from(_err: serde_json::Error) -> LocalError {
+        LocalError::SerializationFailed
+    }
+}
+impl std::convert::From<DbError> for LocalError {
+    fn from(err: DbError) -> LocalError {
+        // Keep the distinctions made for diesel errors
+        match err.downcast::<diesel::result::Error>() {
+            Ok(err) => LocalError::from(*err),
+            Err(err) => match err.downcast::<diesel_async::pooled_connection::bb8::RunError>() {
+                Ok(err) => LocalError::from(*err),
+                Err(err) => {
+                    info!("Database problem: {}", err);
+                    LocalError::DbError
+                }
+            },
+        }
+    }
+}
+impl std::convert::From<mailer::MailError> for LocalError {
+    fn from(_err: mailer::MailError) -> LocalError {
+        LocalError::MailFailed
+    }
+}
+impl std::convert::From<diesel::result::Error> for LocalError {
+    fn from(err: diesel::result::Error) -> LocalError {
+        match err {
+            diesel::result::Error::NotFound => LocalError::NotFound,
+            diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::UniqueViolation,
+                _,
+            ) => LocalError::AlreadyExists,
+            diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::SerializationFailure,
+                _,
+            ) => LocalError::DbContention,
+            // Connection lost, diesel-async reports it as a command that couldn't be sent
+            diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::ClosedConnection
+                | diesel::result::DatabaseErrorKind::UnableToSendCommand,
+                _,
+            ) => LocalError::DbConnectionProblem,
+            // SQLSTATE 57P01 (server shutting down or backend terminated) and 57014
+            // have no kinds of their own
+            diesel::result::Error::DatabaseError(_, info)
+                if info.message() == "terminating connection due to administrator command" =>
+            {
+                LocalError::DbConnectionProblem
+            }
+            diesel::result::Error::DatabaseError(_, info)
+                if info.message() == "canceling statement due to statement timeout" =>
+            {
+                info!("Database statement timed out");
+                LocalError::DbTimeout
+            }
+            err => {
+                info!("Database problem: {}", err);
+                LocalError::DbError
+            }
+        }
+    }
+}
+
+// Rewrite all stored contents that are not in the current shape
+// Works through the table in batches of ids, returns number of rows rewritten
+pub async fn migrate_all_contents(conn: &mut DbConnection) -> Result<usize, DbError> {
+    migrate_all_contents_with(conn, CONTENTS_UPGRADES).await
+}
+
+// Same with the given upgrade steps instead of CONTENTS_UPGRADES
+pub async fn migrate_all_contents_with(
+    conn: &mut DbConnection,
+    upgrades: &[ContentsUpgrade],
+) -> Result<usize, DbError> {
+    use crate::schema::conversations::dsl::*;
+    let current_version = upgrades.len() as i32 + 1;
+    let mut total = 0;
+    let mut last_id = String::new();
+    loop {
+        let batch = conversations
+            .filter(schema_version.ne(current_version))
+            .filter(id.gt(&last_id))
+            .order_by(id)
+            .select((id, schema_version, contents))
+            .limit(CONTENTS_MIGRATION_BATCH)
+            .load::<(String, i32, serde_json::Value)>(conn)
+            .await?;
+        let Some((batch_last_id, _, _)) = batch.last() else {
+            return Ok(total);
+        };
+        last_id = batch_last_id.clone();
+        conn.transaction::<_, DbError, _>(|conn| {
+            async {
+                for (convo_id, stored_version, value) in &batch {
+                    let upgraded = read_contents_with(upgrades, *stored_version, value)
+                        .map_err(|err| format!("conversation {}: {}", convo_id, err))?;
+                    diesel::update(conversations.filter(id.eq(convo_id)))
+                        .set((
+                            contents.eq(serde_json::to_value(&upgraded)?),
+                            schema_version.eq(current_version),
+                        ))
+                        .execute(conn)
+                        .await?;
+                }
+                Ok(())
+            }
+            .scope_boxed()
+        })
+        .await?;
+        total += batch.len();
+        info!("Rewrote {} conversations so far", total);
+    }
+}
+
+// Pool that connects lazily, so the server starts while the database is down
+// Must be called inside the runtime, the pool runs a task that closes idle
+// connections.
+pub fn initialize_db_pool(database_url: &str, config: &PoolConfig) -> DbPool {
+    let statement_timeout = config.statement_timeout.as_millis();
+    let mut manager_config = ManagerConfig::default();
+    manager_config.custom_setup = Box::new(move |url| {
+        Box::pin(async move {
+            let mut conn = DbConnection::establish(url).await?;
+            conn.batch_execute(&format!("SET statement_timeout = {}", statement_timeout))
+                .await
+                .map_err(diesel::ConnectionError::CouldntSetupConfiguration)?;
+            Ok(conn)
+        })
+    });
+    bb8::Pool::builder()
+        .max_size(config.size)
+        .connection_timeout(config.connection_timeout)
+        .build_unchecked(DbConnectionManager::new_with_config(
+            database_url,
+            manager_config,
+        ))
+}
+
+// Bring the schema up to date, returns the number of migrations applied
+// Blocks, call it before starting the server.
+pub fn run_migrations(database_url: &str) -> Result<usize, DbError> {
+    let mut conn = diesel::PgConnection::establish(database_url)?;
+    let applied = conn.run_pending_migrations(MIGRATIONS)?;
+    Ok(applied.len())
+}
@@ -0,0 +1,138 @@
+// Conversations in Postgres, using the queries in storage/mod.rs
+// Every call gets its own connection and is retried on transient failures.
+
+use super::*;
+use crate::schema::conversations;
+use async_trait::async_trait;
+
+pub struct PgStore {
+    pool: DbPool,
+}
+
+impl PgStore {
+    pub fn new(pool: DbPool) -> PgStore {
+        PgStore { pool }
+    }
+}
+
+#[async_trait]
+impl ConversationStore for PgStore {
+    async fn find(&self, id: &str, deleted: bool) -> Result<Option<Conversation>, LocalError> {
+        with_retries(&self.pool, |conn| {
+            find_conversation_by_id(conn, id, deleted).scope_boxed()
+        })
+        .await
+    }
+
+    async fn list(
+        &self,
+        user_id: &str,
+        filter: &ConversationFilter,
+        cursor: Option<&PageCursor>,
+    ) -> Result<ConversationPage, LocalError> {
+        with_retries(&self.pool, |conn| {
+            find_conversations_by_user(conn, user_id, filter, cursor).scope_boxed()
+        })
+        .await
+    }
+
+    async fn count(&self, user_id: &str) -> Result<i64, LocalError> {
+        with_retries(&self.pool, |conn| {
+            get_conversation_count(conn, user_id).scope_boxed()
+        })
+        .await
+    }
+
+    async fn exists(&self, user_id: &str, hmac: &str) -> Result<Option<String>, LocalError> {
+        with_retries(&self.pool, |conn| {
+            conversation_exists(conn, user_id, hmac).scope_boxed()
+        })
+        .await
+    }
+
+    async fn insert(&self, conversation: &Conversation) -> Result<(), LocalError> {
+        with_retries(&self.pool, |conn| {
+            async move {
+                diesel::insert_into(conversations::table)
+                    .values(conversation)
+                    .execute(conn)
+                    .await?;
+                Ok::<_, LocalError>(())
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    async fn create(
+        &self,
+        conversation: &Conversation,
+        max_count: Option<i64>,
+    ) -> Result<String, LocalError> {
+        with_retries(&self.pool, |conn| {
+            create_conversation(conn, conversation, max_count).scope_boxed()
+        })
+        .await
+    }
+
+    async fn update(
+        &self,
+        id: &str,
+        changes: &ConversationChanges,
+        version: i32,
+    ) -> Result<i32, LocalError> {
+        with_retries(&self.pool, |conn| {
+            async move {
+                let updated = diesel::update(
+                    conversations::table
+                        .find(id)
+                        .filter(conversations::version.eq(version)),
+                )
+                .set((
+                    changes,
+                    conversations::version.eq(conversations::version + 1),
+                ))
+                .returning(conversations::version)
+                .get_result::<i32>(conn)
+                .await
+                .optional()?;
+                match updated {
+                    Some(new_version) => Ok(new_version),
+                    // Nothing matched, find out which part didn't
+                    None => match conversations::table
+                        .find(id)
+                        .select(conversations::id)
+                        .first::<String>(conn)
+                        .await
+                        .optional()?
+                    {
+                        Some(_) => Err(LocalError::VersionMismatch),
+                        None => Err(LocalError::NotFound),
+                    },
+                }
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    async fn set_deleted(&self, id: &str, deleted: bool) -> Result<(), LocalError> {
+        with_retries(&self.pool, |conn| {
+            async move {
+                let updated = diesel::update(conversations::table.find(id))
+                    .set((
+                        conversations::deleted.eq(deleted),
+                        conversations::version.eq(conversations::version + 1),
+                    ))
+                    .execute(conn)
+                    .await?;
+                if updated == 0 {
+                    return Err(LocalError::NotFound);
+                }
+                Ok(())
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,61 @@
+// Running database work from handlers
+//
+// `with_retries` gets a connection from the pool and runs the work on it. When
+// that fails for a reason that is likely to go away by itself (no connection
+// could be made, the connection was closed under us, or Postgres aborted the
+// transaction because of a concurrent one) the work is run again on a fresh
+// connection, a few times with a short wait in between. Any other error is
+// returned right away. The work may run more than once, so it must not have
+// effects outside the database that can't be repeated.
+//
+// The work is a closure returning a boxed future borrowing the connection:
+//   with_retries(&pool, |conn| find_tags_by_user(conn, &uid).scope_boxed())
+
+use super::{DbConnection, DbPool, LocalError};
+use diesel_async::scoped_futures::ScopedBoxFuture;
+use log::info;
+
+pub const MAX_ATTEMPTS: u32 = 3;
+// Wait before the second attempt, doubled for every attempt after that
+const FIRST_RETRY_DELAY_MS: u64 = 50;
+
+// Could running the same work again succeed?
+// A full pool (DbTimeout) is not retried, the wait for a connection was long
+// enough already.
+pub fn is_transient(err: &LocalError) -> bool {
+    matches!(
+        err,
+        LocalError::DbConnectionProblem | LocalError::DbContention
+    )
+}
+
+pub async fn with_retries<'a, T, E, F>(pool: &DbPool, mut work: F) -> Result<T, LocalError>
+where
+    E: Into<LocalError>,
+    F: for<'c> FnMut(&'c mut DbConnection) -> ScopedBoxFuture<'a, 'c, Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        let result = match pool.get().await {
+            Ok(mut conn) => work(&mut conn).await.map_err(Into::into),
+            // bb8 also times out when no connection can be made at all, a pool
+            // without connections means the database is gone rather than busy
+            Err(bb8::RunError::TimedOut) if pool.state().connections == 0 => {
+                Err(LocalError::DbConnectionProblem)
+            }
+            Err(err) => Err(err.into()),
+        };
+        match result {
+            Err(err) if is_transient(&err) && attempt < MAX_ATTEMPTS => {
+                let delay = FIRST_RETRY_DELAY_MS << (attempt - 1);
+                info!(
+                    "Database attempt {} failed ({}), trying again in {}ms",
+                    attempt, err, delay
+                );
+                tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
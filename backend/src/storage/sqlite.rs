@@ -0,0 +1,466 @@
+// Conversations in a SQLite file, for small self-hosted deployments
+//
+// One connection behind a lock is plenty at that size, statements run on the
+// blocking thread pool. The table is made when the file is opened. Times are
+// stored as text in a format that sorts like the time itself (see `date_key`)
+// and contents as JSON text. Titles are compared with `fold_case`, which
+// lowercases like Rust does (SQLite's own lower() only knows ASCII) so the
+// title filter and sort match the in-memory store.
+// The functions below run the statements, the store hands them to `run`.
+
+use super::store::{date_key, parse_date_key, ConversationStore};
+use super::{like_escape, next_cursor, page_size, LocalError};
+use crate::models::*;
+use async_trait::async_trait;
+use log::info;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::{FromSql, FromSqlError, ToSql, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction, TransactionBehavior};
+use std::sync::{Arc, Mutex};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS conversations (
+    id TEXT PRIMARY KEY,
+    hmac TEXT NOT NULL,
+    contents TEXT NOT NULL,
+    public INTEGER NOT NULL,
+    research INTEGER NOT NULL,
+    deleted INTEGER NOT NULL,
+    user_id TEXT NOT NULL,
+    folder_id TEXT,
+    created_at TEXT NOT NULL,
+    title TEXT NOT NULL,
+    model TEXT NOT NULL,
+    openaiid TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    length INTEGER NOT NULL,
+    schema_version INTEGER NOT NULL,
+    version INTEGER NOT NULL DEFAULT 1
+);
+CREATE INDEX IF NOT EXISTS conversations_user_id_index ON conversations (user_id, created_at);
+";
+
+const CONVERSATION_COLUMNS: &str = "id, hmac, contents, public, research, deleted, user_id, \
+     folder_id, created_at, title, model, openaiid, updated_at, length, schema_version, version";
+const SUMMARY_COLUMNS: &str =
+    "id, hmac, title, model, openaiid, created_at, length, public, research, deleted, folder_id";
+
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    // Open (or make) the database file, ":memory:" gives a private database
+    pub fn open(path: &str) -> Result<SqliteStore, LocalError> {
+        let conn = Connection::open(path)?;
+        conn.create_scalar_function(
+            "fold_case",
+            1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            |ctx| Ok(ctx.get::<String>(0)?.to_lowercase()),
+        )?;
+        conn.execute_batch(SCHEMA)?;
+        // Files made before conversations had versions
+        if conn
+            .prepare("SELECT version FROM conversations LIMIT 0")
+            .is_err()
+        {
+            conn.execute_batch(
+                "ALTER TABLE conversations ADD COLUMN version INTEGER NOT NULL DEFAULT 1",
+            )?;
+        }
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // Statements wait for the file, so they run on the blocking thread pool
+    async fn run<T, F>(&self, work: F) -> Result<T, LocalError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, LocalError> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            // A statement that panicked has not committed anything
+            let conn = conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            work(&conn)
+        })
+        .await
+        .map_err(|err| {
+            info!("SQLite task failed: {}", err);
+            LocalError::DbError
+        })?
+    }
+}
+
+// Stored time, written by `date_key`
+struct DateKey(chrono::DateTime<chrono::Utc>);
+
+impl FromSql for DateKey {
+    fn column_result(value: ValueRef<'_>) -> Result<Self, FromSqlError> {
+        let text = value.as_str()?;
+        parse_date_key(text)
+            .map(DateKey)
+            .ok_or_else(|| FromSqlError::Other(format!("bad time {}", text).into()))
+    }
+}
+
+fn conversation_from_row(row: &Row) -> rusqlite::Result<Conversation> {
+    Ok(Conversation {
+        id: row.get(0)?,
+        hmac: row.get(1)?,
+        contents: row.get(2)?,
+        public: row.get(3)?,
+        research: row.get(4)?,
+        deleted: row.get(5)?,
+        user_id: row.get(6)?,
+        folder_id: row.get(7)?,
+        created_at: row.get::<_, DateKey>(8)?.0,
+        title: row.get(9)?,
+        model: row.get(10)?,
+        openaiid: row.get(11)?,
+        updated_at: row.get::<_, DateKey>(12)?.0,
+        length: row.get(13)?,
+        schema_version: row.get(14)?,
+        version: row.get(15)?,
+    })
+}
+
+// Summary followed by the sort key as text
+fn summary_from_row(row: &Row) -> rusqlite::Result<(ConversationSummary, String)> {
+    Ok((
+        ConversationSummary {
+            id: row.get(0)?,
+            hmac: row.get(1)?,
+            title: row.get(2)?,
+            model: row.get(3)?,
+            openaiid: row.get(4)?,
+            created_at: row.get::<_, DateKey>(5)?.0,
+            length: row.get(6)?,
+            public: row.get(7)?,
+            research: row.get(8)?,
+            deleted: row.get(9)?,
+            folder_id: row.get(10)?,
+        },
+        row.get(11)?,
+    ))
+}
+
+// Expression to sort by, its value is what goes into the cursor
+fn sort_sql(sort: SortKey) -> &'static str {
+    match sort {
+        SortKey::Date => "created_at",
+        SortKey::Title => "fold_case(title)",
+        SortKey::Model => "model",
+    }
+}
+
+fn find(conn: &Connection, id: &str, deleted: bool) -> Result<Option<Conversation>, LocalError> {
+    Ok(conn
+        .query_row(
+            &format!(
+                "SELECT {} FROM conversations WHERE id = ? AND deleted = ?",
+                CONVERSATION_COLUMNS
+            ),
+            params![id, deleted],
+            conversation_from_row,
+        )
+        .optional()?)
+}
+
+fn list(
+    conn: &Connection,
+    user_id: &str,
+    filter: &ConversationFilter,
+    cursor: Option<&PageCursor>,
+) -> Result<ConversationPage, LocalError> {
+    let sort = filter.sort.unwrap_or(SortKey::Date);
+    let sort_expr = sort_sql(sort);
+    let page_size = page_size(filter);
+    let mut sql = format!(
+        "SELECT {}, {} FROM conversations WHERE user_id = ?",
+        SUMMARY_COLUMNS, sort_expr
+    );
+    let mut values: Vec<Box<dyn ToSql>> = vec![Box::new(user_id.to_string())];
+    if filter.tag.is_some() {
+        // No tags outside Postgres, so no conversation has the tag
+        sql.push_str(" AND 0");
+    }
+    if let Some(folder) = &filter.folder {
+        sql.push_str(" AND folder_id = ?");
+        values.push(Box::new(folder.clone()));
+    }
+    if let Some(model) = &filter.model {
+        sql.push_str(" AND model = ?");
+        values.push(Box::new(model.clone()));
+    }
+    if let Some(title) = &filter.title {
+        sql.push_str(" AND fold_case(title) LIKE ? ESCAPE '\\'");
+        values.push(Box::new(format!(
+            "%{}%",
+            like_escape(&title.to_lowercase())
+        )));
+    }
+    if let Some(since) = &filter.since {
+        sql.push_str(" AND created_at >= ?");
+        values.push(Box::new(date_key(since)));
+    }
+    if let Some(until) = &filter.until {
+        sql.push_str(" AND created_at <= ?");
+        values.push(Box::new(date_key(until)));
+    }
+    for (column, value) in [
+        ("deleted", filter.deleted),
+        ("public", filter.public),
+        ("research", filter.research),
+    ] {
+        if let Some(value) = value {
+            sql.push_str(&format!(" AND {} = ?", column));
+            values.push(Box::new(value));
+        }
+    }
+    // Sort by chosen expression with id to break ties, keyset pagination on both
+    let (direction, comparison) = match filter.order {
+        SortOrder::Asc => ("ASC", ">"),
+        SortOrder::Desc => ("DESC", "<"),
+    };
+    if let Some(cursor) = cursor {
+        sql.push_str(&format!(
+            " AND ({expr} {cmp} ? OR ({expr} = ? AND id {cmp} ?))",
+            expr = sort_expr,
+            cmp = comparison
+        ));
+        values.push(Box::new(cursor.key.clone()));
+        values.push(Box::new(cursor.key.clone()));
+        values.push(Box::new(cursor.id.clone()));
+    }
+    sql.push_str(&format!(
+        " ORDER BY {} {}, id {} LIMIT ?",
+        sort_expr, direction, direction
+    ));
+    values.push(Box::new(page_size as i64 + 1));
+    let mut statement = conn.prepare(&sql)?;
+    let mut rows = statement
+        .query_map(
+            rusqlite::params_from_iter(values.iter().map(|value| value.as_ref())),
+            summary_from_row,
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+    let next_cursor = next_cursor(&mut rows, sort, page_size);
+    Ok(ConversationPage {
+        conversations: rows
+            .into_iter()
+            .map(|(conv, _)| conv.into_info(Vec::new()))
+            .collect(),
+        next_cursor,
+    })
+}
+
+fn count(conn: &Connection, user_id: &str) -> Result<i64, LocalError> {
+    Ok(conn.query_row(
+        "SELECT count(*) FROM conversations WHERE user_id = ? AND deleted = 0",
+        params![user_id],
+        |row| row.get(0),
+    )?)
+}
+
+fn exists(conn: &Connection, user_id: &str, hmac: &str) -> Result<Option<String>, LocalError> {
+    Ok(conn
+        .query_row(
+            "SELECT id FROM conversations WHERE user_id = ? AND hmac = ? AND deleted = 0 \
+             LIMIT 1",
+            params![user_id, hmac],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+fn insert(conn: &Connection, conv: &Conversation) -> Result<(), LocalError> {
+    conn.execute(
+        &format!(
+            "INSERT INTO conversations ({}) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            CONVERSATION_COLUMNS
+        ),
+        params![
+            conv.id,
+            conv.hmac,
+            conv.contents,
+            conv.public,
+            conv.research,
+            conv.deleted,
+            conv.user_id,
+            conv.folder_id,
+            date_key(&conv.created_at),
+            conv.title,
+            conv.model,
+            conv.openaiid,
+            date_key(&conv.updated_at),
+            conv.length,
+            conv.schema_version,
+            conv.version,
+        ],
+    )?;
+    Ok(())
+}
+
+fn create(
+    conn: &Connection,
+    conv: &Conversation,
+    max_count: Option<i64>,
+) -> Result<String, LocalError> {
+    // IMMEDIATE takes the write lock first, other processes using the file wait
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    if let Some(existing) = exists(&tx, &conv.user_id, &conv.hmac)? {
+        return Ok(existing);
+    }
+    if let Some(max_count) = max_count {
+        if count(&tx, &conv.user_id)? >= max_count {
+            return Err(LocalError::MaxCount);
+        }
+    }
+    insert(&tx, conv)?;
+    tx.commit()?;
+    Ok(conv.id.clone())
+}
+
+fn update(
+    conn: &Connection,
+    id: &str,
+    changes: &ConversationChanges,
+    version: i32,
+) -> Result<i32, LocalError> {
+    let updated = conn.execute(
+        "UPDATE conversations SET contents = ?, schema_version = ?, title = ?, model = ?, \
+         openaiid = ?, length = ?, public = ?, research = ?, hmac = ?, updated_at = ?, \
+         version = version + 1 WHERE id = ? AND version = ?",
+        params![
+            changes.contents,
+            changes.schema_version,
+            changes.title,
+            changes.model,
+            changes.openaiid,
+            changes.length,
+            changes.public,
+            changes.research,
+            changes.hmac,
+            date_key(&chrono::Utc::now()),
+            id,
+            version,
+        ],
+    )?;
+    if updated == 0 {
+        // Nothing matched, find out which part didn't
+        let found = conn
+            .query_row("SELECT 1 FROM conversations WHERE id = ?", [id], |_| Ok(()))
+            .optional()?;
+        return match found {
+            Some(()) => Err(LocalError::VersionMismatch),
+            None => Err(LocalError::NotFound),
+        };
+    }
+    Ok(version + 1)
+}
+
+fn set_deleted(conn: &Connection, id: &str, deleted: bool) -> Result<(), LocalError> {
+    let updated = conn.execute(
+        "UPDATE conversations SET deleted = ?, updated_at = ?, version = version + 1 \
+         WHERE id = ?",
+        params![deleted, date_key(&chrono::Utc::now()), id],
+    )?;
+    if updated == 0 {
+        return Err(LocalError::NotFound);
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl ConversationStore for SqliteStore {
+    async fn find(&self, id: &str, deleted: bool) -> Result<Option<Conversation>, LocalError> {
+        let id = id.to_string();
+        self.run(move |conn| find(conn, &id, deleted)).await
+    }
+
+    async fn list(
+        &self,
+        user_id: &str,
+        filter: &ConversationFilter,
+        cursor: Option<&PageCursor>,
+    ) -> Result<ConversationPage, LocalError> {
+        let user_id = user_id.to_string();
+        let filter = filter.clone();
+        let cursor = cursor.cloned();
+        self.run(move |conn| list(conn, &user_id, &filter, cursor.as_ref()))
+            .await
+    }
+
+    async fn count(&self, user_id: &str) -> Result<i64, LocalError> {
+        let user_id = user_id.to_string();
+        self.run(move |conn| count(conn, &user_id)).await
+    }
+
+    async fn exists(&self, user_id: &str, hmac: &str) -> Result<Option<String>, LocalError> {
+        let user_id = user_id.to_string();
+        let hmac = hmac.to_string();
+        self.run(move |conn| exists(conn, &user_id, &hmac)).await
+    }
+
+    async fn insert(&self, conversation: &Conversation) -> Result<(), LocalError> {
+        let conversation = conversation.clone();
+        self.run(move |conn| insert(conn, &conversation)).await
+    }
+
+    async fn create(
+        &self,
+        conversation: &Conversation,
+        max_count: Option<i64>,
+    ) -> Result<String, LocalError> {
+        let conversation = conversation.clone();
+        self.run(move |conn| create(conn, &conversation, max_count))
+            .await
+    }
+
+    async fn update(
+        &self,
+        id: &str,
+        changes: &ConversationChanges,
+        version: i32,
+    ) -> Result<i32, LocalError> {
+        let id = id.to_string();
+        let changes = changes.clone();
+        self.run(move |conn| update(conn, &id, &changes, version))
+            .await
+    }
+
+    async fn set_deleted(&self, id: &str, deleted: bool) -> Result<(), LocalError> {
+        let id = id.to_string();
+        self.run(move |conn| set_deleted(conn, &id, deleted)).await
+    }
+}
+
+impl From<rusqlite::Error> for LocalError {
+    fn from(err: rusqlite::Error) -> LocalError {
+        use rusqlite::ErrorCode;
+        match err {
+            rusqlite::Error::QueryReturnedNoRows => LocalError::NotFound,
+            rusqlite::Error::SqliteFailure(failure, _)
+                if matches!(
+                    failure.code,
+                    ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked
+                ) =>
+            {
+                LocalError::DbContention
+            }
+            rusqlite::Error::SqliteFailure(failure, _)
+                if failure.code == ErrorCode::ConstraintViolation =>
+            {
+                LocalError::AlreadyExists
+            }
+            err => {
+                info!("SQLite problem: {}", err);
+                LocalError::DbError
+            }
+        }
+    }
+}
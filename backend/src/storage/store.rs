@@ -0,0 +1,89 @@
+// Where conversations are kept
+//
+// Conversation handlers go through a `ConversationStore` instead of running
+// queries themselves, so the same API can keep conversations in
+//   - Postgres (PgStore), the normal deployment
+//   - SQLite (SqliteStore), for small self-hosted deployments
+//   - memory (MemoryStore), for tests
+// CONVERSATION_STORE picks one: "postgres" (the default), "sqlite" (with the
+// file given by SQLITE_PATH) or "memory", see config.rs.
+//
+// Tags, folders, local accounts and API tokens are only kept in Postgres. With
+// the other stores conversations have no tags and those endpoints can't be used.
+//
+// Methods are async, PgStore uses the async pool and SqliteStore runs its
+// statements on the blocking thread pool.
+
+use super::LocalError;
+use crate::models::{
+    Conversation, ConversationChanges, ConversationFilter, ConversationPage, PageCursor,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+#[async_trait]
+pub trait ConversationStore: Send + Sync {
+    // Conversation with this id, if it exists and is (not) deleted as asked
+    async fn find(&self, id: &str, deleted: bool) -> Result<Option<Conversation>, LocalError>;
+
+    // One page of the conversations of a user, newest first unless asked otherwise
+    async fn list(
+        &self,
+        user_id: &str,
+        filter: &ConversationFilter,
+        cursor: Option<&PageCursor>,
+    ) -> Result<ConversationPage, LocalError>;
+
+    // Number of conversations of a user that are not deleted
+    async fn count(&self, user_id: &str) -> Result<i64, LocalError>;
+
+    // Id of the conversation of a user (not deleted) with this digest
+    async fn exists(&self, user_id: &str, hmac: &str) -> Result<Option<String>, LocalError>;
+
+    async fn insert(&self, conversation: &Conversation) -> Result<(), LocalError>;
+
+    // Insert unless the user already has a conversation (not deleted) with the
+    // same digest, returns the id of the one that is kept
+    // With `max_count` MaxCount when the user already has that many. The checks
+    // and the insert are one step, parallel creates can't get around them.
+    async fn create(
+        &self,
+        conversation: &Conversation,
+        max_count: Option<i64>,
+    ) -> Result<String, LocalError>;
+
+    // Replace contents and metadata if the conversation is still at `version`,
+    // returns the new version
+    // NotFound when there is no such conversation, VersionMismatch when it was
+    // changed in the meantime. This and `set_deleted` also set updated_at and
+    // bump the version.
+    async fn update(
+        &self,
+        id: &str,
+        changes: &ConversationChanges,
+        version: i32,
+    ) -> Result<i32, LocalError>;
+
+    // Soft delete (or undelete), NotFound when there is no such conversation
+    async fn set_deleted(&self, id: &str, deleted: bool) -> Result<(), LocalError>;
+}
+
+// Which store to use, with the database URL or file path it needs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreConfig {
+    Postgres(String),
+    Sqlite(String),
+    Memory,
+}
+
+// Creation time as text that sorts like the time itself
+// Used by the stores that don't have a timestamp type, for sorting and cursors.
+pub(crate) fn date_key(time: &DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%S%.6fZ").to_string()
+}
+
+pub(crate) fn parse_date_key(key: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(key)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
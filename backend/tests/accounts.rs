@@ -0,0 +1,311 @@
+// Local accounts: registering, logging in with a password or a mailed link
+//
+// These run against a real Postgres database given by DATABASE_URL, see
+// common::database_url. Mail is kept by common::TestMailer, each test uses
+// addresses of its own.
+
+mod common;
+
+use common::*;
+
+fn address() -> String {
+    format!("{}@example.com", uuid::Uuid::new_v4().simple())
+}
+
+// Status of POST /local/login, with the session cookie when it worked
+async fn login<S, B>(app: &S, email: &str, password: &str) -> (StatusCode, Option<Credentials>)
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+{
+    let resp = test::call_service(
+        app,
+        test::TestRequest::post()
+            .uri("/local/login")
+            .set_json(serde_json::json!({"email": email, "password": password}))
+            .to_request(),
+    )
+    .await;
+    let cookie = resp
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == "id")
+        .map(|cookie| Credentials::Cookie(cookie.into_owned()));
+    (resp.status(), cookie)
+}
+
+async fn register<S, B>(app: &S, email: &str, password: &str, expected: StatusCode) -> String
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: actix_web::body::MessageBody,
+{
+    let body = call(
+        app,
+        &Credentials::Nothing,
+        Method::POST,
+        "/local/register",
+        Some(serde_json::json!({"email": email, "password": password})),
+        expected,
+    )
+    .await;
+    body["code"].as_str().unwrap_or_default().to_string()
+}
+
+#[actix_web::test]
+async fn registering_needs_the_mailed_link() {
+    let Some(pool) = test_pool() else { return };
+    let app = test::init_service(app_with(auth::AuthProviders::new(vec![]), pool)).await;
+    let email = address();
+
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::post()
+            .uri("/local/register")
+            .set_json(serde_json::json!({"email": email, "password": "correct horse"}))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    assert!(resp.response().cookies().next().is_none());
+    // No account until the link is opened
+    assert_eq!(
+        login(&app, &email, "correct horse").await.0,
+        StatusCode::UNAUTHORIZED
+    );
+
+    let link = magic_link_path(&email).expect("registration mail");
+    // Opening it only shows a page, so link previews log in nobody
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&link).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.response().cookies().next().is_none());
+    assert_eq!(
+        login(&app, &email, "correct horse").await.0,
+        StatusCode::UNAUTHORIZED
+    );
+    let session = Credentials::Cookie(open_magic_link(&app, &link).await);
+    call(
+        &app,
+        &session,
+        Method::POST,
+        "/authenticated",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    let (status, cookie) = login(&app, &email, "correct horse").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(cookie.is_some());
+    let (status, cookie) = login(&app, &email, "wrong horse").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(cookie.is_none());
+
+    // Links work once
+    let resp = call(
+        &app,
+        &Credentials::Nothing,
+        Method::POST,
+        &link,
+        None,
+        StatusCode::UNAUTHORIZED,
+    )
+    .await;
+    assert_eq!(resp["code"], "magic_link_invalid");
+
+    // Registering again answers the same, the owner gets a login link
+    register(&app, &email, "other horse", StatusCode::ACCEPTED).await;
+    let again = magic_link_path(&email).expect("login mail");
+    assert_ne!(again, link);
+    open_magic_link(&app, &again).await;
+    assert_eq!(
+        login(&app, &email, "other horse").await.0,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(login(&app, &email, "correct horse").await.0, StatusCode::OK);
+
+    assert_eq!(
+        register(
+            &app,
+            "nobody",
+            "correct horse",
+            StatusCode::UNPROCESSABLE_ENTITY
+        )
+        .await,
+        "email_invalid"
+    );
+    assert_eq!(
+        register(&app, &address(), "short", StatusCode::UNPROCESSABLE_ENTITY).await,
+        "password_too_short"
+    );
+}
+
+#[actix_web::test]
+async fn passwords_are_only_added_by_the_owner() {
+    let Some(pool) = test_pool() else { return };
+    let app = test::init_service(app_with(
+        auth::AuthProviders::new(vec![Box::new(TestProvider)]),
+        pool,
+    ))
+    .await;
+    let email = address();
+    call(
+        &app,
+        &Credentials::Nothing,
+        Method::POST,
+        "/local/magic-link",
+        Some(serde_json::json!({"email": email})),
+        StatusCode::OK,
+    )
+    .await;
+    let victim = Credentials::Cookie(
+        open_magic_link(&app, &magic_link_path(&email).expect("login mail")).await,
+    );
+    let id = upload(&app, &victim).await;
+
+    // Someone else registering the address gets neither a password nor a session,
+    // not even when the owner opens the link it mails
+    register(&app, &email, "attacker horse", StatusCode::ACCEPTED).await;
+    open_magic_link(&app, &magic_link_path(&email).expect("login mail")).await;
+    assert_eq!(
+        login(&app, &email, "attacker horse").await.0,
+        StatusCode::UNAUTHORIZED
+    );
+
+    // The owner adds one while logged in, once
+    let password = serde_json::json!({"password": "owner horse"});
+    call(
+        &app,
+        &victim,
+        Method::POST,
+        "/local/password",
+        Some(password.clone()),
+        StatusCode::OK,
+    )
+    .await;
+    let resp = call(
+        &app,
+        &victim,
+        Method::POST,
+        "/local/password",
+        Some(serde_json::json!({"password": "another horse"})),
+        StatusCode::CONFLICT,
+    )
+    .await;
+    assert_eq!(resp["code"], "already_exists");
+    let (status, Some(session)) = login(&app, &email, "owner horse").await else {
+        panic!("no session cookie");
+    };
+    assert_eq!(status, StatusCode::OK);
+    call(
+        &app,
+        &session,
+        Method::DELETE,
+        &format!("/conversation/{}", id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+
+    // Not with a bearer token
+    let resp = call(
+        &app,
+        &Credentials::Bearer("test-access-carol".to_string()),
+        Method::POST,
+        "/local/password",
+        Some(password),
+        StatusCode::FORBIDDEN,
+    )
+    .await;
+    assert_eq!(resp["code"], "session_required");
+}
+
+#[actix_web::test]
+async fn registration_links_never_replace_a_password() {
+    let Some(pool) = test_pool() else { return };
+    let app = test::init_service(app_with(auth::AuthProviders::new(vec![]), pool)).await;
+    let email = address();
+    register(&app, &email, "first horse", StatusCode::ACCEPTED).await;
+    let first = magic_link_path(&email).expect("registration mail");
+    register(&app, &email, "second horse", StatusCode::ACCEPTED).await;
+    let second = magic_link_path(&email).expect("registration mail");
+    assert_ne!(first, second);
+
+    open_magic_link(&app, &second).await;
+    open_magic_link(&app, &first).await;
+    assert_eq!(login(&app, &email, "second horse").await.0, StatusCode::OK);
+    assert_eq!(
+        login(&app, &email, "first horse").await.0,
+        StatusCode::UNAUTHORIZED
+    );
+
+    // An account made by login link in the meantime gets the password
+    let email = address();
+    register(&app, &email, "pending horse", StatusCode::ACCEPTED).await;
+    let pending = magic_link_path(&email).expect("registration mail");
+    call(
+        &app,
+        &Credentials::Nothing,
+        Method::POST,
+        "/local/magic-link",
+        Some(serde_json::json!({"email": email})),
+        StatusCode::OK,
+    )
+    .await;
+    open_magic_link(&app, &magic_link_path(&email).expect("login mail")).await;
+    assert_eq!(
+        login(&app, &email, "pending horse").await.0,
+        StatusCode::UNAUTHORIZED
+    );
+    open_magic_link(&app, &pending).await;
+    assert_eq!(login(&app, &email, "pending horse").await.0, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn links_are_not_mailed_again_right_away() {
+    let Some(pool) = test_pool() else { return };
+    let app = test::init_service(app_with_state(
+        pool,
+        AppState {
+            magic_link_cooldown_secs: 60,
+            ..test_state(auth::AuthProviders::new(vec![]))
+        },
+    ))
+    .await;
+    let email = address();
+    register(&app, &email, "correct horse", StatusCode::ACCEPTED).await;
+    let link = magic_link_path(&email).expect("registration mail");
+
+    // Same answers, but no more mail
+    register(&app, &email, "other horse", StatusCode::ACCEPTED).await;
+    call(
+        &app,
+        &Credentials::Nothing,
+        Method::POST,
+        "/local/magic-link",
+        Some(serde_json::json!({"email": email})),
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(magic_link_path(&email), Some(link.clone()));
+
+    // The first link still works, other addresses still get theirs
+    open_magic_link(&app, &link).await;
+    assert_eq!(login(&app, &email, "correct horse").await.0, StatusCode::OK);
+    let other = address();
+    call(
+        &app,
+        &Credentials::Nothing,
+        Method::POST,
+        "/local/magic-link",
+        Some(serde_json::json!({"email": other})),
+        StatusCode::OK,
+    )
+    .await;
+    assert!(magic_link_path(&other).is_some());
+}
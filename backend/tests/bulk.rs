@@ -0,0 +1,154 @@
+// Bulk operations report an outcome for every conversation they name
+//
+// These run against a real Postgres database given by DATABASE_URL, see
+// common::database_url.
+
+mod common;
+
+use common::*;
+
+fn statuses(results: &serde_json::Value) -> Vec<(String, String)> {
+    results
+        .as_array()
+        .expect("bulk results")
+        .iter()
+        .map(|result| {
+            (
+                result["id"].as_str().unwrap_or_default().to_string(),
+                result["status"].as_str().unwrap_or_default().to_string(),
+            )
+        })
+        .collect()
+}
+
+#[actix_web::test]
+async fn bulk_results_are_per_conversation() {
+    let Some(pool) = test_pool() else { return };
+    let app = test::init_service(app_with(
+        auth::AuthProviders::new(vec![Box::new(TestProvider)]),
+        pool,
+    ))
+    .await;
+    let alice = Credentials::Cookie(session_cookie(&app).await);
+    let bob = Credentials::Cookie(session_cookie(&app).await);
+    let first = upload(&app, &alice).await;
+    let second = upload(&app, &alice).await;
+    let bobs = upload(&app, &bob).await;
+    let missing = uuid::Uuid::new_v4().simple().to_string();
+
+    let results = call(
+        &app,
+        &alice,
+        Method::POST,
+        "/conversations/bulk",
+        Some(serde_json::json!({
+            "ids": [first, bobs, missing, second],
+            "operation": {"op": "delete"},
+        })),
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(
+        statuses(&results),
+        vec![
+            (first.clone(), "ok".to_string()),
+            (bobs.clone(), "forbidden".to_string()),
+            (missing.clone(), "not_found".to_string()),
+            (second.clone(), "ok".to_string()),
+        ]
+    );
+    // Bob's conversation is untouched
+    let count = call(
+        &app,
+        &bob,
+        Method::GET,
+        "/conversation/count",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(count, 1);
+
+    // Undelete only finds deleted conversations
+    let results = call(
+        &app,
+        &alice,
+        Method::POST,
+        "/conversations/bulk",
+        Some(serde_json::json!({"ids": [first], "operation": {"op": "undelete"}})),
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(statuses(&results), vec![(first.clone(), "ok".to_string())]);
+    let results = call(
+        &app,
+        &alice,
+        Method::POST,
+        "/conversations/bulk",
+        Some(serde_json::json!({"ids": [first], "operation": {"op": "undelete"}})),
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(
+        statuses(&results),
+        vec![(first.clone(), "not_found".to_string())]
+    );
+}
+
+#[actix_web::test]
+async fn bulk_add_tag_uses_tag_names() {
+    let Some(pool) = test_pool() else { return };
+    let app = test::init_service(app_with(
+        auth::AuthProviders::new(vec![Box::new(TestProvider)]),
+        pool,
+    ))
+    .await;
+    let alice = Credentials::Cookie(session_cookie(&app).await);
+    let first = upload(&app, &alice).await;
+    let second = upload(&app, &alice).await;
+
+    // Names are trimmed like the ones of tags made directly, so both add one tag
+    for (id, name) in [(&first, " work "), (&second, "work")] {
+        let results = call(
+            &app,
+            &alice,
+            Method::POST,
+            "/conversations/bulk",
+            Some(serde_json::json!({"ids": [id], "operation": {"op": "add_tag", "value": name}})),
+            StatusCode::OK,
+        )
+        .await;
+        assert_eq!(statuses(&results), vec![(id.clone(), "ok".to_string())]);
+    }
+    let tags = call(&app, &alice, Method::GET, "/tags", None, StatusCode::OK).await;
+    let tags = tags.as_array().expect("tags");
+    assert_eq!(tags.len(), 1);
+    assert_eq!(tags[0]["name"], "work");
+    let page = call(
+        &app,
+        &alice,
+        Method::POST,
+        &format!("/conversations?tag={}", tags[0]["id"].as_str().unwrap()),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(page["conversations"].as_array().map(Vec::len), Some(2));
+
+    for name in ["   ", &"x".repeat(101)] {
+        let resp = call(
+            &app,
+            &alice,
+            Method::POST,
+            "/conversations/bulk",
+            Some(
+                serde_json::json!({"ids": [first], "operation": {"op": "add_tag", "value": name}}),
+            ),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        )
+        .await;
+        assert_eq!(resp["code"], "tag_name_invalid");
+    }
+    let tags = call(&app, &alice, Method::GET, "/tags", None, StatusCode::OK).await;
+    assert_eq!(tags.as_array().map(Vec::len), Some(1));
+}
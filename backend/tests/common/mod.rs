@@ -0,0 +1,439 @@
+// Helpers shared by the backend tests
+//
+// endpoints.rs goes through every endpoint with each way of authenticating (needs
+// a database), oidc.rs checks identity and access tokens against a local mock
+// of Google (no database or network needed), db.rs breaks the database on
+// purpose (in a throwaway database of its own), migrations.rs runs the
+// migrations over old rows (likewise), pool.rs runs out of connections on
+// purpose.
+
+#![allow(dead_code, unused_imports)]
+
+pub use actix_web::cookie::Cookie;
+pub use actix_web::http::{Method, StatusCode};
+pub use actix_web::{test, web, App, HttpResponse, HttpServer};
+pub use async_trait::async_trait;
+pub use shareprompts_backend_api::api::page_cache::PageCache;
+pub use shareprompts_backend_api::storage::{
+    self, ConversationStore, DbConnection, DbConnectionManager, DbPool, LocalError, MemoryStore,
+    PgStore, PoolConfig,
+};
+pub use shareprompts_backend_api::{auth, mailer, render, AppState};
+pub use std::sync::Arc;
+
+use actix_web::body::MessageBody;
+use actix_web::cookie::Key;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use diesel::connection::SimpleConnection;
+use diesel::{Connection, PgConnection};
+use diesel_migrations::MigrationHarness;
+
+// The app as main() sets it up, with the given authentication providers
+pub fn app_with(
+    providers: auth::AuthProviders,
+    pool: DbPool,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let store = Arc::new(PgStore::new(pool.clone()));
+    app_with_store(providers, Some(pool), store)
+}
+
+// Same with conversations kept somewhere else, `pool` is needed for the rest
+pub fn app_with_store(
+    providers: auth::AuthProviders,
+    pool: Option<DbPool>,
+    store: Arc<dyn ConversationStore>,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    shareprompts_backend_api::app(
+        pool,
+        store,
+        web::Data::new(test_state(providers)),
+        Key::derive_from(&[7u8; 64]),
+    )
+}
+
+// Same with state of its own, made from `test_state`
+pub fn app_with_state(
+    pool: DbPool,
+    state: AppState,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let store = Arc::new(PgStore::new(pool.clone()));
+    shareprompts_backend_api::app(
+        Some(pool),
+        store,
+        web::Data::new(state),
+        Key::derive_from(&[7u8; 64]),
+    )
+}
+
+// State of the apps made here
+// Links can be mailed to an address again right away, tests open several.
+pub fn test_state(providers: auth::AuthProviders) -> AppState {
+    AppState {
+        auth: providers,
+        mailer: Box::new(TestMailer),
+        public_url: "http://localhost".to_string(),
+        max_free_user_count: 1000,
+        session_ttl_days: 30,
+        magic_link_cooldown_secs: 0,
+        pages: render::PageRenderer::embedded(),
+        page_cache: PageCache::new(100),
+    }
+}
+
+// Mail sent by any app made here, as (to, body)
+static OUTBOX: std::sync::Mutex<Vec<(String, String)>> = std::sync::Mutex::new(Vec::new());
+
+pub struct TestMailer;
+
+impl mailer::Mailer for TestMailer {
+    fn send(&self, to: &str, _subject: &str, body: &str) -> Result<(), mailer::MailError> {
+        OUTBOX
+            .lock()
+            .unwrap()
+            .push((to.to_string(), body.to_string()));
+        Ok(())
+    }
+}
+
+// Path of the magic link in the last mail sent to address
+// Tests use addresses of their own, so they don't see each other's mail.
+pub fn magic_link_path(address: &str) -> Option<String> {
+    let outbox = OUTBOX.lock().unwrap();
+    let (_, body) = outbox.iter().rev().find(|(to, _)| to == address)?;
+    let start = body.find("http://localhost/api/local/magic-link/")?;
+    let link = body[start..].split_whitespace().next()?;
+    Some(link.trim_start_matches("http://localhost/api").to_string())
+}
+
+// URL of the test database, None when the test should be skipped
+// With CI set (CI services set it) a missing DATABASE_URL fails the test
+// instead, so a run without Postgres can't pass for one that tested everything.
+pub fn database_url() -> Option<String> {
+    dotenvy::dotenv().ok();
+    match std::env::var("DATABASE_URL") {
+        Ok(url) => Some(url),
+        Err(_) if std::env::var_os("CI").is_some() => {
+            panic!("DATABASE_URL must be set to run the database tests in CI")
+        }
+        Err(_) => {
+            eprintln!("DATABASE_URL not set, skipping database test");
+            None
+        }
+    }
+}
+
+// Pool on the test database with migrations applied
+pub fn test_pool_with(config: &PoolConfig) -> Option<DbPool> {
+    let database_url = database_url()?;
+    storage::run_migrations(&database_url).expect("could not run pending migrations");
+    Some(storage::initialize_db_pool(&database_url, config))
+}
+
+pub fn test_pool() -> Option<DbPool> {
+    test_pool_with(&PoolConfig::default())
+}
+
+// Database that exists for the duration of one test
+// Made next to the one in DATABASE_URL (the user needs CREATEDB) and dropped
+// at the end.
+pub struct DisposableDb {
+    admin_url: String,
+    name: String,
+    pub url: String,
+    pub pool: DbPool,
+}
+
+impl DisposableDb {
+    // With every migration applied
+    pub fn create() -> Option<DisposableDb> {
+        let db = DisposableDb::empty()?;
+        storage::run_migrations(&db.url).expect("could not run pending migrations");
+        Some(db)
+    }
+
+    // Without any migration applied, not even diesel's setup
+    pub fn empty() -> Option<DisposableDb> {
+        let admin_url = database_url()?;
+        let name = format!("sp_test_{}", uuid::Uuid::new_v4().simple());
+        let mut admin = PgConnection::establish(&admin_url).expect("connect to DATABASE_URL");
+        admin
+            .batch_execute(&format!("CREATE DATABASE {}", name))
+            .expect("create disposable database");
+        // Same server and user, other database name
+        let (base, query) = admin_url
+            .split_once('?')
+            .map(|(base, query)| (base, format!("?{}", query)))
+            .unwrap_or((&admin_url, String::new()));
+        let (server, _) = base
+            .rsplit_once('/')
+            .expect("database name in DATABASE_URL");
+        let url = format!("{}/{}{}", server, name, query);
+        let pool = storage::initialize_db_pool(
+            &url,
+            &PoolConfig {
+                size: 4,
+                ..Default::default()
+            },
+        );
+        Some(DisposableDb {
+            admin_url,
+            name,
+            url,
+            pool,
+        })
+    }
+
+    pub fn connect(&self) -> PgConnection {
+        PgConnection::establish(&self.url).expect("connect to disposable database")
+    }
+
+    // Apply pending migrations up to and including the named one
+    // (its directory name, like "2023-06-12-120000_created_at")
+    pub fn migrate_through(&self, migration: &str) {
+        let version: String = migration
+            .split('_')
+            .next()
+            .unwrap_or_default()
+            .replace('-', "");
+        let mut conn = self.connect();
+        loop {
+            let applied = conn
+                .run_next_migration(storage::MIGRATIONS)
+                .expect("run next migration");
+            if applied.to_string() == version {
+                return;
+            }
+        }
+    }
+
+    // Like a database restart: every open connection is gone
+    pub fn kill_connections(&self) {
+        let mut admin = PgConnection::establish(&self.admin_url).expect("connect to DATABASE_URL");
+        admin
+            .batch_execute(&format!(
+                "SELECT pg_terminate_backend(pid) FROM pg_stat_activity WHERE datname = '{}'",
+                self.name
+            ))
+            .expect("terminate connections");
+    }
+}
+
+impl Drop for DisposableDb {
+    fn drop(&mut self) {
+        if let Ok(mut admin) = PgConnection::establish(&self.admin_url) {
+            let _ = admin.batch_execute(&format!("DROP DATABASE {} WITH (FORCE)", self.name));
+        }
+    }
+}
+
+// Accepts access tokens "test-access-<user>" as belonging to <user>
+pub struct TestProvider;
+
+#[async_trait(?Send)]
+impl auth::AuthProvider for TestProvider {
+    fn name(&self) -> &str {
+        "test"
+    }
+
+    fn handles_issuer(&self, _issuer: &str) -> bool {
+        false
+    }
+
+    async fn validate_identity_token(&self, _token: &str) -> Result<String, auth::TokenError> {
+        Err(auth::TokenError::Invalid)
+    }
+
+    async fn validate_access_token(
+        &self,
+        token: &str,
+    ) -> Result<auth::AccessToken, auth::TokenError> {
+        token
+            .strip_prefix("test-access-")
+            .map(|user| auth::AccessToken {
+                user_id: user.to_string(),
+                expires_in: Some(3600),
+            })
+            .ok_or(auth::TokenError::Invalid)
+    }
+}
+
+#[derive(Clone)]
+pub enum Credentials {
+    Cookie(Cookie<'static>),
+    Bearer(String),
+    Nothing,
+}
+
+pub fn request(method: Method, path: &str, creds: &Credentials) -> test::TestRequest {
+    let req = test::TestRequest::default().method(method).uri(path);
+    match creds {
+        Credentials::Cookie(cookie) => req.cookie(cookie.clone()),
+        Credentials::Bearer(token) => {
+            req.insert_header(("Authorization", format!("Bearer {}", token)))
+        }
+        Credentials::Nothing => req,
+    }
+}
+
+// Call endpoint, check the status and return the body as JSON (or null)
+pub async fn call<S, B>(
+    app: &S,
+    creds: &Credentials,
+    method: Method,
+    path: &str,
+    body: Option<serde_json::Value>,
+    expected: StatusCode,
+) -> serde_json::Value
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: actix_web::body::MessageBody,
+{
+    call_with_headers(app, creds, method, path, &[], body, expected).await
+}
+
+// Same with extra request headers
+pub async fn call_with_headers<S, B>(
+    app: &S,
+    creds: &Credentials,
+    method: Method,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<serde_json::Value>,
+    expected: StatusCode,
+) -> serde_json::Value
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: actix_web::body::MessageBody,
+{
+    let mut req = request(method.clone(), path, creds);
+    for &header in headers {
+        req = req.insert_header(header);
+    }
+    if let Some(body) = body {
+        req = req.set_json(body);
+    }
+    let resp = test::call_service(app, req.to_request()).await;
+    assert_eq!(resp.status(), expected, "{} {}", method, path);
+    let bytes = test::read_body(resp).await;
+    serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null)
+}
+
+// Register local account, open the mailed link and return the session cookie
+pub async fn session_cookie<S, B>(app: &S) -> Cookie<'static>
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: actix_web::body::MessageBody,
+{
+    let email = format!("{}@example.com", uuid::Uuid::new_v4().simple());
+    let resp = test::call_service(
+        app,
+        test::TestRequest::post()
+            .uri("/local/register")
+            .set_json(serde_json::json!({"email": email, "password": "correct horse"}))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    open_magic_link(app, &magic_link_path(&email).expect("registration mail")).await
+}
+
+// Log in with magic link at path and return the session cookie
+// Like a person would: open the page, then press its button.
+pub async fn open_magic_link<S, B>(app: &S, path: &str) -> Cookie<'static>
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: actix_web::body::MessageBody,
+{
+    let resp = test::call_service(app, test::TestRequest::get().uri(path).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let page = String::from_utf8(test::read_body(resp).await.to_vec()).expect("confirm page");
+    assert!(
+        page.contains(&format!(
+            "<form method=\"post\" action=\"http://localhost/api{}\">",
+            path
+        )),
+        "{}",
+        page
+    );
+    let resp = test::call_service(app, test::TestRequest::post().uri(path).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    resp.response()
+        .cookies()
+        .find(|cookie| cookie.name() == "id")
+        .expect("session cookie")
+        .into_owned()
+}
+
+pub fn new_conversation() -> serde_json::Value {
+    serde_json::json!({
+        "openaiid": uuid::Uuid::new_v4().simple().to_string(),
+        "title": "Test conversation",
+        "contents": {"avatar": "", "dialog": [{"who": "human", "what": "Hello"}]},
+        "model": "test",
+        "public": false,
+        "research": false,
+        "paiduser": false,
+    })
+}
+
+// Upload a new conversation and return its id
+pub async fn upload<S, B>(app: &S, creds: &Credentials) -> String
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: actix_web::body::MessageBody,
+{
+    let id = call(
+        app,
+        creds,
+        Method::POST,
+        "/conversation/",
+        Some(new_conversation()),
+        StatusCode::CREATED,
+    )
+    .await;
+    id.as_str().expect("conversation id").to_string()
+}
@@ -0,0 +1,226 @@
+// Settings from the environment and the TOML file, and the checks on them
+//
+// The environment is a map here, nothing is read from the real one.
+
+use shareprompts_backend_api::config::Config;
+use shareprompts_backend_api::mailer::MailerKind;
+use shareprompts_backend_api::storage::{PoolConfig, StoreConfig};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+fn load(toml: Option<&str>, vars: &[(&str, &str)]) -> Result<Config, Vec<String>> {
+    let env: HashMap<String, String> = vars
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    Config::from_sources(toml, |name| env.get(name).cloned()).map_err(|err| err.0)
+}
+
+#[test]
+fn defaults_from_the_environment() {
+    let config = load(
+        None,
+        &[
+            ("SECRET", SECRET),
+            ("MAX_FREE_USER_COUNT", "100"),
+            ("DATABASE_URL", "postgres://localhost/sp"),
+        ],
+    )
+    .expect("valid configuration");
+    assert_eq!(config.bind.to_string(), "0.0.0.0:9090");
+    assert_eq!(config.public_url, "https://shareconversation.com");
+    assert_eq!(config.session_ttl_days, 30);
+    assert_eq!(config.max_free_user_count, 100);
+    assert_eq!(config.magic_link_cooldown_secs, 60);
+    assert_eq!(
+        config.store,
+        StoreConfig::Postgres("postgres://localhost/sp".to_string())
+    );
+    assert_eq!(config.pool, PoolConfig::default());
+    assert!(config.site_dir.is_none() && !config.dev_mode);
+    assert!(config.google.is_none() && config.oidc.is_none());
+    assert_eq!(config.mailer.kind, MailerKind::Stdout);
+}
+
+#[test]
+fn environment_wins_over_the_file() {
+    let toml = r#"
+        bind = "127.0.0.1:8080"
+        public_url = "https://example.com/"
+        secret = "0123456789abcdef0123456789abcdef"
+        session_ttl_days = 7
+        max_free_user_count = 5
+        magic_link_cooldown_secs = 10
+        site_dir = "site"
+        dev_mode = true
+
+        [database]
+        store = "sqlite"
+        sqlite_path = "/tmp/conversations.db"
+        pool_size = 4
+        connection_timeout_ms = 1000
+        statement_timeout_ms = 0
+
+        [google]
+        project_id = "my-project"
+        issuers = ["https://accounts.google.com"]
+
+        [oidc]
+        issuer = "https://login.example.com"
+        audience = "shareconversation"
+        jwks_url = "https://login.example.com/keys"
+
+        [jwks]
+        default_max_age_secs = 600
+        min_max_age_secs = 30
+
+        [mail]
+        mailer = "file:/tmp/mail.txt"
+    "#;
+    let config = load(
+        Some(toml),
+        &[
+            ("SESSION_TTL_DAYS", "14"),
+            ("GOOGLE_AUDIENCE", "client-id"),
+            ("DATABASE_CONNECTION_TIMEOUT_MS", "250"),
+        ],
+    )
+    .expect("valid configuration");
+    assert_eq!(config.bind.to_string(), "127.0.0.1:8080");
+    assert_eq!(config.public_url, "https://example.com");
+    assert_eq!(config.session_ttl_days, 14);
+    assert_eq!(config.max_free_user_count, 5);
+    assert_eq!(config.magic_link_cooldown_secs, 10);
+    assert_eq!(config.site_dir, Some("site".into()));
+    assert!(config.dev_mode);
+    assert_eq!(
+        config.store,
+        StoreConfig::Sqlite("/tmp/conversations.db".to_string())
+    );
+    assert_eq!(
+        config.pool,
+        PoolConfig {
+            size: 4,
+            connection_timeout: Duration::from_millis(250),
+            statement_timeout: Duration::ZERO,
+        }
+    );
+    let google = config.google.expect("Google configured");
+    assert_eq!(google.audience, "client-id");
+    assert_eq!(google.issuers, ["https://accounts.google.com"]);
+    assert_eq!(google.jwks_expiry.default_secs, 600);
+    assert_eq!(google.jwks_expiry.min_secs, 30);
+    let oidc = config.oidc.expect("OIDC configured");
+    assert_eq!(oidc.name, "oidc");
+    assert_eq!(oidc.jwks_expiry.default_secs, 600);
+    assert_eq!(config.mailer.kind, MailerKind::File("/tmp/mail.txt".into()));
+}
+
+#[test]
+fn every_problem_is_reported() {
+    let problems = load(
+        None,
+        &[
+            ("BIND_ADDRESS", "everywhere"),
+            ("PUBLIC_URL", "shareconversation.com"),
+            ("SECRET", "short"),
+            ("SESSION_TTL_DAYS", "0"),
+            ("MAX_FREE_USER_COUNT", "lots"),
+            ("MAGIC_LINK_COOLDOWN_SECS", "-1"),
+            ("SITE_DIR", "/nonexistent"),
+            ("CONVERSATION_STORE", "sqlite"),
+            ("OIDC_ISSUER", "https://login.example.com"),
+            ("JWKS_MIN_MAX_AGE_SECS", "100000"),
+            ("MAILER", "carrier-pigeon"),
+            ("DEV_MODE", "sometimes"),
+            ("DATABASE_POOL_SIZE", "0"),
+            ("DATABASE_CONNECTION_TIMEOUT_MS", "0"),
+        ],
+    )
+    .err()
+    .expect("invalid configuration");
+    for setting in [
+        "BIND_ADDRESS",
+        "PUBLIC_URL",
+        "SECRET",
+        "SESSION_TTL_DAYS",
+        "MAX_FREE_USER_COUNT",
+        "MAGIC_LINK_COOLDOWN_SECS",
+        "SITE_DIR",
+        "SQLITE_PATH",
+        "OIDC_AUDIENCE",
+        "OIDC_JWKS_URL",
+        "JWKS_MIN_MAX_AGE_SECS",
+        "MAILER",
+        "DEV_MODE",
+        "DATABASE_POOL_SIZE",
+        "DATABASE_CONNECTION_TIMEOUT_MS",
+    ] {
+        assert!(
+            problems.iter().any(|problem| problem.starts_with(setting)
+                || problem.contains(&format!("{} (", setting))),
+            "no problem with {} in {:?}",
+            setting,
+            problems
+        );
+    }
+}
+
+#[test]
+fn required_settings_and_unknown_keys() {
+    let problems = load(None, &[]).err().expect("invalid configuration");
+    assert_eq!(problems.len(), 3, "{:?}", problems);
+    assert!(problems[0].starts_with("SECRET"));
+    assert!(problems[1].starts_with("MAX_FREE_USER_COUNT"));
+    assert!(problems[2].starts_with("DATABASE_URL"));
+
+    let problems = load(Some("secrett = \"typo\""), &[])
+        .err()
+        .expect("invalid configuration");
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].contains("secrett"), "{:?}", problems);
+
+    let problems = load(
+        None,
+        &[
+            ("SECRET", SECRET),
+            ("MAX_FREE_USER_COUNT", "1"),
+            ("CONVERSATION_STORE", "memory"),
+            ("GOOGLE_KEYS_URL", "http://localhost/certs"),
+        ],
+    )
+    .err()
+    .expect("invalid configuration");
+    assert!(
+        problems[0].starts_with("GOOGLE_PROJECT_ID"),
+        "{:?}",
+        problems
+    );
+}
+
+#[test]
+fn oidc_names_are_checked() {
+    let oidc = |name: &'static str| {
+        load(
+            None,
+            &[
+                ("SECRET", SECRET),
+                ("MAX_FREE_USER_COUNT", "1"),
+                ("CONVERSATION_STORE", "memory"),
+                ("OIDC_ISSUER", "https://login.example.com"),
+                ("OIDC_AUDIENCE", "shareconversation"),
+                ("OIDC_JWKS_URL", "https://login.example.com/keys"),
+                ("OIDC_NAME", name),
+            ],
+        )
+    };
+    for name in ["local", "google", "corp:eu"] {
+        let problems = oidc(name).err().expect("invalid configuration");
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("OIDC_NAME"), "{:?}", problems);
+    }
+    let config = oidc("corp").expect("valid configuration");
+    assert_eq!(config.oidc.expect("OIDC configured").name, "corp");
+}
@@ -0,0 +1,160 @@
+// Database failures must turn into errors (or be retried), never panics
+//
+// Each test makes a throwaway database on the server given by DATABASE_URL so
+// killing connections doesn't disturb the other tests, and drops it at the
+// end. Without DATABASE_URL the tests are skipped, except the one that needs no
+// server at all.
+
+mod common;
+
+use common::*;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::SimpleAsyncConnection;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+async fn raise(conn: &mut DbConnection, sqlstate: &str) -> Result<(), diesel::result::Error> {
+    conn.batch_execute(&format!(
+        "DO $$ BEGIN RAISE EXCEPTION 'injected' USING ERRCODE = '{}'; END $$",
+        sqlstate
+    ))
+    .await
+}
+
+#[actix_web::test]
+async fn serialization_failures_are_retried() {
+    let Some(db) = DisposableDb::create() else {
+        return;
+    };
+    let attempts = &AtomicU32::new(0);
+    let result = storage::with_retries(&db.pool, |conn| {
+        async move {
+            if attempts.fetch_add(1, Ordering::Relaxed) == 0 {
+                raise(conn, "40001").await?;
+            }
+            Ok::<_, LocalError>(42)
+        }
+        .scope_boxed()
+    })
+    .await;
+    assert!(matches!(result, Ok(42)));
+    assert_eq!(attempts.load(Ordering::Relaxed), 2);
+}
+
+#[actix_web::test]
+async fn closed_connections_are_retried() {
+    let Some(db) = DisposableDb::create() else {
+        return;
+    };
+    let attempts = &AtomicU32::new(0);
+    let result = storage::with_retries(&db.pool, |conn| {
+        async move {
+            if attempts.fetch_add(1, Ordering::Relaxed) == 0 {
+                conn.batch_execute("SELECT pg_terminate_backend(pg_backend_pid())")
+                    .await?;
+            }
+            Ok::<_, LocalError>(())
+        }
+        .scope_boxed()
+    })
+    .await;
+    assert!(result.is_ok(), "{:?}", result);
+    assert_eq!(attempts.load(Ordering::Relaxed), 2);
+}
+
+#[actix_web::test]
+async fn retries_give_up() {
+    let Some(db) = DisposableDb::create() else {
+        return;
+    };
+    let attempts = &AtomicU32::new(0);
+    let result = storage::with_retries(&db.pool, |conn| {
+        attempts.fetch_add(1, Ordering::Relaxed);
+        raise(conn, "40001").scope_boxed()
+    })
+    .await;
+    assert!(matches!(result, Err(LocalError::DbContention)));
+    assert_eq!(attempts.load(Ordering::Relaxed), storage::MAX_ATTEMPTS);
+}
+
+#[actix_web::test]
+async fn other_errors_are_not_retried() {
+    let Some(db) = DisposableDb::create() else {
+        return;
+    };
+    let attempts = &AtomicU32::new(0);
+    let result = storage::with_retries(&db.pool, |conn| {
+        attempts.fetch_add(1, Ordering::Relaxed);
+        raise(conn, "23505").scope_boxed()
+    })
+    .await;
+    assert!(matches!(result, Err(LocalError::AlreadyExists)));
+    let result = storage::with_retries(&db.pool, |conn| {
+        attempts.fetch_add(1, Ordering::Relaxed);
+        raise(conn, "XX000").scope_boxed()
+    })
+    .await;
+    assert!(matches!(result, Err(LocalError::DbError)));
+    assert_eq!(attempts.load(Ordering::Relaxed), 2);
+}
+
+#[actix_web::test]
+async fn endpoints_survive_killed_connections() {
+    let Some(db) = DisposableDb::create() else {
+        return;
+    };
+    let app = test::init_service(app_with(auth::AuthProviders::new(vec![]), db.pool.clone())).await;
+    let cookie = Credentials::Cookie(session_cookie(&app).await);
+    let id = call(
+        &app,
+        &cookie,
+        Method::POST,
+        "/conversation/",
+        Some(new_conversation()),
+        StatusCode::CREATED,
+    )
+    .await;
+    let id = id.as_str().expect("conversation id").to_string();
+    db.kill_connections();
+    call(
+        &app,
+        &Credentials::Nothing,
+        Method::GET,
+        &format!("/conversation/json/{}", id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    db.kill_connections();
+    call(
+        &app,
+        &cookie,
+        Method::DELETE,
+        &format!("/conversation/{}", id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+}
+
+// Needs no database server, nothing listens on the port
+#[actix_web::test]
+async fn unreachable_database_is_service_unavailable() {
+    let pool = storage::initialize_db_pool(
+        "postgres://nobody@127.0.0.1:1/nothing",
+        &PoolConfig {
+            connection_timeout: std::time::Duration::from_millis(100),
+            ..Default::default()
+        },
+    );
+    let app = test::init_service(app_with(auth::AuthProviders::new(vec![]), pool)).await;
+    let resp = call(
+        &app,
+        &Credentials::Nothing,
+        Method::GET,
+        "/conversation/json/abc",
+        None,
+        StatusCode::SERVICE_UNAVAILABLE,
+    )
+    .await;
+    assert_eq!(resp["code"], "database_unavailable");
+}
@@ -0,0 +1,623 @@
+// Every endpoint under every way of authenticating
+//
+// These run against a real Postgres database given by DATABASE_URL (the .env
+// file is read too). Each test makes its own users so they can share a
+// database. Without DATABASE_URL the tests are skipped (see common::database_url).
+
+mod common;
+
+use common::*;
+use futures_util::future::join_all;
+
+macro_rules! test_app {
+    ($pool:expr) => {
+        test::init_service(app_with(
+            auth::AuthProviders::new(vec![Box::new(TestProvider)]),
+            $pool.clone(),
+        ))
+        .await
+    };
+}
+
+// Go through every endpoint that needs a user, expecting each to work
+async fn exercise_endpoints<S, B>(app: &S, creds: &Credentials)
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: actix_web::body::MessageBody,
+{
+    call(
+        app,
+        creds,
+        Method::POST,
+        "/authenticated",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    let id = call(
+        app,
+        creds,
+        Method::POST,
+        "/conversation/",
+        Some(new_conversation()),
+        StatusCode::CREATED,
+    )
+    .await;
+    let id = id.as_str().expect("conversation id").to_string();
+    call(
+        app,
+        creds,
+        Method::POST,
+        "/conversations",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    call(
+        app,
+        creds,
+        Method::GET,
+        "/conversation/count",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    let info = call(
+        app,
+        &Credentials::Nothing,
+        Method::GET,
+        &format!("/conversation/json/{}", id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    let patch = serde_json::json!({
+        "id": id,
+        "contents": info["contents"],
+        "metadata": info["metadata"],
+        "public": true,
+        "research": false,
+    });
+    let if_match = format!("\"{}\"", info["version"]);
+    call_with_headers(
+        app,
+        creds,
+        Method::PATCH,
+        &format!("/conversation/{}", id),
+        &[("If-Match", &if_match)],
+        Some(patch),
+        StatusCode::OK,
+    )
+    .await;
+    let tag = call(
+        app,
+        creds,
+        Method::POST,
+        "/tags",
+        Some(serde_json::json!({"name": "a"})),
+        StatusCode::CREATED,
+    )
+    .await;
+    let tag_id = tag["id"].as_str().expect("tag id").to_string();
+    call(app, creds, Method::GET, "/tags", None, StatusCode::OK).await;
+    call(
+        app,
+        creds,
+        Method::PATCH,
+        &format!("/tags/{}", tag_id),
+        Some(serde_json::json!({"name": "b"})),
+        StatusCode::OK,
+    )
+    .await;
+    let folder = call(
+        app,
+        creds,
+        Method::POST,
+        "/folders",
+        Some(serde_json::json!({"name": "f"})),
+        StatusCode::CREATED,
+    )
+    .await;
+    let folder_id = folder["id"].as_str().expect("folder id").to_string();
+    call(app, creds, Method::GET, "/folders", None, StatusCode::OK).await;
+    call(
+        app,
+        creds,
+        Method::PATCH,
+        &format!("/folders/{}", folder_id),
+        Some(serde_json::json!({"name": "g"})),
+        StatusCode::OK,
+    )
+    .await;
+    call(
+        app,
+        creds,
+        Method::PUT,
+        &format!("/conversation/{}/folder", id),
+        Some(serde_json::json!({"folder_id": folder_id})),
+        StatusCode::OK,
+    )
+    .await;
+    call(
+        app,
+        creds,
+        Method::PUT,
+        &format!("/conversation/{}/tags/{}", id, tag_id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    call(
+        app,
+        creds,
+        Method::DELETE,
+        &format!("/conversation/{}/tags/{}", id, tag_id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    let bulk = serde_json::json!({"ids": [id], "operation": {"op": "set_research", "value": true}});
+    call(
+        app,
+        creds,
+        Method::POST,
+        "/conversations/bulk",
+        Some(bulk),
+        StatusCode::OK,
+    )
+    .await;
+    call(
+        app,
+        creds,
+        Method::DELETE,
+        &format!("/conversation/{}", id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    call(
+        app,
+        creds,
+        Method::POST,
+        &format!("/conversation/undelete/{}", id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    call(
+        app,
+        creds,
+        Method::DELETE,
+        &format!("/tags/{}", tag_id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    call(
+        app,
+        creds,
+        Method::DELETE,
+        &format!("/folders/{}", folder_id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+}
+
+// Endpoints that need a user, with valid bodies so only authentication can fail
+fn protected_endpoints() -> Vec<(Method, &'static str, Option<serde_json::Value>)> {
+    let patch = serde_json::json!({
+        "id": "x",
+        "contents": {"avatar": "", "dialog": []},
+        "metadata": {
+            "title": "",
+            "openaiid": "",
+            "model": "",
+            "creationdate": {"secs_since_epoch": 0, "nanos_since_epoch": 0},
+            "length": 0,
+        },
+        "public": false,
+        "research": false,
+    });
+    let name = serde_json::json!({"name": "a"});
+    vec![
+        (Method::POST, "/conversation/", Some(new_conversation())),
+        (Method::POST, "/conversations", None),
+        (Method::GET, "/conversation/count", None),
+        (Method::PATCH, "/conversation/x", Some(patch)),
+        (Method::DELETE, "/conversation/x", None),
+        (Method::POST, "/conversation/undelete/x", None),
+        (
+            Method::POST,
+            "/conversations/bulk",
+            Some(serde_json::json!({"ids": ["x"], "operation": {"op": "delete"}})),
+        ),
+        (Method::GET, "/tags", None),
+        (Method::POST, "/tags", Some(name.clone())),
+        (Method::PATCH, "/tags/x", Some(name.clone())),
+        (Method::DELETE, "/tags/x", None),
+        (Method::GET, "/folders", None),
+        (Method::POST, "/folders", Some(name.clone())),
+        (Method::PATCH, "/folders/x", Some(name)),
+        (Method::DELETE, "/folders/x", None),
+        (
+            Method::PUT,
+            "/conversation/x/folder",
+            Some(serde_json::json!({"folder_id": null})),
+        ),
+        (Method::PUT, "/conversation/x/tags/y", None),
+        (Method::DELETE, "/conversation/x/tags/y", None),
+        (Method::GET, "/tokens", None),
+        (
+            Method::POST,
+            "/tokens",
+            Some(serde_json::json!({"name": "t", "scopes": ["read"]})),
+        ),
+        (Method::DELETE, "/tokens/x", None),
+        (
+            Method::POST,
+            "/local/password",
+            Some(serde_json::json!({"password": "correct horse"})),
+        ),
+    ]
+}
+
+#[actix_web::test]
+async fn endpoints_accept_session_cookie() {
+    let Some(pool) = test_pool() else { return };
+    let app = test_app!(pool);
+    let creds = Credentials::Cookie(session_cookie(&app).await);
+    exercise_endpoints(&app, &creds).await;
+}
+
+#[actix_web::test]
+async fn endpoints_accept_api_token() {
+    let Some(pool) = test_pool() else { return };
+    let app = test_app!(pool);
+    let cookie = Credentials::Cookie(session_cookie(&app).await);
+    let token = call(
+        &app,
+        &cookie,
+        Method::POST,
+        "/tokens",
+        Some(serde_json::json!({"name": "ci", "scopes": ["read", "write"]})),
+        StatusCode::CREATED,
+    )
+    .await;
+    let creds = Credentials::Bearer(token["token"].as_str().expect("token").to_string());
+    exercise_endpoints(&app, &creds).await;
+}
+
+#[actix_web::test]
+async fn endpoints_accept_access_token() {
+    let Some(pool) = test_pool() else { return };
+    let app = test_app!(pool);
+    let creds = Credentials::Bearer(format!("test-access-{}", uuid::Uuid::new_v4().simple()));
+    exercise_endpoints(&app, &creds).await;
+}
+
+#[actix_web::test]
+async fn endpoints_reject_missing_or_bad_credentials() {
+    let Some(pool) = test_pool() else { return };
+    let app = test_app!(pool);
+    let bad = [
+        (Credentials::Nothing, "credentials_missing"),
+        (
+            Credentials::Bearer("sp_not_a_real_token".to_string()),
+            "api_token_invalid",
+        ),
+        (
+            Credentials::Bearer("not-a-real-access-token".to_string()),
+            "token_invalid",
+        ),
+        (
+            Credentials::Cookie(Cookie::new("id", "garbage")),
+            "credentials_missing",
+        ),
+    ];
+    for (creds, code) in bad.iter() {
+        for (method, path, body) in protected_endpoints() {
+            let resp = call(&app, creds, method, path, body, StatusCode::UNAUTHORIZED).await;
+            assert_eq!(resp["code"], *code, "{}", path);
+        }
+    }
+    call(
+        &app,
+        &Credentials::Nothing,
+        Method::POST,
+        "/authenticated",
+        None,
+        StatusCode::UNAUTHORIZED,
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn token_management_needs_session() {
+    let Some(pool) = test_pool() else { return };
+    let app = test_app!(pool);
+    let cookie = Credentials::Cookie(session_cookie(&app).await);
+    let token = call(
+        &app,
+        &cookie,
+        Method::POST,
+        "/tokens",
+        Some(serde_json::json!({"name": "ci", "scopes": ["read", "write"]})),
+        StatusCode::CREATED,
+    )
+    .await;
+    let token_id = token["id"].as_str().expect("token id").to_string();
+    let bearers = [
+        Credentials::Bearer(token["token"].as_str().expect("token").to_string()),
+        Credentials::Bearer(format!("test-access-{}", uuid::Uuid::new_v4().simple())),
+    ];
+    for creds in bearers.iter() {
+        call(
+            &app,
+            creds,
+            Method::GET,
+            "/tokens",
+            None,
+            StatusCode::FORBIDDEN,
+        )
+        .await;
+        call(
+            &app,
+            creds,
+            Method::POST,
+            "/tokens",
+            Some(serde_json::json!({"name": "more", "scopes": ["write"]})),
+            StatusCode::FORBIDDEN,
+        )
+        .await;
+        call(
+            &app,
+            creds,
+            Method::DELETE,
+            &format!("/tokens/{}", token_id),
+            None,
+            StatusCode::FORBIDDEN,
+        )
+        .await;
+        call(
+            &app,
+            creds,
+            Method::POST,
+            "/local/password",
+            Some(serde_json::json!({"password": "correct horse"})),
+            StatusCode::FORBIDDEN,
+        )
+        .await;
+    }
+    call(&app, &cookie, Method::GET, "/tokens", None, StatusCode::OK).await;
+    call(
+        &app,
+        &cookie,
+        Method::DELETE,
+        &format!("/tokens/{}", token_id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    // Revoked token no longer works
+    call(
+        &app,
+        &bearers[0],
+        Method::POST,
+        "/conversations",
+        None,
+        StatusCode::UNAUTHORIZED,
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn read_token_cannot_write() {
+    let Some(pool) = test_pool() else { return };
+    let app = test_app!(pool);
+    let cookie = Credentials::Cookie(session_cookie(&app).await);
+    let token = call(
+        &app,
+        &cookie,
+        Method::POST,
+        "/tokens",
+        Some(serde_json::json!({"name": "ro", "scopes": ["read"]})),
+        StatusCode::CREATED,
+    )
+    .await;
+    let creds = Credentials::Bearer(token["token"].as_str().expect("token").to_string());
+    call(
+        &app,
+        &creds,
+        Method::POST,
+        "/conversations",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    call(
+        &app,
+        &creds,
+        Method::GET,
+        "/conversation/count",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    call(&app, &creds, Method::GET, "/tags", None, StatusCode::OK).await;
+    call(&app, &creds, Method::GET, "/folders", None, StatusCode::OK).await;
+    call(
+        &app,
+        &creds,
+        Method::POST,
+        "/conversation/",
+        Some(new_conversation()),
+        StatusCode::FORBIDDEN,
+    )
+    .await;
+    call(
+        &app,
+        &creds,
+        Method::POST,
+        "/tags",
+        Some(serde_json::json!({"name": "a"})),
+        StatusCode::FORBIDDEN,
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn access_token_validation_is_cached() {
+    let Some(pool) = test_pool() else { return };
+    let app = test_app!(pool);
+    let creds = Credentials::Bearer(format!("test-access-{}", uuid::Uuid::new_v4().simple()));
+    for _ in 0..3 {
+        call(&app, &creds, Method::GET, "/tags", None, StatusCode::OK).await;
+    }
+    let resp =
+        test::call_service(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = String::from_utf8(test::read_body(resp).await.to_vec()).expect("utf8 metrics");
+    assert!(
+        body.contains("access_token_cache_hits_total 2\n"),
+        "{}",
+        body
+    );
+    assert!(
+        body.contains("access_token_cache_misses_total 1\n"),
+        "{}",
+        body
+    );
+    assert!(body.contains("access_token_cache_entries 1\n"), "{}", body);
+}
+
+#[actix_web::test]
+async fn errors_are_problem_details() {
+    let Some(pool) = test_pool() else { return };
+    let app = test_app!(pool);
+    let cookie = Credentials::Cookie(session_cookie(&app).await);
+    let missing = uuid::Uuid::new_v4().simple().to_string();
+    let resp = test::call_service(
+        &app,
+        request(
+            Method::DELETE,
+            &format!("/conversation/{}", missing),
+            &cookie,
+        )
+        .insert_header(("X-Request-Id", "test-request-1"))
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        resp.headers().get("X-Request-Id").expect("request id"),
+        "test-request-1"
+    );
+    assert_eq!(
+        resp.headers().get("Content-Type").expect("content type"),
+        "application/problem+json"
+    );
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["status"], 404);
+    assert_eq!(body["code"], "not_found");
+    assert_eq!(body["request_id"], "test-request-1");
+
+    // Someone else's conversation
+    let other = Credentials::Cookie(session_cookie(&app).await);
+    let id = call(
+        &app,
+        &other,
+        Method::POST,
+        "/conversation/",
+        Some(new_conversation()),
+        StatusCode::CREATED,
+    )
+    .await;
+    let resp = call(
+        &app,
+        &cookie,
+        Method::DELETE,
+        &format!("/conversation/{}", id.as_str().expect("conversation id")),
+        None,
+        StatusCode::FORBIDDEN,
+    )
+    .await;
+    assert_eq!(resp["code"], "forbidden");
+
+    let tag = serde_json::json!({"name": "dup"});
+    call(
+        &app,
+        &cookie,
+        Method::POST,
+        "/tags",
+        Some(tag.clone()),
+        StatusCode::CREATED,
+    )
+    .await;
+    let resp = call(
+        &app,
+        &cookie,
+        Method::POST,
+        "/tags",
+        Some(tag),
+        StatusCode::CONFLICT,
+    )
+    .await;
+    assert_eq!(resp["code"], "already_exists");
+    let resp = call(
+        &app,
+        &cookie,
+        Method::POST,
+        "/tags",
+        Some(serde_json::json!({"name": "  "})),
+        StatusCode::UNPROCESSABLE_ENTITY,
+    )
+    .await;
+    assert_eq!(resp["code"], "tag_name_invalid");
+    let resp = call(
+        &app,
+        &cookie,
+        Method::POST,
+        "/tags",
+        Some(serde_json::json!({"label": "x"})),
+        StatusCode::BAD_REQUEST,
+    )
+    .await;
+    assert_eq!(resp["code"], "body_invalid");
+    // Every response has an id, generated when the client didn't send one
+    assert!(!resp["request_id"].as_str().unwrap_or_default().is_empty());
+}
+
+#[actix_web::test]
+async fn parallel_uploads_make_one_conversation() {
+    let Some(pool) = test_pool() else { return };
+    let app = test_app!(pool);
+    let creds = Credentials::Bearer(format!("test-access-{}", uuid::Uuid::new_v4().simple()));
+    let body = new_conversation();
+    let ids = join_all((0..10).map(|_| {
+        call(
+            &app,
+            &creds,
+            Method::POST,
+            "/conversation/",
+            Some(body.clone()),
+            StatusCode::CREATED,
+        )
+    }))
+    .await;
+    assert!(ids.iter().all(|id| *id == ids[0]), "{:?}", ids);
+    let count = call(
+        &app,
+        &creds,
+        Method::GET,
+        "/conversation/count",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(count, 1);
+}
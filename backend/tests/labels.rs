@@ -0,0 +1,310 @@
+// Tags and folders, and listing conversations by them
+//
+// These run against a real Postgres database given by DATABASE_URL, see
+// common::database_url.
+
+mod common;
+
+use common::*;
+
+// Ids of the conversations listed with the given query, in list order
+async fn listed<S, B>(app: &S, creds: &Credentials, query: &str) -> Vec<String>
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: actix_web::body::MessageBody,
+{
+    let page = call(
+        app,
+        creds,
+        Method::POST,
+        &format!("/conversations?{}", query),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    page["conversations"]
+        .as_array()
+        .expect("conversations")
+        .iter()
+        .map(|conv| conv["id"].as_str().expect("id").to_string())
+        .collect()
+}
+
+#[actix_web::test]
+async fn tags_and_folders_filter_the_list() {
+    let Some(pool) = test_pool() else { return };
+    let app = test::init_service(app_with(
+        auth::AuthProviders::new(vec![Box::new(TestProvider)]),
+        pool,
+    ))
+    .await;
+    let alice = Credentials::Cookie(session_cookie(&app).await);
+    let work = call(
+        &app,
+        &alice,
+        Method::POST,
+        "/tags",
+        Some(serde_json::json!({"name": " work "})),
+        StatusCode::CREATED,
+    )
+    .await;
+    assert_eq!(work["name"], "work");
+    let work = work["id"].as_str().expect("tag id").to_string();
+    let urgent = call(
+        &app,
+        &alice,
+        Method::POST,
+        "/tags",
+        Some(serde_json::json!({"name": "urgent"})),
+        StatusCode::CREATED,
+    )
+    .await;
+    let urgent = urgent["id"].as_str().expect("tag id").to_string();
+    let folder = call(
+        &app,
+        &alice,
+        Method::POST,
+        "/folders",
+        Some(serde_json::json!({"name": "Projects"})),
+        StatusCode::CREATED,
+    )
+    .await;
+    let folder = folder["id"].as_str().expect("folder id").to_string();
+
+    let first = upload(&app, &alice).await;
+    let second = upload(&app, &alice).await;
+    let third = upload(&app, &alice).await;
+    for (id, tag) in [(&first, &work), (&second, &work), (&second, &urgent)] {
+        call(
+            &app,
+            &alice,
+            Method::PUT,
+            &format!("/conversation/{}/tags/{}", id, tag),
+            None,
+            StatusCode::OK,
+        )
+        .await;
+    }
+    call(
+        &app,
+        &alice,
+        Method::PUT,
+        &format!("/conversation/{}/folder", third),
+        Some(serde_json::json!({"folder_id": folder})),
+        StatusCode::OK,
+    )
+    .await;
+
+    let by_work = listed(&app, &alice, &format!("tag={}&order=asc", work)).await;
+    assert_eq!(by_work, vec![first.clone(), second.clone()]);
+    let by_urgent = listed(&app, &alice, &format!("tag={}", urgent)).await;
+    assert_eq!(by_urgent, vec![second.clone()]);
+    let in_folder = listed(&app, &alice, &format!("folder={}", folder)).await;
+    assert_eq!(in_folder, vec![third.clone()]);
+    // Filters combine, and a page carries the tags sorted by name
+    let both = listed(&app, &alice, &format!("tag={}&folder={}", work, folder)).await;
+    assert!(both.is_empty());
+    let page = call(
+        &app,
+        &alice,
+        Method::POST,
+        &format!("/conversations?tag={}", urgent),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    let names: Vec<_> = page["conversations"][0]["tags"]
+        .as_array()
+        .expect("tags")
+        .iter()
+        .map(|tag| tag["name"].as_str().expect("tag name").to_string())
+        .collect();
+    assert_eq!(names, vec!["urgent", "work"]);
+
+    // Renamed labels keep their conversations
+    call(
+        &app,
+        &alice,
+        Method::PATCH,
+        &format!("/tags/{}", urgent),
+        Some(serde_json::json!({"name": "later"})),
+        StatusCode::OK,
+    )
+    .await;
+    call(
+        &app,
+        &alice,
+        Method::PATCH,
+        &format!("/folders/{}", folder),
+        Some(serde_json::json!({"name": "Archive"})),
+        StatusCode::OK,
+    )
+    .await;
+    let tags = call(&app, &alice, Method::GET, "/tags", None, StatusCode::OK).await;
+    assert_eq!(tags[0]["name"], "later");
+    let folders = call(&app, &alice, Method::GET, "/folders", None, StatusCode::OK).await;
+    assert_eq!(folders[0]["name"], "Archive");
+    assert_eq!(
+        listed(&app, &alice, &format!("tag={}", urgent)).await,
+        vec![second.clone()]
+    );
+
+    call(
+        &app,
+        &alice,
+        Method::DELETE,
+        &format!("/conversation/{}/tags/{}", first, work),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(
+        listed(&app, &alice, &format!("tag={}", work)).await,
+        vec![second.clone()]
+    );
+
+    // Deleting labels leaves the conversations
+    call(
+        &app,
+        &alice,
+        Method::DELETE,
+        &format!("/tags/{}", urgent),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    call(
+        &app,
+        &alice,
+        Method::DELETE,
+        &format!("/folders/{}", folder),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    assert!(listed(&app, &alice, &format!("folder={}", folder))
+        .await
+        .is_empty());
+    let all = call(
+        &app,
+        &alice,
+        Method::POST,
+        "/conversations?order=asc",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    let all = all["conversations"].as_array().expect("conversations");
+    assert_eq!(all.len(), 3);
+    assert_eq!(all[1]["tags"].as_array().map(Vec::len), Some(1));
+    assert_eq!(all[2]["folder_id"], serde_json::Value::Null);
+}
+
+#[actix_web::test]
+async fn labels_belong_to_one_user() {
+    let Some(pool) = test_pool() else { return };
+    let app = test::init_service(app_with(
+        auth::AuthProviders::new(vec![Box::new(TestProvider)]),
+        pool,
+    ))
+    .await;
+    let alice = Credentials::Cookie(session_cookie(&app).await);
+    let bob = Credentials::Cookie(session_cookie(&app).await);
+    let tag = call(
+        &app,
+        &alice,
+        Method::POST,
+        "/tags",
+        Some(serde_json::json!({"name": "mine"})),
+        StatusCode::CREATED,
+    )
+    .await;
+    let tag = tag["id"].as_str().expect("tag id").to_string();
+    let alices = upload(&app, &alice).await;
+    let bobs = upload(&app, &bob).await;
+
+    // Bob sees none of Alice's labels and can't use them
+    let tags = call(&app, &bob, Method::GET, "/tags", None, StatusCode::OK).await;
+    assert_eq!(tags, serde_json::json!([]));
+    call(
+        &app,
+        &bob,
+        Method::PUT,
+        &format!("/conversation/{}/tags/{}", bobs, tag),
+        None,
+        StatusCode::NOT_FOUND,
+    )
+    .await;
+    call(
+        &app,
+        &bob,
+        Method::PATCH,
+        &format!("/tags/{}", tag),
+        Some(serde_json::json!({"name": "taken"})),
+        StatusCode::NOT_FOUND,
+    )
+    .await;
+    call(
+        &app,
+        &bob,
+        Method::DELETE,
+        &format!("/tags/{}", tag),
+        None,
+        StatusCode::NOT_FOUND,
+    )
+    .await;
+    call(
+        &app,
+        &alice,
+        Method::PUT,
+        &format!("/conversation/{}/tags/{}", bobs, tag),
+        None,
+        StatusCode::FORBIDDEN,
+    )
+    .await;
+    assert!(listed(&app, &bob, &format!("tag={}", tag)).await.is_empty());
+
+    // Names are checked and unique per user only
+    for name in ["", "  ", &"x".repeat(101)] {
+        let resp = call(
+            &app,
+            &alice,
+            Method::POST,
+            "/folders",
+            Some(serde_json::json!({"name": name})),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        )
+        .await;
+        assert_eq!(resp["code"], "folder_name_invalid");
+    }
+    call(
+        &app,
+        &bob,
+        Method::POST,
+        "/tags",
+        Some(serde_json::json!({"name": "mine"})),
+        StatusCode::CREATED,
+    )
+    .await;
+    assert_eq!(
+        listed(&app, &alice, &format!("tag={}", tag)).await,
+        Vec::<String>::new()
+    );
+    call(
+        &app,
+        &alice,
+        Method::PUT,
+        &format!("/conversation/{}/tags/{}", alices, tag),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(
+        listed(&app, &alice, &format!("tag={}", tag)).await,
+        vec![alices]
+    );
+}
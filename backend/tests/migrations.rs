@@ -0,0 +1,240 @@
+// Migrations carry rows stored by older versions over to the new columns, and
+// stored contents over to the current shape
+//
+// Each test makes a throwaway database on the server given by DATABASE_URL,
+// applies the migrations up to the one under test, stores a row the way the
+// code of that time did and checks it after the migration. Contents upgrades
+// are checked with steps made up here, as long as CONTENTS_UPGRADES is empty.
+// Without DATABASE_URL the tests are skipped (see common::database_url),
+// except the one that needs no database.
+
+mod common;
+
+use common::*;
+use diesel::connection::SimpleConnection;
+use diesel::sql_types::{Bool, Double, Integer, Text};
+use diesel::RunQueryDsl;
+use shareprompts_backend_api::models::{
+    read_contents, read_contents_with, ContentsUpgrade, CONTENTS_SCHEMA_VERSION,
+};
+
+#[derive(diesel::QueryableByName)]
+struct Epoch {
+    #[diesel(sql_type = Double)]
+    epoch: f64,
+}
+
+#[actix_web::test]
+async fn created_at_is_backfilled_from_metadata() {
+    let Some(db) = DisposableDb::empty() else {
+        return;
+    };
+    db.migrate_through("2023-06-06-120000_folders");
+    let mut conn = db.connect();
+    conn.batch_execute(
+        r#"INSERT INTO conversations (id, hmac, contents, metadata, user_id) VALUES (
+            'old', 'h', '{"avatar": "", "dialog": []}',
+            '{"title": "Old", "model": "m", "openaiid": "o", "length": 0,
+              "creationdate": {"secs_since_epoch": 1686000000, "nanos_since_epoch": 250000000}}',
+            'alice')"#,
+    )
+    .expect("insert conversation as stored before created_at");
+
+    db.migrate_through("2023-06-12-120000_created_at");
+    let created: Epoch = diesel::sql_query(
+        "SELECT EXTRACT(EPOCH FROM created_at)::FLOAT8 AS epoch FROM conversations WHERE id = 'old'",
+    )
+    .get_result(&mut conn)
+    .expect("created_at");
+    assert_eq!(created.epoch, 1686000000.25);
+}
+
+#[derive(diesel::QueryableByName)]
+struct Metadata {
+    #[diesel(sql_type = Text)]
+    title: String,
+    #[diesel(sql_type = Text)]
+    model: String,
+    #[diesel(sql_type = Text)]
+    openaiid: String,
+    #[diesel(sql_type = Integer)]
+    length: i32,
+    #[diesel(sql_type = Bool)]
+    updated_is_created: bool,
+}
+
+#[actix_web::test]
+async fn metadata_moves_to_columns() {
+    let Some(db) = DisposableDb::empty() else {
+        return;
+    };
+    db.migrate_through("2023-06-12-120000_created_at");
+    let mut conn = db.connect();
+    conn.batch_execute(
+        r#"INSERT INTO conversations (id, hmac, contents, metadata, user_id, created_at) VALUES (
+            'full', 'h', '{"avatar": "", "dialog": []}',
+            '{"title": "Old", "model": "gpt-4", "openaiid": "abc", "length": 7,
+              "creationdate": {"secs_since_epoch": 1686000000, "nanos_since_epoch": 0}}',
+            'alice', '2023-06-05 21:20:00+00'),
+          ('sparse', 'h', '{"avatar": "", "dialog": []}', '{}', 'alice', '2023-06-05 21:20:00+00')"#,
+    )
+    .expect("insert conversations as stored before the metadata columns");
+
+    db.migrate_through("2023-06-19-120000_metadata_columns");
+    let query = |id: &str| {
+        diesel::sql_query(
+            "SELECT title, model, openaiid, length, updated_at = created_at AS updated_is_created
+             FROM conversations WHERE id = $1",
+        )
+        .bind::<Text, _>(id.to_string())
+    };
+    let full: Metadata = query("full").get_result(&mut conn).expect("metadata");
+    assert_eq!(full.title, "Old");
+    assert_eq!(full.model, "gpt-4");
+    assert_eq!(full.openaiid, "abc");
+    assert_eq!(full.length, 7);
+    assert!(full.updated_is_created);
+    // Missing fields get the column defaults instead of failing the migration
+    let sparse: Metadata = query("sparse").get_result(&mut conn).expect("metadata");
+    assert_eq!(sparse.title, "");
+    assert_eq!(sparse.model, "");
+    assert_eq!(sparse.length, 0);
+}
+
+#[actix_web::test]
+async fn updates_set_updated_at() {
+    let Some(db) = DisposableDb::create() else {
+        return;
+    };
+    let mut conn = db.connect();
+    conn.batch_execute(
+        "INSERT INTO conversations (id, hmac, contents, user_id, created_at, updated_at)
+         VALUES ('c', 'h', '{\"avatar\": \"\", \"dialog\": []}', 'alice',
+                 '2023-06-05 21:20:00+00', '2023-06-05 21:20:00+00')",
+    )
+    .expect("insert conversation");
+    let updated_at = |conn: &mut diesel::PgConnection| -> f64 {
+        diesel::sql_query(
+            "SELECT EXTRACT(EPOCH FROM updated_at)::FLOAT8 AS epoch FROM conversations",
+        )
+        .get_result::<Epoch>(conn)
+        .expect("updated_at")
+        .epoch
+    };
+    let before = updated_at(&mut conn);
+
+    // Any change moves it forward, with no help from the code
+    conn.batch_execute("UPDATE conversations SET public = FALSE")
+        .expect("update");
+    let after = updated_at(&mut conn);
+    assert!(after > before, "{} > {}", after, before);
+
+    // Unless the update sets it itself
+    conn.batch_execute(
+        "UPDATE conversations SET research = FALSE, updated_at = '2023-07-01 00:00:00+00'",
+    )
+    .expect("update");
+    assert_eq!(updated_at(&mut conn), 1688169600.0);
+}
+
+// Version 1 kept the dialog as "messages", version 2 had no avatar
+const UPGRADES: &[ContentsUpgrade] = &[
+    |mut value| {
+        let messages = value["messages"].take();
+        serde_json::json!({"dialog": messages})
+    },
+    |mut value| {
+        value["avatar"] = "".into();
+        value
+    },
+];
+
+fn dialog() -> serde_json::Value {
+    serde_json::json!([{"who": "human", "what": "Hello"}])
+}
+
+#[actix_web::test]
+async fn contents_upgrades_run_from_the_stored_version() {
+    let stored = [
+        (1, serde_json::json!({"messages": dialog()})),
+        (2, serde_json::json!({"dialog": dialog()})),
+        (3, serde_json::json!({"avatar": "", "dialog": dialog()})),
+    ];
+    for (version, value) in &stored {
+        let contents = read_contents_with(UPGRADES, *version, value).expect("readable contents");
+        assert_eq!(contents.dialog[0].what, "Hello", "version {}", version);
+    }
+    // Steps only run from the stored version on
+    assert!(read_contents_with(UPGRADES, 2, &stored[0].1).is_err());
+
+    for version in [0, -1, i32::MIN, 4, i32::MAX] {
+        match read_contents_with(UPGRADES, version, &stored[2].1) {
+            Err(LocalError::UnsupportedContentsVersion(v)) => assert_eq!(v, version),
+            other => panic!("version {}: {:?}", version, other),
+        }
+    }
+    assert!(matches!(
+        read_contents(CONTENTS_SCHEMA_VERSION + 1, &stored[2].1),
+        Err(LocalError::UnsupportedContentsVersion(_))
+    ));
+}
+
+#[actix_web::test]
+async fn contents_are_rewritten_in_batches() {
+    let Some(db) = DisposableDb::create() else {
+        return;
+    };
+    // Enough old rows for several batches, and some that are current already
+    let old_rows = 2 * storage::CONTENTS_MIGRATION_BATCH + 3;
+    let mut conn = db.connect();
+    conn.batch_execute(&format!(
+        r#"INSERT INTO conversations (id, hmac, contents, user_id, schema_version)
+           SELECT 'old' || n, 'h', '{{"messages": [{{"who": "human", "what": "Hello"}}]}}',
+                  'alice', 1
+           FROM generate_series(1, {old}) AS n;
+           INSERT INTO conversations (id, hmac, contents, user_id, schema_version) VALUES
+             ('middle', 'h', '{{"dialog": []}}', 'alice', 2),
+             ('current', 'h', '{{"avatar": "a", "dialog": []}}', 'alice', 3)"#,
+        old = old_rows,
+    ))
+    .expect("insert conversations with old contents");
+
+    let mut async_conn = db.pool.get().await.expect("connection");
+    let rewritten = storage::migrate_all_contents_with(&mut async_conn, UPGRADES)
+        .await
+        .expect("contents migration");
+    assert_eq!(rewritten as i64, old_rows + 1);
+
+    #[derive(diesel::QueryableByName)]
+    struct Stored {
+        #[diesel(sql_type = Integer)]
+        schema_version: i32,
+        #[diesel(sql_type = diesel::sql_types::Jsonb)]
+        contents: serde_json::Value,
+    }
+    let rows: Vec<Stored> = diesel::sql_query("SELECT schema_version, contents FROM conversations")
+        .load(&mut conn)
+        .expect("conversations");
+    assert_eq!(rows.len() as i64, old_rows + 2);
+    for row in &rows {
+        assert_eq!(row.schema_version, 3);
+        // Current contents read without any step
+        read_contents_with(&[], 1, &row.contents).expect("current contents");
+    }
+    // Nothing left to do on a second run
+    let rewritten = storage::migrate_all_contents_with(&mut async_conn, UPGRADES)
+        .await
+        .expect("contents migration");
+    assert_eq!(rewritten, 0);
+
+    // A version from a newer server stops the migration instead of being skipped
+    conn.batch_execute(
+        r#"INSERT INTO conversations (id, hmac, contents, user_id, schema_version)
+           VALUES ('newer', 'h', '{}', 'alice', 9)"#,
+    )
+    .expect("insert conversation from a newer server");
+    let err = storage::migrate_all_contents_with(&mut async_conn, UPGRADES)
+        .await
+        .expect_err("unreadable contents");
+    assert!(err.to_string().contains("newer"), "{}", err);
+}
@@ -0,0 +1,401 @@
+// Identity and access tokens checked against a local stand-in for Google
+//
+// The mock serves the test key set (testdata/oidc-test-jwks.json) and a fake
+// tokeninfo endpoint. Tokens are signed with testdata/oidc-test-key.pem.
+
+mod common;
+
+use chrono::Utc;
+use common::*;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::Deserialize;
+use shareprompts_backend_api::auth::AuthProvider;
+
+const TEST_KEY: &[u8] = include_bytes!("../testdata/oidc-test-key.pem");
+const TEST_JWKS: &str = include_str!("../testdata/oidc-test-jwks.json");
+const TEST_KID: &str = "test-key";
+const ISSUER: &str = "https://accounts.google.com";
+const AUDIENCE: &str = "test-project";
+
+#[derive(Deserialize)]
+struct TokenInfoQuery {
+    access_token: String,
+}
+
+// Access tokens "good-<user>" belong to <user>, everything else is invalid
+async fn tokeninfo(query: web::Query<TokenInfoQuery>) -> HttpResponse {
+    match query.access_token.strip_prefix("good-") {
+        Some(user) => HttpResponse::Ok().json(serde_json::json!({
+            "user_id": user,
+            "audience": AUDIENCE,
+            "expires_in": 3599,
+        })),
+        None => HttpResponse::BadRequest().json(serde_json::json!({"error": "invalid_token"})),
+    }
+}
+
+// Serve keys at /certs and tokeninfo at /tokeninfo, returns the base URL
+fn start_mock_google() -> String {
+    let server = HttpServer::new(|| {
+        App::new()
+            .route(
+                "/certs",
+                web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .insert_header(("Cache-Control", "public, max-age=300"))
+                        .content_type("application/json")
+                        .body(TEST_JWKS)
+                }),
+            )
+            .route("/tokeninfo", web::get().to(tokeninfo))
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .expect("bind mock Google server");
+    let addr = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    format!("http://{}", addr)
+}
+
+fn google(base: &str) -> auth::GoogleProvider {
+    auth::GoogleProvider::new(auth::GoogleConfig {
+        issuers: vec![ISSUER.to_string()],
+        audience: AUDIENCE.to_string(),
+        keys_url: format!("{}/certs", base),
+        tokeninfo_url: format!("{}/tokeninfo", base),
+        jwks_expiry: Default::default(),
+    })
+}
+
+fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+// Claims of a token that is valid right now
+fn claims(sub: &str) -> serde_json::Value {
+    serde_json::json!({
+        "iss": ISSUER,
+        "aud": AUDIENCE,
+        "sub": sub,
+        "iat": now(),
+        "nbf": now() - 10,
+        "exp": now() + 3600,
+    })
+}
+
+fn sign(claims: &serde_json::Value, kid: &str) -> String {
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(kid.to_string());
+    let key = EncodingKey::from_rsa_pem(TEST_KEY).expect("test signing key");
+    jsonwebtoken::encode(&header, claims, &key).expect("sign test token")
+}
+
+// Status, WWW-Authenticate header and JSON error code of POST /authenticate
+async fn authenticate_status<S, B>(app: &S, token: &str) -> (StatusCode, String, String)
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: actix_web::body::MessageBody,
+{
+    let req = test::TestRequest::post()
+        .uri("/authenticate")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    let resp = test::call_service(app, req).await;
+    let status = resp.status();
+    let challenge = resp
+        .headers()
+        .get("WWW-Authenticate")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let body: serde_json::Value =
+        serde_json::from_slice(&test::read_body(resp).await).unwrap_or_default();
+    let code = body["code"].as_str().unwrap_or_default().to_string();
+    (status, challenge, code)
+}
+
+#[actix_web::test]
+async fn authenticate_with_id_token_sets_session() {
+    let base = start_mock_google();
+    let app = test::init_service(app_with_store(
+        auth::AuthProviders::new(vec![Box::new(google(&base))]),
+        None,
+        Arc::new(MemoryStore::new()),
+    ))
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/authenticate")
+        .insert_header((
+            "Authorization",
+            format!("Bearer {}", sign(&claims("alice"), TEST_KID)),
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let cookie = resp
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == "id")
+        .expect("session cookie")
+        .into_owned();
+    call(
+        &app,
+        &Credentials::Cookie(cookie),
+        Method::POST,
+        "/authenticated",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn id_token_claims_are_checked() {
+    let base = start_mock_google();
+    let provider = google(&base);
+    assert_eq!(
+        provider
+            .validate_identity_token(&sign(&claims("alice"), TEST_KID))
+            .await
+            .expect("valid token"),
+        "alice"
+    );
+    let mut many_audiences = claims("alice");
+    many_audiences["aud"] = serde_json::json!(["someone-else", AUDIENCE]);
+    assert!(provider
+        .validate_identity_token(&sign(&many_audiences, TEST_KID))
+        .await
+        .is_ok());
+
+    let mut expired = claims("alice");
+    expired["exp"] = serde_json::json!(now() - 3600);
+    assert!(matches!(
+        provider
+            .validate_identity_token(&sign(&expired, TEST_KID))
+            .await,
+        Err(auth::TokenError::Expired)
+    ));
+    let mut early = claims("alice");
+    early["nbf"] = serde_json::json!(now() + 3600);
+    assert!(matches!(
+        provider
+            .validate_identity_token(&sign(&early, TEST_KID))
+            .await,
+        Err(auth::TokenError::NotValidBefore)
+    ));
+    let mut audience = claims("alice");
+    audience["aud"] = serde_json::json!("someone-else");
+    assert!(matches!(
+        provider
+            .validate_identity_token(&sign(&audience, TEST_KID))
+            .await,
+        Err(auth::TokenError::AudienceMismatch)
+    ));
+    let mut issuer = claims("alice");
+    issuer["iss"] = serde_json::json!("https://evil.example.com");
+    assert!(matches!(
+        provider
+            .validate_identity_token(&sign(&issuer, TEST_KID))
+            .await,
+        Err(auth::TokenError::Issuer)
+    ));
+    assert!(matches!(
+        provider
+            .validate_identity_token(&sign(&claims("alice"), "unknown-key"))
+            .await,
+        Err(auth::TokenError::UnknownKey)
+    ));
+}
+
+#[actix_web::test]
+async fn authenticate_rejects_bad_id_tokens() {
+    let base = start_mock_google();
+    let app = test::init_service(app_with_store(
+        auth::AuthProviders::new(vec![Box::new(google(&base))]),
+        None,
+        Arc::new(MemoryStore::new()),
+    ))
+    .await;
+    let mut expired = claims("alice");
+    expired["exp"] = serde_json::json!(now() - 3600);
+    let mut early = claims("alice");
+    early["nbf"] = serde_json::json!(now() + 3600);
+    let mut audience = claims("alice");
+    audience["aud"] = serde_json::json!("someone-else");
+    let mut issuer = claims("alice");
+    issuer["iss"] = serde_json::json!("https://evil.example.com");
+    for (bad, code) in [
+        (expired, "token_expired"),
+        (early, "token_not_yet_valid"),
+        (audience, "token_audience_mismatch"),
+        (issuer, "token_issuer_unknown"),
+    ] {
+        let (status, challenge, got) = authenticate_status(&app, &sign(&bad, TEST_KID)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", bad);
+        assert_eq!(got, code);
+        assert!(challenge.starts_with("Bearer realm=\"shareconversation\""));
+        assert!(
+            challenge.contains("error=\"invalid_token\""),
+            "{}",
+            challenge
+        );
+    }
+    let (status, _, code) = authenticate_status(&app, "not.a.jwt").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(code, "token_invalid");
+    let (status, _, code) = authenticate_status(&app, &sign(&claims("alice"), "unknown-key")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(code, "token_key_unknown");
+    assert_eq!(
+        authenticate_status(&app, &sign(&claims("alice"), TEST_KID))
+            .await
+            .0,
+        StatusCode::OK
+    );
+}
+
+// Problems on our side are a 503, not a reason for the user to log in again
+#[actix_web::test]
+async fn unreachable_keys_are_a_server_problem() {
+    let app = test::init_service(app_with_store(
+        auth::AuthProviders::new(vec![Box::new(google("http://127.0.0.1:1"))]),
+        None,
+        Arc::new(MemoryStore::new()),
+    ))
+    .await;
+    let (status, challenge, code) =
+        authenticate_status(&app, &sign(&claims("alice"), TEST_KID)).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(code, "keys_unavailable");
+    assert!(challenge.is_empty());
+}
+
+// Generic OIDC provider "corp" signing with the same test key
+fn corp(base: &str) -> auth::OidcProvider {
+    auth::OidcProvider::new(auth::OidcConfig {
+        name: "corp".to_string(),
+        issuer: "https://login.example.com".to_string(),
+        audience: "shareconversation".to_string(),
+        jwks_url: format!("{}/certs", base),
+        jwks_expiry: Default::default(),
+    })
+}
+
+fn corp_claims(sub: &str) -> serde_json::Value {
+    let mut corp = claims(sub);
+    corp["iss"] = serde_json::json!("https://login.example.com");
+    corp["aud"] = serde_json::json!("shareconversation");
+    corp
+}
+
+#[actix_web::test]
+async fn oidc_user_ids_are_prefixed() {
+    let base = start_mock_google();
+    let provider = corp(&base);
+    assert_eq!(
+        provider
+            .validate_identity_token(&sign(&corp_claims("bob"), TEST_KID))
+            .await
+            .expect("valid token"),
+        "corp:bob"
+    );
+    // Issuer picks the provider, a Google token doesn't work with this one
+    let providers = auth::AuthProviders::new(vec![Box::new(provider)]);
+    assert!(matches!(
+        providers
+            .validate_identity_token(&sign(&claims("bob"), TEST_KID))
+            .await,
+        Err(auth::TokenError::Issuer)
+    ));
+}
+
+#[actix_web::test]
+async fn oidc_tokens_need_issuer_and_audience() {
+    let base = start_mock_google();
+    let provider = corp(&base);
+    let mut audience = corp_claims("bob");
+    audience["aud"] = serde_json::json!(AUDIENCE);
+    assert!(matches!(
+        provider
+            .validate_identity_token(&sign(&audience, TEST_KID))
+            .await,
+        Err(auth::TokenError::AudienceMismatch)
+    ));
+    let mut issuer = corp_claims("bob");
+    issuer["iss"] = serde_json::json!(ISSUER);
+    assert!(matches!(
+        provider
+            .validate_identity_token(&sign(&issuer, TEST_KID))
+            .await,
+        Err(auth::TokenError::Issuer)
+    ));
+
+    // With Google next to it, each token is only checked by its own issuer
+    let app = test::init_service(app_with_store(
+        auth::AuthProviders::new(vec![Box::new(google(&base)), Box::new(provider)]),
+        None,
+        Arc::new(MemoryStore::new()),
+    ))
+    .await;
+    let mut unknown = corp_claims("bob");
+    unknown["iss"] = serde_json::json!("https://evil.example.com");
+    let mut google_audience = claims("bob");
+    google_audience["aud"] = serde_json::json!("shareconversation");
+    for (bad, code) in [
+        (audience, "token_audience_mismatch"),
+        (google_audience, "token_audience_mismatch"),
+        (unknown, "token_issuer_unknown"),
+    ] {
+        let (status, _, got) = authenticate_status(&app, &sign(&bad, TEST_KID)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", bad);
+        assert_eq!(got, code, "{}", bad);
+    }
+    for good in [corp_claims("bob"), claims("bob")] {
+        let (status, _, _) = authenticate_status(&app, &sign(&good, TEST_KID)).await;
+        assert_eq!(status, StatusCode::OK, "{}", good);
+    }
+}
+
+#[actix_web::test]
+async fn access_tokens_use_tokeninfo() {
+    let base = start_mock_google();
+    let provider = google(&base);
+    let access = provider
+        .validate_access_token("good-carol")
+        .await
+        .expect("valid access token");
+    assert_eq!(access.user_id, "carol");
+    assert_eq!(access.expires_in, Some(3599));
+    assert!(provider.validate_access_token("bad").await.is_err());
+
+    let app = test::init_service(app_with_store(
+        auth::AuthProviders::new(vec![Box::new(google(&base))]),
+        None,
+        Arc::new(MemoryStore::new()),
+    ))
+    .await;
+    let good = Credentials::Bearer("good-carol".to_string());
+    call(
+        &app,
+        &good,
+        Method::POST,
+        "/authenticated",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    let bad = Credentials::Bearer("bad".to_string());
+    call(
+        &app,
+        &bad,
+        Method::POST,
+        "/authenticated",
+        None,
+        StatusCode::UNAUTHORIZED,
+    )
+    .await;
+}
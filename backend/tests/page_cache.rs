@@ -0,0 +1,181 @@
+// Rendered conversations are cached, revalidated and invalidated
+//
+// Conversations are kept in memory, no database needed.
+
+mod common;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::ServiceResponse;
+use common::*;
+use shareprompts_backend_api::api::page_cache::{CachedPage, PageKind};
+
+// Status, ETag and Last-Modified of a GET with optional conditional headers
+async fn get<S, B>(app: &S, path: &str, headers: &[(&str, &str)]) -> (StatusCode, String, String)
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: MessageBody,
+{
+    let mut req = test::TestRequest::get().uri(path);
+    for header in headers {
+        req = req.insert_header(*header);
+    }
+    let resp = test::call_service(app, req.to_request()).await;
+    let header = |name: &str| {
+        resp.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    (resp.status(), header("ETag"), header("Last-Modified"))
+}
+
+#[actix_web::test]
+async fn pages_are_cached_and_revalidated() {
+    let app = test::init_service(app_with_store(
+        auth::AuthProviders::new(vec![Box::new(TestProvider)]),
+        None,
+        Arc::new(MemoryStore::new()),
+    ))
+    .await;
+    let alice = Credentials::Bearer("test-access-alice".to_string());
+    let id = call(
+        &app,
+        &alice,
+        Method::POST,
+        "/conversation/",
+        Some(new_conversation()),
+        StatusCode::CREATED,
+    )
+    .await;
+    let id = id.as_str().expect("conversation id").to_string();
+
+    for path in [
+        format!("/conversation/html/{}", id),
+        format!("/conversation/json/{}", id),
+    ] {
+        let (status, etag, last_modified) = get(&app, &path, &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(etag.starts_with('"') && etag.len() > 2, "{}", etag);
+        assert!(!last_modified.is_empty());
+        // Second time from the cache, same validators
+        assert_eq!(
+            get(&app, &path, &[]).await,
+            (StatusCode::OK, etag.clone(), last_modified.clone())
+        );
+        let (status, _, _) = get(&app, &path, &[("If-None-Match", etag.as_str())]).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        let (status, _, _) = get(&app, &path, &[("If-None-Match", "\"other\"")]).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, _) = get(
+            &app,
+            &path,
+            &[("If-Modified-Since", last_modified.as_str())],
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        let (status, _, _) = get(
+            &app,
+            &path,
+            &[("If-Modified-Since", "Sat, 01 Jan 2000 00:00:00 GMT")],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let metrics =
+        test::call_and_read_body(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+    let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+    assert!(
+        metrics.contains("page_cache_misses_total 2\n"),
+        "{}",
+        metrics
+    );
+    assert!(metrics.contains("page_cache_entries 1\n"), "{}", metrics);
+
+    // Changing the conversation drops the cached pages
+    let html = format!("/conversation/html/{}", id);
+    let (_, etag, _) = get(&app, &html, &[]).await;
+    let info = call(
+        &app,
+        &Credentials::Nothing,
+        Method::GET,
+        &format!("/conversation/json/{}", id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    let mut metadata = info["metadata"].clone();
+    metadata["title"] = serde_json::json!("Changed title");
+    call_with_headers(
+        &app,
+        &alice,
+        Method::PATCH,
+        &format!("/conversation/{}", id),
+        &[("If-Match", "*")],
+        Some(serde_json::json!({
+            "id": id,
+            "contents": info["contents"],
+            "metadata": metadata,
+            "public": true,
+            "research": false,
+        })),
+        StatusCode::OK,
+    )
+    .await;
+    let (status, changed, _) = get(&app, &html, &[("If-None-Match", etag.as_str())]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_ne!(changed, etag);
+    let body =
+        test::call_and_read_body(&app, test::TestRequest::get().uri(&html).to_request()).await;
+    assert!(String::from_utf8_lossy(&body).contains("Changed title"));
+
+    call(
+        &app,
+        &alice,
+        Method::DELETE,
+        &format!("/conversation/{}", id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(get(&app, &html, &[]).await.0, StatusCode::NOT_FOUND);
+    call(
+        &app,
+        &alice,
+        Method::POST,
+        &format!("/conversation/undelete/{}", id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(get(&app, &html, &[]).await.0, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn full_cache_drops_least_recently_used() {
+    let cache = PageCache::new(2);
+    let page = |body: &str| CachedPage::new(PageKind::Json, body.to_string(), chrono::Utc::now());
+    let generation = cache.generation();
+    cache.insert("a", "digest-a", generation, page("a"));
+    cache.insert("b", "digest-b", generation, page("b"));
+    assert!(cache.get("a", PageKind::Json).is_some());
+    cache.insert("c", "digest-c", generation, page("c"));
+    assert!(cache.get("b", PageKind::Json).is_none());
+    assert!(cache.get("a", PageKind::Json).is_some());
+    assert!(cache.get("c", PageKind::Json).is_some());
+    assert!(cache.get("a", PageKind::Html).is_none());
+
+    // Read before an invalidation, too late to keep
+    let stale = cache.generation();
+    cache.invalidate("a");
+    cache.insert("a", "digest-a", stale, page("a"));
+    assert!(cache.get("a", PageKind::Json).is_none());
+    // Other contents replace the pages of the old ones
+    let generation = cache.generation();
+    cache.insert("c", "digest-c2", generation, page("c2"));
+    assert_eq!(&cache.get("c", PageKind::Json).unwrap().body[..], b"c2");
+}
@@ -0,0 +1,197 @@
+// Conversations are changed with merge patches or JSON Patch
+//
+// Conversations are kept in memory, no database needed.
+
+mod common;
+
+use common::*;
+
+const MERGE_PATCH: &str = "application/merge-patch+json";
+const JSON_PATCH: &str = "application/json-patch+json";
+
+struct Fixture<S> {
+    app: S,
+    alice: Credentials,
+    id: String,
+}
+
+impl<S, B> Fixture<S>
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: actix_web::body::MessageBody,
+{
+    async fn get(&self) -> serde_json::Value {
+        call(
+            &self.app,
+            &Credentials::Nothing,
+            Method::GET,
+            &format!("/conversation/json/{}", self.id),
+            None,
+            StatusCode::OK,
+        )
+        .await
+    }
+
+    // Patch whatever version is stored, check the status and return the body
+    async fn patch(
+        &self,
+        content_type: &str,
+        body: serde_json::Value,
+        expected: StatusCode,
+    ) -> serde_json::Value {
+        let req = request(
+            Method::PATCH,
+            &format!("/conversation/{}", self.id),
+            &self.alice,
+        )
+        .insert_header(("If-Match", "*"))
+        .insert_header(("Content-Type", content_type))
+        .set_payload(body.to_string());
+        let resp = test::call_service(&self.app, req.to_request()).await;
+        assert_eq!(resp.status(), expected, "{}", body);
+        let bytes = test::read_body(resp).await;
+        serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null)
+    }
+}
+
+async fn fixture() -> Fixture<
+    impl actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<impl actix_web::body::MessageBody>,
+        Error = actix_web::Error,
+    >,
+> {
+    let app = test::init_service(app_with_store(
+        auth::AuthProviders::new(vec![Box::new(TestProvider)]),
+        None,
+        Arc::new(MemoryStore::new()),
+    ))
+    .await;
+    let alice = Credentials::Bearer("test-access-alice".to_string());
+    let id = call(
+        &app,
+        &alice,
+        Method::POST,
+        "/conversation/",
+        Some(new_conversation()),
+        StatusCode::CREATED,
+    )
+    .await;
+    let id = id.as_str().expect("conversation id").to_string();
+    Fixture { app, alice, id }
+}
+
+#[actix_web::test]
+async fn merge_patch_changes_only_what_it_names() {
+    let f = fixture().await;
+    let before = f.get().await;
+
+    // Visibility alone leaves the contents and digest alone
+    f.patch(
+        MERGE_PATCH,
+        serde_json::json!({"public": true}),
+        StatusCode::OK,
+    )
+    .await;
+    let after = f.get().await;
+    assert_eq!(after["public"], true);
+    assert_eq!(after["research"], false);
+    assert_eq!(after["contents"], before["contents"]);
+    assert_eq!(after["hmac"], before["hmac"]);
+
+    f.patch(
+        MERGE_PATCH,
+        serde_json::json!({"metadata": {"title": "Renamed"}}),
+        StatusCode::OK,
+    )
+    .await;
+    let renamed = f.get().await;
+    assert_eq!(renamed["metadata"]["title"], "Renamed");
+    assert_eq!(renamed["metadata"]["model"], before["metadata"]["model"]);
+    assert_eq!(renamed["public"], true);
+    assert_ne!(renamed["hmac"], before["hmac"]);
+
+    // The whole conversation as GET returned it is a merge patch too
+    let mut whole = renamed.clone();
+    whole["research"] = true.into();
+    f.patch("application/json", whole, StatusCode::OK).await;
+    let whole = f.get().await;
+    assert_eq!(whole["research"], true);
+    assert_eq!(whole["hmac"], renamed["hmac"]);
+}
+
+#[actix_web::test]
+async fn json_patch_edits_the_dialog() {
+    let f = fixture().await;
+    let before = f.get().await;
+    f.patch(
+        JSON_PATCH,
+        serde_json::json!([
+            {"op": "test", "path": "/contents/dialog/0/what", "value": "Hello"},
+            {"op": "replace", "path": "/contents/dialog/0/what", "value": "Hi"},
+            {"op": "add", "path": "/contents/dialog/-", "value": {"who": "gpt", "what": "Hello!"}},
+        ]),
+        StatusCode::OK,
+    )
+    .await;
+    let after = f.get().await;
+    assert_eq!(after["contents"]["dialog"][0]["what"], "Hi");
+    assert_eq!(after["contents"]["dialog"][1]["who"], "gpt");
+    // Length follows the dialog
+    assert_eq!(after["metadata"]["length"], 2);
+    assert_ne!(after["hmac"], before["hmac"]);
+
+    // Operations apply all or nothing
+    let body = f
+        .patch(
+            JSON_PATCH,
+            serde_json::json!([
+                {"op": "replace", "path": "/metadata/title", "value": "Lost"},
+                {"op": "test", "path": "/contents/dialog/0/what", "value": "Hello"},
+            ]),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        )
+        .await;
+    assert_eq!(body["code"], "patch_failed");
+    assert_eq!(f.get().await["metadata"]["title"], "Test conversation");
+
+    let body = f
+        .patch(
+            JSON_PATCH,
+            serde_json::json!({"public": true}),
+            StatusCode::BAD_REQUEST,
+        )
+        .await;
+    assert_eq!(body["code"], "patch_invalid");
+}
+
+#[actix_web::test]
+async fn patched_conversation_is_checked() {
+    let f = fixture().await;
+    let before = f.get().await;
+    for (patch, code) in [
+        (serde_json::json!({"public": "yes"}), "patch_result_invalid"),
+        (
+            serde_json::json!({"contents": {"dialog": null}}),
+            "patch_result_invalid",
+        ),
+        (
+            serde_json::json!({"contents": {"dialog": [{"who": "gpt"}]}}),
+            "patch_result_invalid",
+        ),
+        (serde_json::json!({"id": "other"}), "field_read_only"),
+        (serde_json::json!({"hmac": "forged"}), "field_read_only"),
+        (serde_json::json!({"deleted": true}), "field_read_only"),
+        (serde_json::json!({"version": 9}), "field_read_only"),
+    ] {
+        let body = f
+            .patch(MERGE_PATCH, patch, StatusCode::UNPROCESSABLE_ENTITY)
+            .await;
+        assert_eq!(body["code"], code);
+    }
+    assert_eq!(f.get().await, before);
+}
@@ -0,0 +1,110 @@
+// The connection pool under load
+//
+// With every connection busy a request waits at most `connection_timeout` and
+// then gets 503 with Retry-After, it never queues forever. Long statements are
+// cancelled by Postgres. These use their own small pool on DATABASE_URL and
+// are skipped without it.
+
+mod common;
+
+use common::*;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::SimpleAsyncConnection;
+use futures_util::future::join_all;
+use std::time::{Duration, Instant};
+
+fn count_request(user: &str) -> actix_http::Request {
+    request(
+        Method::GET,
+        "/conversation/count",
+        &Credentials::Bearer(format!("test-access-{}", user)),
+    )
+    .to_request()
+}
+
+#[actix_web::test]
+async fn exhausted_pool_answers_service_unavailable() {
+    let Some(pool) = test_pool_with(&PoolConfig {
+        size: 2,
+        connection_timeout: Duration::from_millis(200),
+        ..Default::default()
+    }) else {
+        return;
+    };
+    let app = test::init_service(app_with(
+        auth::AuthProviders::new(vec![Box::new(TestProvider)]),
+        pool.clone(),
+    ))
+    .await;
+    let user = uuid::Uuid::new_v4().simple().to_string();
+
+    // Something else has every connection
+    let held = join_all((0..2).map(|_| pool.get())).await;
+    assert!(held.iter().all(Result::is_ok));
+    let start = Instant::now();
+    let responses = join_all((0..20).map(|_| test::call_service(&app, count_request(&user)))).await;
+    // All of them waited at the same time, none waited for the others
+    assert!(
+        start.elapsed() < Duration::from_secs(2),
+        "{:?}",
+        start.elapsed()
+    );
+    for resp in responses {
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "1");
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "database_timeout");
+    }
+
+    // Back to normal once the connections are returned
+    drop(held);
+    let resp = test::call_service(&app, count_request(&user)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn busy_pool_serves_requests_in_turn() {
+    let Some(pool) = test_pool_with(&PoolConfig {
+        size: 2,
+        ..Default::default()
+    }) else {
+        return;
+    };
+    let app = test::init_service(app_with(
+        auth::AuthProviders::new(vec![Box::new(TestProvider)]),
+        pool.clone(),
+    ))
+    .await;
+    let user = uuid::Uuid::new_v4().simple().to_string();
+    let responses = join_all((0..50).map(|_| test::call_service(&app, count_request(&user)))).await;
+    assert!(responses.iter().all(|resp| resp.status() == StatusCode::OK));
+    assert!(pool.state().connections <= 2);
+}
+
+#[actix_web::test]
+async fn long_statements_are_cancelled() {
+    let Some(pool) = test_pool_with(&PoolConfig {
+        size: 1,
+        statement_timeout: Duration::from_millis(100),
+        ..Default::default()
+    }) else {
+        return;
+    };
+    let start = Instant::now();
+    let result = storage::with_retries(&pool, |conn| {
+        conn.batch_execute("SELECT pg_sleep(5)").scope_boxed()
+    })
+    .await;
+    assert!(matches!(result, Err(LocalError::DbTimeout)), "{:?}", result);
+    assert!(
+        start.elapsed() < Duration::from_secs(2),
+        "{:?}",
+        start.elapsed()
+    );
+    // The connection is still good for short ones
+    let result = storage::with_retries(&pool, |conn| {
+        conn.batch_execute("SELECT pg_sleep(0.01)").scope_boxed()
+    })
+    .await;
+    assert!(result.is_ok(), "{:?}", result);
+}
@@ -0,0 +1,91 @@
+// Shared conversation pages from the built-in and the overriding site files
+
+use chrono::Utc;
+use shareprompts_backend_api::models::*;
+use shareprompts_backend_api::render::PageRenderer;
+
+fn conversation() -> Conversation {
+    Conversation {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        hmac: "hmac".to_string(),
+        contents: serde_json::json!({"avatar": "", "dialog": [{"who": "human", "what": "Hi"}]}),
+        public: true,
+        research: false,
+        deleted: false,
+        user_id: "alice".to_string(),
+        folder_id: None,
+        created_at: Utc::now(),
+        title: "Rendered title".to_string(),
+        model: "test".to_string(),
+        openaiid: "openai".to_string(),
+        updated_at: Utc::now(),
+        length: 1,
+        schema_version: CONTENTS_SCHEMA_VERSION,
+        version: 1,
+    }
+}
+
+fn site_dir() -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("sp-site-{}", uuid::Uuid::new_v4().simple()));
+    std::fs::create_dir(&dir).expect("make site directory");
+    dir
+}
+
+#[test]
+fn built_in_page() {
+    let pages = PageRenderer::embedded();
+    let page = pages
+        .conversation_html(&conversation())
+        .expect("built-in page");
+    assert!(page.contains("<title>ShareConversation - Rendered title</title>"));
+    assert!(page.contains("data:image/png;base64,"));
+    // Same page the second time, from the same registry
+    assert_eq!(pages.conversation_html(&conversation()).unwrap(), page);
+}
+
+#[test]
+fn site_files_override_the_built_in_ones() {
+    let site = site_dir();
+    std::fs::write(site.join("index.hbs"), "<h1>{{title}}</h1>{{{style}}}").unwrap();
+    let pages = PageRenderer::new(Some(site.clone()), false).expect("read site directory");
+    let page = pages
+        .conversation_html(&conversation())
+        .expect("overridden page");
+    // Style sheet is still the built-in one
+    assert!(page.starts_with("<h1>Rendered title</h1>"));
+    assert!(page.len() > "<h1>Rendered title</h1>".len());
+
+    // Compiled once, later changes don't show up
+    std::fs::write(site.join("index.hbs"), "<h2>{{title}}</h2>").unwrap();
+    assert!(pages
+        .conversation_html(&conversation())
+        .unwrap()
+        .starts_with("<h1>"));
+
+    std::fs::write(site.join("index.hbs"), "<h1>{{#if}}</h1>").unwrap();
+    assert!(PageRenderer::new(Some(site.clone()), false).is_err());
+    std::fs::remove_dir_all(&site).ok();
+}
+
+#[test]
+fn development_mode_reads_files_for_every_page() {
+    let site = site_dir();
+    std::fs::write(site.join("index.hbs"), "<h1>{{title}}</h1>").unwrap();
+    let pages = PageRenderer::new(Some(site.clone()), true).expect("read site directory");
+    assert_eq!(
+        pages.conversation_html(&conversation()).unwrap(),
+        "<h1>Rendered title</h1>"
+    );
+    std::fs::write(site.join("index.hbs"), "<h3>{{title}}</h3>").unwrap();
+    assert_eq!(
+        pages.conversation_html(&conversation()).unwrap(),
+        "<h3>Rendered title</h3>"
+    );
+    // A broken template keeps the one read at startup
+    std::fs::write(site.join("index.hbs"), "<h1>{{#if}}</h1>").unwrap();
+    assert_eq!(
+        pages.conversation_html(&conversation()).unwrap(),
+        "<h1>Rendered title</h1>"
+    );
+    std::fs::remove_dir_all(&site).ok();
+}
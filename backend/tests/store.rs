@@ -0,0 +1,465 @@
+// Every conversation store behaves the same
+//
+// The same checks run against the in-memory store, SQLite (in memory) and
+// Postgres (only with DATABASE_URL, like the endpoint tests). The API is also
+// run on the in-memory store alone, without any database.
+
+mod common;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use common::*;
+use futures_util::future::join_all;
+use shareprompts_backend_api::models::*;
+use shareprompts_backend_api::storage::SqliteStore;
+
+fn conversation(
+    user_id: &str,
+    title: &str,
+    model: &str,
+    created_at: DateTime<Utc>,
+) -> Conversation {
+    Conversation {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        hmac: uuid::Uuid::new_v4().simple().to_string(),
+        contents: serde_json::json!({"avatar": "", "dialog": []}),
+        public: false,
+        research: false,
+        deleted: false,
+        user_id: user_id.to_string(),
+        folder_id: None,
+        created_at,
+        title: title.to_string(),
+        model: model.to_string(),
+        openaiid: "openai".to_string(),
+        updated_at: created_at,
+        length: 0,
+        schema_version: CONTENTS_SCHEMA_VERSION,
+        version: 1,
+    }
+}
+
+fn ids(page: &ConversationPage) -> Vec<&str> {
+    page.conversations
+        .iter()
+        .map(|conv| conv.id.as_str())
+        .collect()
+}
+
+async fn list(
+    store: &dyn ConversationStore,
+    user: &str,
+    filter: ConversationFilter,
+) -> ConversationPage {
+    let cursor = filter
+        .cursor
+        .as_deref()
+        .map(|cursor| PageCursor::decode(cursor).expect("valid cursor"));
+    store
+        .list(user, &filter, cursor.as_ref())
+        .await
+        .expect("list conversations")
+}
+
+async fn check_store(store: &dyn ConversationStore) {
+    let user = uuid::Uuid::new_v4().simple().to_string();
+    let start = Utc.with_ymd_and_hms(2023, 7, 1, 12, 0, 0).unwrap();
+    let a = conversation(&user, "Alpha", "m1", start);
+    let b = conversation(&user, "beta", "m2", start + Duration::minutes(1));
+    let c = conversation(&user, "Gamma", "m1", start + Duration::minutes(2));
+    for conv in [&a, &b, &c] {
+        store.insert(conv).await.expect("insert conversation");
+    }
+    assert!(matches!(
+        store.insert(&a).await,
+        Err(LocalError::AlreadyExists)
+    ));
+
+    let found = store
+        .find(&a.id, false)
+        .await
+        .unwrap()
+        .expect("conversation a");
+    assert_eq!(found.title, "Alpha");
+    assert_eq!(found.created_at, start);
+    assert!(store.find(&a.id, true).await.unwrap().is_none());
+    assert!(store.find("missing", false).await.unwrap().is_none());
+    assert_eq!(
+        store.exists(&user, &a.hmac).await.unwrap(),
+        Some(a.id.clone())
+    );
+    assert_eq!(store.exists("someone else", &a.hmac).await.unwrap(), None);
+    assert_eq!(store.count(&user).await.unwrap(), 3);
+
+    // Newest first, one page at a time
+    let page = list(store, &user, ConversationFilter::default()).await;
+    assert_eq!(ids(&page), [&c.id, &b.id, &a.id]);
+    assert!(page.next_cursor.is_none());
+    let first = list(
+        store,
+        &user,
+        ConversationFilter {
+            limit: Some(2),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(ids(&first), [&c.id, &b.id]);
+    let second = list(
+        store,
+        &user,
+        ConversationFilter {
+            limit: Some(2),
+            cursor: first.next_cursor.clone(),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(ids(&second), [&a.id]);
+    assert!(second.next_cursor.is_none());
+
+    // Sorting by title ignores case
+    let page = list(
+        store,
+        &user,
+        ConversationFilter {
+            sort: Some(SortKey::Title),
+            order: SortOrder::Asc,
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(ids(&page), [&a.id, &b.id, &c.id]);
+    let page = list(
+        store,
+        &user,
+        ConversationFilter {
+            model: Some("m1".to_string()),
+            title: Some("AMM".to_string()),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(ids(&page), [&c.id]);
+    let page = list(
+        store,
+        &user,
+        ConversationFilter {
+            since: Some(start + Duration::seconds(30)),
+            until: Some(start + Duration::seconds(90)),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(ids(&page), [&b.id]);
+
+    let changes = ConversationChanges {
+        contents: serde_json::json!({"avatar": "", "dialog": [{"who": "human", "what": "Hi"}]}),
+        schema_version: CONTENTS_SCHEMA_VERSION,
+        title: "Beta".to_string(),
+        model: "m3".to_string(),
+        openaiid: "other".to_string(),
+        length: 1,
+        public: true,
+        research: true,
+        hmac: "changed".to_string(),
+    };
+    assert_eq!(
+        store
+            .update(&b.id, &changes, b.version)
+            .await
+            .expect("update conversation"),
+        b.version + 1
+    );
+    // Changes to the version it had before are refused
+    assert!(matches!(
+        store.update(&b.id, &changes, b.version).await,
+        Err(LocalError::VersionMismatch)
+    ));
+    let updated = store
+        .find(&b.id, false)
+        .await
+        .unwrap()
+        .expect("conversation b");
+    assert_eq!(updated.title, "Beta");
+    assert_eq!(updated.length, 1);
+    assert!(updated.public && updated.research);
+    assert_eq!(updated.contents, changes.contents);
+    assert_eq!(updated.version, b.version + 1);
+    assert!(updated.updated_at > b.updated_at);
+    assert_eq!(
+        store.exists(&user, "changed").await.unwrap(),
+        Some(b.id.clone())
+    );
+    assert!(matches!(
+        store.update("missing", &changes, 1).await,
+        Err(LocalError::NotFound)
+    ));
+
+    store
+        .set_deleted(&a.id, true)
+        .await
+        .expect("delete conversation");
+    assert!(store.find(&a.id, false).await.unwrap().is_none());
+    let deleted = store.find(&a.id, true).await.unwrap().expect("deleted a");
+    assert_eq!(deleted.version, a.version + 1);
+    assert_eq!(store.count(&user).await.unwrap(), 2);
+    assert_eq!(store.exists(&user, &a.hmac).await.unwrap(), None);
+    let page = list(
+        store,
+        &user,
+        ConversationFilter {
+            deleted: Some(true),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(ids(&page), [&a.id]);
+    store
+        .set_deleted(&a.id, false)
+        .await
+        .expect("undelete conversation");
+    assert_eq!(store.count(&user).await.unwrap(), 3);
+    assert!(matches!(
+        store.set_deleted("missing", true).await,
+        Err(LocalError::NotFound)
+    ));
+}
+
+// Titles outside ASCII ignore case too, in the stores that fold it themselves
+// Postgres lowercases by the database's locale, with "C" only ASCII.
+async fn check_unicode_titles(store: &dyn ConversationStore) {
+    let user = uuid::Uuid::new_v4().simple().to_string();
+    let start = Utc.with_ymd_and_hms(2023, 7, 1, 12, 0, 0).unwrap();
+    let a = conversation(&user, "Éb", "m1", start);
+    let b = conversation(&user, "éa", "m1", start + Duration::minutes(1));
+    let c = conversation(&user, "ÄRGER", "m1", start + Duration::minutes(2));
+    for conv in [&a, &b, &c] {
+        store.insert(conv).await.expect("insert conversation");
+    }
+    let page = list(
+        store,
+        &user,
+        ConversationFilter {
+            title: Some("ärg".to_string()),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(ids(&page), [&c.id]);
+    let page = list(
+        store,
+        &user,
+        ConversationFilter {
+            sort: Some(SortKey::Title),
+            order: SortOrder::Asc,
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(ids(&page), [&c.id, &b.id, &a.id]);
+}
+
+// Uploads arriving at the same time, the same one many times and more than allowed
+async fn check_parallel_creates(store: &dyn ConversationStore) {
+    let user = uuid::Uuid::new_v4().simple().to_string();
+    let start = Utc.with_ymd_and_hms(2023, 7, 1, 12, 0, 0).unwrap();
+    let same = conversation(&user, "Same", "m1", start);
+    let copies: Vec<Conversation> = (0..10)
+        .map(|_| Conversation {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            ..same.clone()
+        })
+        .collect();
+    let ids = join_all(copies.iter().map(|conv| store.create(conv, Some(5)))).await;
+    let first = ids[0].as_ref().expect("create conversation").clone();
+    assert!(
+        ids.iter().all(|id| id.as_ref().ok() == Some(&first)),
+        "{:?}",
+        ids
+    );
+    assert_eq!(store.count(&user).await.unwrap(), 1);
+
+    let others: Vec<Conversation> = (0..10)
+        .map(|i| conversation(&user, &format!("Other {}", i), "m1", start))
+        .collect();
+    let results = join_all(others.iter().map(|conv| store.create(conv, Some(5)))).await;
+    let created = results.iter().filter(|result| result.is_ok()).count();
+    assert_eq!(created, 4, "{:?}", results);
+    assert!(results
+        .iter()
+        .all(|result| matches!(result, Ok(_) | Err(LocalError::MaxCount))));
+    assert_eq!(store.count(&user).await.unwrap(), 5);
+    // A deleted copy doesn't count as the same, and paying users have no limit
+    store.set_deleted(&first, true).await.unwrap();
+    let copy = Conversation {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        ..same.clone()
+    };
+    let again = store.create(&copy, Some(5)).await.unwrap();
+    assert_ne!(again, first);
+    let paid = conversation(&user, "Paid", "m1", start);
+    assert_eq!(store.create(&paid, None).await.unwrap(), paid.id);
+    assert_eq!(store.count(&user).await.unwrap(), 6);
+}
+
+#[actix_web::test]
+async fn memory_store() {
+    let store = MemoryStore::new();
+    check_store(&store).await;
+    check_unicode_titles(&store).await;
+    check_parallel_creates(&store).await;
+}
+
+#[actix_web::test]
+async fn sqlite_store() {
+    let store = SqliteStore::open(":memory:").expect("open SQLite database");
+    check_store(&store).await;
+    check_unicode_titles(&store).await;
+    check_parallel_creates(&store).await;
+}
+
+#[actix_web::test]
+async fn postgres_store() {
+    let Some(pool) = test_pool() else { return };
+    let store = PgStore::new(pool);
+    check_store(&store).await;
+    check_parallel_creates(&store).await;
+}
+
+#[actix_web::test]
+async fn conversations_without_postgres() {
+    let app = test::init_service(app_with_store(
+        auth::AuthProviders::new(vec![Box::new(TestProvider)]),
+        None,
+        Arc::new(MemoryStore::new()),
+    ))
+    .await;
+    let alice = Credentials::Bearer("test-access-alice".to_string());
+    let id = call(
+        &app,
+        &alice,
+        Method::POST,
+        "/conversation/",
+        Some(new_conversation()),
+        StatusCode::CREATED,
+    )
+    .await;
+    let id = id.as_str().expect("conversation id").to_string();
+    let conv = call(
+        &app,
+        &Credentials::Nothing,
+        Method::GET,
+        &format!("/conversation/json/{}", id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(conv["metadata"]["title"], "Test conversation");
+    let page = call(
+        &app,
+        &alice,
+        Method::POST,
+        "/conversations",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(page["conversations"][0]["id"], id.as_str());
+    let count = call(
+        &app,
+        &alice,
+        Method::GET,
+        "/conversation/count",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(count, 1);
+    call(
+        &app,
+        &alice,
+        Method::DELETE,
+        &format!("/conversation/{}", id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    call(
+        &app,
+        &Credentials::Nothing,
+        Method::GET,
+        &format!("/conversation/json/{}", id),
+        None,
+        StatusCode::NOT_FOUND,
+    )
+    .await;
+    // API tokens live in Postgres, so there are none to accept
+    let resp = call(
+        &app,
+        &Credentials::Bearer("sp_unknown".to_string()),
+        Method::GET,
+        "/conversation/count",
+        None,
+        StatusCode::UNAUTHORIZED,
+    )
+    .await;
+    assert_eq!(resp["code"], "api_token_invalid");
+}
+
+#[actix_web::test]
+async fn database_endpoints_answer_501_without_postgres() {
+    let app = test::init_service(app_with_store(
+        auth::AuthProviders::new(vec![Box::new(TestProvider)]),
+        None,
+        Arc::new(MemoryStore::new()),
+    ))
+    .await;
+    let alice = Credentials::Bearer("test-access-alice".to_string());
+    for (method, path) in [
+        (Method::GET, "/tags"),
+        (Method::POST, "/tags"),
+        (Method::DELETE, "/tags/1"),
+        (Method::GET, "/folders"),
+        (Method::PATCH, "/folders/1"),
+        (Method::POST, "/conversations/bulk"),
+        (Method::PUT, "/conversation/abc/folder"),
+        (Method::PUT, "/conversation/abc/tags/1"),
+        (Method::GET, "/tokens"),
+        (Method::DELETE, "/tokens/abc"),
+        (Method::POST, "/local/register"),
+        (Method::POST, "/local/login"),
+        (Method::POST, "/local/magic-link"),
+        (Method::GET, "/local/magic-link/abc"),
+        (Method::POST, "/local/magic-link/abc"),
+    ] {
+        let resp =
+            test::call_service(&app, request(method.clone(), path, &alice).to_request()).await;
+        assert_eq!(
+            resp.status(),
+            StatusCode::NOT_IMPLEMENTED,
+            "{} {}",
+            method,
+            path
+        );
+        assert_eq!(
+            resp.headers()
+                .get("content-type")
+                .map(|value| value.as_bytes()),
+            Some(&b"application/problem+json"[..])
+        );
+        let body: serde_json::Value =
+            serde_json::from_slice(&test::read_body(resp).await).expect("problem details");
+        assert_eq!(body["code"], "not_available", "{} {}", method, path);
+        assert_eq!(body["status"], 501);
+    }
+    // Conversations themselves still work
+    call(
+        &app,
+        &alice,
+        Method::GET,
+        "/conversation/count",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+}
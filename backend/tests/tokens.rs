@@ -0,0 +1,210 @@
+// Personal API tokens: scopes, listing and revoking
+//
+// These run against a real Postgres database given by DATABASE_URL, see
+// common::database_url.
+
+mod common;
+
+use common::*;
+
+// Make a token with the given scopes, returns its id and the token itself
+async fn new_token<S, B>(app: &S, session: &Credentials, scopes: &[&str]) -> (String, Credentials)
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: actix_web::body::MessageBody,
+{
+    let created = call(
+        app,
+        session,
+        Method::POST,
+        "/tokens",
+        Some(serde_json::json!({"name": "script", "scopes": scopes})),
+        StatusCode::CREATED,
+    )
+    .await;
+    (
+        created["id"].as_str().expect("token id").to_string(),
+        Credentials::Bearer(created["token"].as_str().expect("token").to_string()),
+    )
+}
+
+// Status, WWW-Authenticate header and error code of a request
+// Uploads get a new conversation as body, everything else none.
+async fn refusal<S, B>(
+    app: &S,
+    creds: &Credentials,
+    method: Method,
+    path: &str,
+) -> (StatusCode, String, String)
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: actix_web::body::MessageBody,
+{
+    let mut req = request(method, path, creds);
+    if path == "/conversation/" {
+        req = req.set_json(new_conversation());
+    }
+    let resp = test::call_service(app, req.to_request()).await;
+    let status = resp.status();
+    let challenge = resp
+        .headers()
+        .get("WWW-Authenticate")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let body: serde_json::Value =
+        serde_json::from_slice(&test::read_body(resp).await).unwrap_or_default();
+    (
+        status,
+        challenge,
+        body["code"].as_str().unwrap_or_default().to_string(),
+    )
+}
+
+#[actix_web::test]
+async fn scopes_limit_what_tokens_do() {
+    let Some(pool) = test_pool() else { return };
+    let app = test::init_service(app_with(auth::AuthProviders::new(vec![]), pool)).await;
+    let session = Credentials::Cookie(session_cookie(&app).await);
+    let (_, read) = new_token(&app, &session, &["read"]).await;
+    let (_, write) = new_token(&app, &session, &["write"]).await;
+
+    let (status, challenge, code) = refusal(&app, &read, Method::POST, "/conversation/").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(code, "insufficient_scope");
+    assert!(
+        challenge.contains("error=\"insufficient_scope\"") && challenge.contains("scope=\"write\""),
+        "{}",
+        challenge
+    );
+    let (status, challenge, code) = refusal(&app, &write, Method::GET, "/tags").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(code, "insufficient_scope");
+    assert!(challenge.contains("scope=\"read\""), "{}", challenge);
+    let (status, _, _) = refusal(&app, &write, Method::POST, "/conversations").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Each works for its own scope
+    let id = upload(&app, &write).await;
+    let page = call(
+        &app,
+        &read,
+        Method::POST,
+        "/conversations",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(page["conversations"][0]["id"], id.as_str());
+}
+
+#[actix_web::test]
+async fn revoked_tokens_stop_working() {
+    let Some(pool) = test_pool() else { return };
+    let app = test::init_service(app_with(auth::AuthProviders::new(vec![]), pool)).await;
+    let alice = Credentials::Cookie(session_cookie(&app).await);
+    let bob = Credentials::Cookie(session_cookie(&app).await);
+    let (id, token) = new_token(&app, &alice, &["write", "read", "read"]).await;
+    call(
+        &app,
+        &token,
+        Method::GET,
+        "/conversation/count",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+
+    // The list never has the token itself
+    let listed = call(&app, &alice, Method::GET, "/tokens", None, StatusCode::OK).await;
+    assert_eq!(listed.as_array().map(Vec::len), Some(1));
+    assert_eq!(listed[0]["id"], id.as_str());
+    assert_eq!(listed[0]["scopes"], serde_json::json!(["read", "write"]));
+    assert_eq!(listed[0]["revoked"], false);
+    assert!(listed[0]["last_used_at"].is_string());
+    assert!(listed[0].get("token").is_none());
+    assert!(listed[0].get("token_hash").is_none());
+    let listed = call(&app, &bob, Method::GET, "/tokens", None, StatusCode::OK).await;
+    assert_eq!(listed, serde_json::json!([]));
+
+    // Only its owner can revoke it
+    call(
+        &app,
+        &bob,
+        Method::DELETE,
+        &format!("/tokens/{}", id),
+        None,
+        StatusCode::NOT_FOUND,
+    )
+    .await;
+    call(
+        &app,
+        &token,
+        Method::GET,
+        "/conversation/count",
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    call(
+        &app,
+        &alice,
+        Method::DELETE,
+        &format!("/tokens/{}", id),
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    let (status, challenge, code) = refusal(&app, &token, Method::GET, "/conversation/count").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(code, "api_token_invalid");
+    assert!(
+        challenge.contains("error=\"invalid_token\""),
+        "{}",
+        challenge
+    );
+    let listed = call(&app, &alice, Method::GET, "/tokens", None, StatusCode::OK).await;
+    assert_eq!(listed[0]["revoked"], true);
+}
+
+#[actix_web::test]
+async fn new_tokens_are_checked() {
+    let Some(pool) = test_pool() else { return };
+    let app = test::init_service(app_with(auth::AuthProviders::new(vec![]), pool)).await;
+    let session = Credentials::Cookie(session_cookie(&app).await);
+    for (body, status, code) in [
+        (
+            serde_json::json!({"name": " ", "scopes": ["read"]}),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "token_name_invalid",
+        ),
+        (
+            serde_json::json!({"name": "x".repeat(101), "scopes": ["read"]}),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "token_name_invalid",
+        ),
+        (
+            serde_json::json!({"name": "ci", "scopes": []}),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "token_scopes_missing",
+        ),
+        (
+            serde_json::json!({"name": "ci", "scopes": ["admin"]}),
+            StatusCode::BAD_REQUEST,
+            "body_invalid",
+        ),
+    ] {
+        let resp = call(&app, &session, Method::POST, "/tokens", Some(body), status).await;
+        assert_eq!(resp["code"], code);
+    }
+    let listed = call(&app, &session, Method::GET, "/tokens", None, StatusCode::OK).await;
+    assert_eq!(listed, serde_json::json!([]));
+}
@@ -0,0 +1,118 @@
+// Conversations carry a version, changes have to name the one they were made to
+//
+// Conversations are kept in memory, no database needed.
+
+mod common;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::ServiceResponse;
+use common::*;
+
+// Status, ETag and JSON body of a PATCH with the given If-Match
+async fn patch<S, B>(
+    app: &S,
+    creds: &Credentials,
+    id: &str,
+    if_match: Option<&str>,
+    body: serde_json::Value,
+) -> (StatusCode, String, serde_json::Value)
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: MessageBody,
+{
+    let mut req = request(Method::PATCH, &format!("/conversation/{}", id), creds).set_json(body);
+    if let Some(if_match) = if_match {
+        req = req.insert_header(("If-Match", if_match));
+    }
+    let resp = test::call_service(app, req.to_request()).await;
+    let status = resp.status();
+    let etag = resp
+        .headers()
+        .get("ETag")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let bytes = test::read_body(resp).await;
+    let body = serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null);
+    (status, etag, body)
+}
+
+#[actix_web::test]
+async fn changes_need_the_current_version() {
+    let app = test::init_service(app_with_store(
+        auth::AuthProviders::new(vec![Box::new(TestProvider)]),
+        None,
+        Arc::new(MemoryStore::new()),
+    ))
+    .await;
+    let alice = Credentials::Bearer("test-access-alice".to_string());
+    let id = call(
+        &app,
+        &alice,
+        Method::POST,
+        "/conversation/",
+        Some(new_conversation()),
+        StatusCode::CREATED,
+    )
+    .await;
+    let id = id.as_str().expect("conversation id").to_string();
+    let json = format!("/conversation/json/{}", id);
+
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&json).to_request()).await;
+    assert_eq!(resp.headers().get("ETag").unwrap(), "\"1\"");
+    let info: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(info["version"], 1);
+    let change = |title: &str| {
+        let mut metadata = info["metadata"].clone();
+        metadata["title"] = title.into();
+        serde_json::json!({
+            "id": id,
+            "contents": info["contents"],
+            "metadata": metadata,
+            "public": false,
+            "research": false,
+        })
+    };
+
+    // Without If-Match nothing is changed
+    let (status, _, body) = patch(&app, &alice, &id, None, change("First")).await;
+    assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+    assert_eq!(body["code"], "if_match_missing");
+
+    let (status, etag, _) = patch(&app, &alice, &id, Some("\"1\""), change("First")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(etag, "\"2\"");
+
+    // A second change made to version 1 would undo the first
+    let (status, etag, body) = patch(&app, &alice, &id, Some("\"1\""), change("Second")).await;
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(body["code"], "version_mismatch");
+    assert_eq!(etag, "\"2\"");
+    assert_eq!(body["current"]["version"], 2);
+    assert_eq!(body["current"]["metadata"]["title"], "First");
+
+    // Weak tags don't promise the same version
+    let (status, _, _) = patch(&app, &alice, &id, Some("W/\"2\""), change("Second")).await;
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    let (status, _, _) = patch(&app, &alice, &id, Some("\"7\", \"2\""), change("Second")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, etag, _) = patch(&app, &alice, &id, Some("*"), change("Third")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(etag, "\"4\"");
+
+    let info = call(
+        &app,
+        &Credentials::Nothing,
+        Method::GET,
+        &json,
+        None,
+        StatusCode::OK,
+    )
+    .await;
+    assert_eq!(info["version"], 4);
+    assert_eq!(info["metadata"]["title"], "Third");
+}